@@ -0,0 +1,419 @@
+use crate::error::RuntimeError;
+use crate::value::{self, compare, describe, type_name};
+use serde_json::Value;
+use std::cmp::Ordering;
+
+/// An array paired with the key each element sorts by
+pub type Keyed = Vec<(Value, Value)>;
+
+/// The elements of an array that is about to be sorted or grouped
+pub fn elements(input: Value) -> Result<Vec<Value>, RuntimeError> {
+    match input {
+        Value::Array(items) => Ok(items),
+        other => Err(RuntimeError::Type(format!(
+            "{} cannot be sorted, as it is not an array",
+            describe(&other)
+        ))),
+    }
+}
+
+/// Stable sort by key, in jq's ordering of values
+fn sorted(mut keyed: Keyed) -> Keyed {
+    keyed.sort_by(|(a, _), (b, _)| compare(a, b));
+    keyed
+}
+
+/// The elements ordered by their keys
+pub fn sort(keyed: Keyed) -> Value {
+    Value::Array(sorted(keyed).into_iter().map(|(_, item)| item).collect())
+}
+
+/// Runs of elements with equal keys, in key order
+fn runs(keyed: Keyed) -> Vec<Vec<Value>> {
+    let mut runs: Vec<Vec<Value>> = Vec::new();
+    let mut last: Option<Value> = None;
+    for (key, item) in sorted(keyed) {
+        match (&last, runs.last_mut()) {
+            (Some(last), Some(run)) if compare(last, &key) == Ordering::Equal => run.push(item),
+            _ => runs.push(vec![item]),
+        }
+        last = Some(key);
+    }
+    runs
+}
+
+/// Arrays of the elements sharing each key, in key order
+pub fn group(keyed: Keyed) -> Value {
+    Value::Array(runs(keyed).into_iter().map(Value::Array).collect())
+}
+
+/// The first element with each key, in key order
+pub fn unique(keyed: Keyed) -> Value {
+    Value::Array(
+        runs(keyed)
+            .into_iter()
+            .filter_map(|run| run.into_iter().next())
+            .collect(),
+    )
+}
+
+/// The first element with the smallest key, or null for an empty array
+pub fn min(keyed: Keyed) -> Value {
+    keyed
+        .into_iter()
+        .reduce(|best, next| {
+            if compare(&next.0, &best.0) == Ordering::Less {
+                next
+            } else {
+                best
+            }
+        })
+        .map_or(Value::Null, |(_, item)| item)
+}
+
+/// The last element with the largest key, or null for an empty array
+pub fn max(keyed: Keyed) -> Value {
+    keyed
+        .into_iter()
+        .reduce(|best, next| {
+            if compare(&next.0, &best.0) != Ordering::Less {
+                next
+            } else {
+                best
+            }
+        })
+        .map_or(Value::Null, |(_, item)| item)
+}
+
+/// An array in reverse order, or a string with its codepoints reversed
+pub fn reverse(input: &Value) -> Result<Value, RuntimeError> {
+    match input {
+        Value::Null => Ok(Value::Array(Vec::new())),
+        Value::String(s) => Ok(Value::String(s.chars().rev().collect())),
+        Value::Array(items) => Ok(Value::Array(items.iter().rev().cloned().collect())),
+        other => Err(RuntimeError::Type(format!(
+            "{} cannot be reversed, as it is not an array",
+            describe(other)
+        ))),
+    }
+}
+
+/// Splice nested arrays into their parent, at most `depth` levels deep
+pub fn flatten(input: &Value, depth: &Value) -> Result<Value, RuntimeError> {
+    fn splice(items: &[Value], depth: f64, out: &mut Vec<Value>) {
+        for item in items {
+            match item {
+                Value::Array(inner) if depth > 0.0 => splice(inner, depth - 1.0, out),
+                other => out.push(other.clone()),
+            }
+        }
+    }
+    let depth = match value::as_f64(depth) {
+        Some(depth) if depth < 0.0 => {
+            return Err(RuntimeError::Type(
+                "flatten depth must not be negative".to_string(),
+            ))
+        }
+        Some(depth) => depth,
+        None => {
+            return Err(RuntimeError::Type(format!(
+                "{} is not a valid flatten depth",
+                describe(depth)
+            )))
+        }
+    };
+    let items = match input {
+        Value::Array(items) => items.clone(),
+        Value::Object(entries) => entries.values().cloned().collect(),
+        other => return Err(RuntimeError::CannotIterate(describe(other))),
+    };
+    let mut out = Vec::new();
+    splice(&items, depth, &mut out);
+    Ok(Value::Array(out))
+}
+
+/// The positions where `needle` occurs in the input: codepoint offsets of a
+/// substring, indices of an element, or start indices of a subarray.
+/// Overlapping occurrences are all reported, and an empty needle gives null.
+pub fn indices(input: &Value, needle: &Value) -> Result<Value, RuntimeError> {
+    let positions: Vec<usize> = match (input, needle) {
+        (Value::Null, _) => return Ok(Value::Null),
+        (Value::String(_), Value::String(n)) if n.is_empty() => return Ok(Value::Null),
+        (Value::Array(_), Value::Array(n)) if n.is_empty() => return Ok(Value::Null),
+        (Value::String(s), Value::String(n)) => s
+            .char_indices()
+            .enumerate()
+            .filter(|(_, (byte, _))| s[*byte..].starts_with(n.as_str()))
+            .map(|(i, _)| i)
+            .collect(),
+        (Value::Array(items), Value::Array(n)) => items
+            .windows(n.len())
+            .enumerate()
+            .filter(|(_, window)| compare_all(window, n))
+            .map(|(i, _)| i)
+            .collect(),
+        (Value::Array(items), n) => items
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| compare(item, n) == Ordering::Equal)
+            .map(|(i, _)| i)
+            .collect(),
+        _ => return Err(value::cannot_index(input, needle)),
+    };
+    Ok(Value::Array(
+        positions.into_iter().map(Value::from).collect(),
+    ))
+}
+
+fn compare_all(a: &[Value], b: &[Value]) -> bool {
+    a.iter()
+        .zip(b)
+        .all(|(a, b)| compare(a, b) == Ordering::Equal)
+}
+
+/// The first position of `needle`, or null
+pub fn index(input: &Value, needle: &Value) -> Result<Value, RuntimeError> {
+    Ok(match indices(input, needle)? {
+        Value::Array(positions) => positions.into_iter().next().unwrap_or(Value::Null),
+        other => other,
+    })
+}
+
+/// The last position of `needle`, or null
+pub fn rindex(input: &Value, needle: &Value) -> Result<Value, RuntimeError> {
+    Ok(match indices(input, needle)? {
+        Value::Array(positions) => positions.into_iter().last().unwrap_or(Value::Null),
+        other => other,
+    })
+}
+
+/// Whether `b` is contained in `a`: substrings of strings, every element of
+/// an array contained in some element, every key's value contained
+/// recursively for objects, and equality for everything else
+pub fn contains(a: &Value, b: &Value) -> Result<bool, RuntimeError> {
+    if type_name(a) != type_name(b) {
+        return Err(RuntimeError::Type(format!(
+            "{} and {} cannot have their containment checked",
+            describe(a),
+            describe(b)
+        )));
+    }
+    Ok(contained(a, b))
+}
+
+/// `contains` for nested values, where mismatched types are simply not contained
+fn contained(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Object(a), Value::Object(b)) => b
+            .iter()
+            .all(|(key, b)| a.get(key).is_some_and(|a| contained(a, b))),
+        (Value::Array(a), Value::Array(b)) => b.iter().all(|b| a.iter().any(|a| contained(a, b))),
+        (Value::String(a), Value::String(b)) => a.contains(b.as_str()),
+        _ => type_name(a) == type_name(b) && compare(a, b) == Ordering::Equal,
+    }
+}
+
+/// Swap the rows and columns of an array of arrays, padding short rows with null
+pub fn transpose(input: &Value) -> Result<Value, RuntimeError> {
+    let rows = match input {
+        Value::Array(rows) => rows,
+        other => return Err(RuntimeError::CannotIterate(describe(other))),
+    };
+    let rows = rows
+        .iter()
+        .map(|row| match row {
+            Value::Array(row) => Ok(row.as_slice()),
+            Value::Null => Ok(&[][..]),
+            other => Err(RuntimeError::Type(format!(
+                "{} cannot be transposed, as it is not an array",
+                describe(other)
+            ))),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let width = rows.iter().map(|row| row.len()).max().unwrap_or(0);
+    Ok(Value::Array(
+        (0..width)
+            .map(|i| {
+                Value::Array(
+                    rows.iter()
+                        .map(|row| row.get(i).cloned().unwrap_or(Value::Null))
+                        .collect(),
+                )
+            })
+            .collect(),
+    ))
+}
+
+/// Every way of picking one element from each of a list of arrays, with the
+/// first array's element varying slowest
+pub struct Combinations {
+    choices: Vec<Vec<Value>>,
+    /// The picks of the next combination, or `None` once all are produced
+    picks: Option<Vec<usize>>,
+}
+
+/// The combinations of the elements of each array in the input
+pub fn combinations(input: Value) -> Result<Combinations, RuntimeError> {
+    let choices = value::iterate(input)?
+        .into_iter()
+        .map(value::iterate)
+        .collect::<Result<Vec<_>, _>>()?;
+    let picks = if choices.iter().any(Vec::is_empty) {
+        None
+    } else {
+        Some(vec![0; choices.len()])
+    };
+    Ok(Combinations { choices, picks })
+}
+
+impl Iterator for Combinations {
+    type Item = Value;
+
+    fn next(&mut self) -> Option<Value> {
+        let picks = self.picks.as_mut()?;
+        let combination = picks
+            .iter()
+            .zip(&self.choices)
+            .map(|(&pick, choice)| choice[pick].clone())
+            .collect();
+        // Advance like an odometer, the last pick turning fastest
+        let mut position = picks.len();
+        loop {
+            if position == 0 {
+                self.picks = None;
+                break;
+            }
+            position -= 1;
+            picks[position] += 1;
+            if picks[position] < self.choices[position].len() {
+                break;
+            }
+            picks[position] = 0;
+        }
+        Some(Value::Array(combination))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn keyed(items: Value) -> Keyed {
+        elements(items)
+            .unwrap()
+            .into_iter()
+            .map(|item| (item.clone(), item))
+            .collect()
+    }
+
+    #[test]
+    fn test_sort_group_unique() {
+        let items = json!([3, "a", null, [1], true, {"a": 1}, 1, false, 1.0]);
+        assert_eq!(
+            sort(keyed(items)),
+            json!([null, false, true, 1, 1.0, 3, "a", [1], {"a": 1}])
+        );
+        assert_eq!(
+            group(keyed(json!([2, 1, 2, 3, 1]))),
+            json!([[1, 1], [2, 2], [3]])
+        );
+        assert_eq!(unique(keyed(json!([2, 1, 2, 3, 1]))), json!([1, 2, 3]));
+        assert_eq!(
+            elements(json!("abc")).unwrap_err().to_string(),
+            "string (\"abc\") cannot be sorted, as it is not an array"
+        );
+    }
+
+    #[test]
+    fn test_min_max() {
+        // Ties go to the first element for min and the last for max
+        let keyed: Keyed = vec![
+            (json!(1), json!("a")),
+            (json!(0), json!("b")),
+            (json!(1), json!("c")),
+            (json!(0), json!("d")),
+        ];
+        assert_eq!(min(keyed.clone()), json!("b"));
+        assert_eq!(max(keyed), json!("c"));
+        assert_eq!(min(Vec::new()), json!(null));
+    }
+
+    #[test]
+    fn test_reverse_and_flatten() {
+        assert_eq!(reverse(&json!([1, 2, 3])).unwrap(), json!([3, 2, 1]));
+        assert_eq!(reverse(&json!("abé")).unwrap(), json!("éba"));
+        assert_eq!(reverse(&json!(null)).unwrap(), json!([]));
+        assert!(reverse(&json!({})).is_err());
+
+        let nested = json!([1, [2, [3, [4]]]]);
+        assert_eq!(flatten(&nested, &json!(1e9)).unwrap(), json!([1, 2, 3, 4]));
+        assert_eq!(
+            flatten(&nested, &json!(1)).unwrap(),
+            json!([1, 2, [3, [4]]])
+        );
+        assert_eq!(flatten(&nested, &json!(0)).unwrap(), nested);
+        assert_eq!(
+            flatten(&nested, &json!(-1)).unwrap_err().to_string(),
+            "flatten depth must not be negative"
+        );
+    }
+
+    #[test]
+    fn test_indices() {
+        assert_eq!(
+            indices(&json!("a,b, cd, efg"), &json!(", ")).unwrap(),
+            json!([3, 7])
+        );
+        assert_eq!(
+            indices(&json!("éaaa"), &json!("aa")).unwrap(),
+            json!([1, 2])
+        );
+        assert_eq!(
+            indices(&json!([0, 1, 2, 1, 3, 1, 2]), &json!([1, 2])).unwrap(),
+            json!([1, 5])
+        );
+        assert_eq!(
+            indices(&json!([0, 1, 1]), &json!(1)).unwrap(),
+            json!([1, 2])
+        );
+        assert_eq!(indices(&json!("abc"), &json!("")).unwrap(), json!(null));
+        assert_eq!(index(&json!("abcb"), &json!("b")).unwrap(), json!(1));
+        assert_eq!(rindex(&json!("abcb"), &json!("b")).unwrap(), json!(3));
+        assert_eq!(index(&json!("abc"), &json!("z")).unwrap(), json!(null));
+    }
+
+    #[test]
+    fn test_contains() {
+        let a = json!({"foo": 12, "bar": [1, 2, {"barp": 12, "blip": 13}]});
+        assert!(contains(&a, &json!({"foo": 12, "bar": [{"barp": 12}]})).unwrap());
+        assert!(!contains(&a, &json!({"foo": 12, "bar": [{"barp": 15}]})).unwrap());
+        assert!(contains(&json!(["foobar", "baz"]), &json!(["baz", "bar"])).unwrap());
+        assert!(!contains(&json!(["foobar", "baz"]), &json!(["bazzz"])).unwrap());
+        assert!(contains(&json!(1), &json!(1.0)).unwrap());
+        assert_eq!(
+            contains(&json!("a"), &json!(1)).unwrap_err().to_string(),
+            "string (\"a\") and number (1) cannot have their containment checked"
+        );
+    }
+
+    #[test]
+    fn test_transpose_and_combinations() {
+        assert_eq!(
+            transpose(&json!([[1, 2], [3]])).unwrap(),
+            json!([[1, 3], [2, null]])
+        );
+        assert_eq!(transpose(&json!([])).unwrap(), json!([]));
+
+        let all: Vec<Value> = combinations(json!([[1, 2], [3, 4]])).unwrap().collect();
+        assert_eq!(
+            all,
+            vec![json!([1, 3]), json!([1, 4]), json!([2, 3]), json!([2, 4])]
+        );
+        assert_eq!(
+            combinations(json!([])).unwrap().collect::<Vec<_>>(),
+            vec![json!([])]
+        );
+        assert_eq!(combinations(json!([[1], []])).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,327 @@
+use serde_json::Value;
+
+/// A byte range into the filter source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+
+    /// Smallest span covering both `self` and `other`
+    pub fn to(self, other: Span) -> Span {
+        Span::new(self.start.min(other.start), self.end.max(other.end))
+    }
+}
+
+/// A parsed filter node along with the source range it came from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ast {
+    pub kind: AstKind,
+    pub span: Span,
+}
+
+impl Ast {
+    pub fn new(kind: AstKind, span: Span) -> Self {
+        Ast { kind, span }
+    }
+}
+
+/// Every filter form the parser knows how to build
+#[derive(Debug, Clone, PartialEq)]
+pub enum AstKind {
+    /// `.`
+    Identity,
+    /// `target.name`
+    Field { target: Box<Ast>, name: String },
+    /// `target[index]`, where `index` is evaluated against the original input
+    Index { target: Box<Ast>, index: Box<Ast> },
+    /// `target[start:end]`, either bound may be omitted
+    Slice {
+        target: Box<Ast>,
+        start: Option<Box<Ast>>,
+        end: Option<Box<Ast>>,
+    },
+    /// `target[]`
+    Iterate { target: Box<Ast> },
+    /// `lhs | rhs`
+    Pipe { lhs: Box<Ast>, rhs: Box<Ast> },
+    /// `lhs, rhs`: every output of `lhs` followed by every output of `rhs`
+    Comma { lhs: Box<Ast>, rhs: Box<Ast> },
+    /// `[body]`, collecting all outputs of `body`; `[]` has no body
+    Array(Option<Box<Ast>>),
+    /// `{key: value, ...}`, where each key is a filter producing strings
+    Object(Vec<(Ast, Ast)>),
+    /// `lhs op rhs` for arithmetic, comparison, boolean and alternative operators
+    Binary {
+        op: BinaryOp,
+        lhs: Box<Ast>,
+        rhs: Box<Ast>,
+    },
+    /// `-term`
+    Neg(Box<Ast>),
+    /// `if cond then then_branch else else_branch end`; `elif` chains nest in
+    /// the else branch, and a missing `else` behaves like `else . end`
+    If {
+        cond: Box<Ast>,
+        then_branch: Box<Ast>,
+        else_branch: Option<Box<Ast>>,
+    },
+    /// `try body catch handler`: the outputs of `body` up to its first error,
+    /// then the outputs of `handler` run on that error. Without a handler,
+    /// as in `body?`, the error is suppressed.
+    Try {
+        body: Box<Ast>,
+        catch: Option<Box<Ast>>,
+    },
+    /// `$name`
+    Variable(String),
+    /// `source as pattern | body`: `body` runs on the original input once per
+    /// output of `source`, with the pattern's variables bound
+    Bind {
+        source: Box<Ast>,
+        pattern: Pattern,
+        body: Box<Ast>,
+    },
+    /// `reduce source as pattern (init; update)`
+    Reduce {
+        source: Box<Ast>,
+        pattern: Pattern,
+        init: Box<Ast>,
+        update: Box<Ast>,
+    },
+    /// `foreach source as pattern (init; update; extract)`; without `extract`
+    /// every intermediate state is emitted
+    Foreach {
+        source: Box<Ast>,
+        pattern: Pattern,
+        init: Box<Ast>,
+        update: Box<Ast>,
+        extract: Option<Box<Ast>>,
+    },
+    /// `def name(params): body; rest`, where `def` is visible to `rest` and
+    /// to its own body
+    Def { def: FunctionDef, rest: Box<Ast> },
+    /// `name` or `name(arg; ...)`
+    FunctionCall { name: String, args: Vec<Ast> },
+    /// A constant JSON value written in the filter
+    Literal(Value),
+    /// `@name`: the input formatted as a string
+    Format(String),
+    /// A string literal with `\(...)` interpolations, each formatted with
+    /// `@format` when the literal follows one
+    Interpolation {
+        format: Option<String>,
+        parts: Vec<StringPart>,
+    },
+}
+
+/// A piece of an interpolated string
+#[derive(Debug, Clone, PartialEq)]
+pub enum StringPart {
+    Literal(String),
+    /// A filter whose outputs are spliced into the string
+    Filter(Ast),
+}
+
+/// A filter along with the modules it imports
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub imports: Vec<Import>,
+    pub body: Ast,
+}
+
+/// A library of functions loaded from a `.jq` file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Module {
+    pub imports: Vec<Import>,
+    pub defs: Vec<FunctionDef>,
+}
+
+/// `import "path" as alias;`, or `include "path";` when there is no alias
+#[derive(Debug, Clone, PartialEq)]
+pub struct Import {
+    pub path: String,
+    pub alias: Option<String>,
+    pub span: Span,
+}
+
+/// A function definition
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionDef {
+    pub name: String,
+    pub params: Vec<Param>,
+    pub body: Box<Ast>,
+}
+
+/// A parameter of a function definition
+#[derive(Debug, Clone, PartialEq)]
+pub enum Param {
+    /// `f`: the argument is a filter, run each time the body calls `f`
+    Filter(String),
+    /// `$x`: the body runs once per output of the argument with `$x` bound
+    /// to it; `x` can still be called as a filter
+    Value(String),
+}
+
+impl Param {
+    pub fn name(&self) -> &str {
+        match self {
+            Param::Filter(name) | Param::Value(name) => name,
+        }
+    }
+}
+
+/// The left side of an `as` binding
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    /// `$name`
+    Variable(String),
+    /// `[p0, p1, ...]`, matching array elements by position
+    Array(Vec<Pattern>),
+    /// `{key: p, $name, ...}`
+    Object(Vec<ObjectPattern>),
+}
+
+/// One entry of an object pattern. `$name` binds the whole value at `key`,
+/// and `pattern` destructures it further; at least one of them is present.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectPattern {
+    pub key: Ast,
+    pub variable: Option<String>,
+    pub pattern: Option<Pattern>,
+}
+
+impl Pattern {
+    /// Names of every variable the pattern binds, in order of appearance
+    pub fn variables(&self) -> Vec<&str> {
+        match self {
+            Pattern::Variable(name) => vec![name.as_str()],
+            Pattern::Array(items) => items.iter().flat_map(Pattern::variables).collect(),
+            Pattern::Object(entries) => entries
+                .iter()
+                .flat_map(|entry| {
+                    entry
+                        .variable
+                        .as_deref()
+                        .into_iter()
+                        .chain(entry.pattern.iter().flat_map(Pattern::variables))
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Infix operators, from loosest to tightest binding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    /// `//`
+    Alt,
+    /// `=`: set every path on the left to each value of the right
+    Assign,
+    /// `|=`: replace the value at every path on the left with the first
+    /// output of the right, run on the old value
+    Update,
+    /// `+=`, `-=`, `*=`, `/=`, `%=` and `//=`: combine the value at every
+    /// path on the left with each value of the right
+    AddAssign,
+    SubAssign,
+    MulAssign,
+    DivAssign,
+    ModAssign,
+    AltAssign,
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
+
+/// How operators of equal precedence group
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Assoc {
+    Left,
+    Right,
+    /// `a == b == c` is a syntax error
+    None,
+}
+
+impl BinaryOp {
+    /// Binding power and associativity, following jq's grammar
+    pub fn precedence(self) -> (u8, Assoc) {
+        match self {
+            BinaryOp::Alt => (1, Assoc::Right),
+            BinaryOp::Assign
+            | BinaryOp::Update
+            | BinaryOp::AddAssign
+            | BinaryOp::SubAssign
+            | BinaryOp::MulAssign
+            | BinaryOp::DivAssign
+            | BinaryOp::ModAssign
+            | BinaryOp::AltAssign => (2, Assoc::None),
+            BinaryOp::Or => (3, Assoc::Left),
+            BinaryOp::And => (4, Assoc::Left),
+            BinaryOp::Eq
+            | BinaryOp::Ne
+            | BinaryOp::Lt
+            | BinaryOp::Le
+            | BinaryOp::Gt
+            | BinaryOp::Ge => (5, Assoc::None),
+            BinaryOp::Add | BinaryOp::Sub => (6, Assoc::Left),
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod => (7, Assoc::Left),
+        }
+    }
+
+    /// For `op=` operators, the operator that combines the old and new values
+    pub fn updating(self) -> Option<BinaryOp> {
+        match self {
+            BinaryOp::AddAssign => Some(BinaryOp::Add),
+            BinaryOp::SubAssign => Some(BinaryOp::Sub),
+            BinaryOp::MulAssign => Some(BinaryOp::Mul),
+            BinaryOp::DivAssign => Some(BinaryOp::Div),
+            BinaryOp::ModAssign => Some(BinaryOp::Mod),
+            BinaryOp::AltAssign => Some(BinaryOp::Alt),
+            _ => None,
+        }
+    }
+
+    /// The operator as written in a filter
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Alt => "//",
+            BinaryOp::Assign => "=",
+            BinaryOp::Update => "|=",
+            BinaryOp::AddAssign => "+=",
+            BinaryOp::SubAssign => "-=",
+            BinaryOp::MulAssign => "*=",
+            BinaryOp::DivAssign => "/=",
+            BinaryOp::ModAssign => "%=",
+            BinaryOp::AltAssign => "//=",
+            BinaryOp::Or => "or",
+            BinaryOp::And => "and",
+            BinaryOp::Eq => "==",
+            BinaryOp::Ne => "!=",
+            BinaryOp::Lt => "<",
+            BinaryOp::Le => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::Ge => ">=",
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Mod => "%",
+        }
+    }
+}
@@ -0,0 +1,1806 @@
+use crate::arrays::{self, Keyed};
+use crate::ast::Ast;
+use crate::env::Env;
+use crate::error::RuntimeError;
+use crate::eval::{bind, eval, many, once, Stream, ValueStream};
+use crate::input;
+use crate::ops;
+use crate::path::{self, eval_paths, PathStream, PathValue};
+use crate::regexp::{self, Found, Matcher};
+use crate::strings;
+use crate::time;
+use crate::value;
+use serde_json::{Map, Value};
+use std::iter;
+use std::mem;
+use std::rc::Rc;
+
+/// Names and arities of every builtin function
+const BUILTINS: &[(&str, usize)] = &[
+    ("add", 0),
+    ("length", 0),
+    ("del", 1),
+    ("not", 0),
+    ("select", 1),
+    ("map", 1),
+    ("map_values", 1),
+    ("empty", 0),
+    ("error", 0),
+    ("error", 1),
+    ("limit", 2),
+    ("first", 0),
+    ("first", 1),
+    ("last", 0),
+    ("last", 1),
+    ("until", 2),
+    ("while", 2),
+    ("range", 1),
+    ("range", 2),
+    ("range", 3),
+    ("path", 1),
+    ("paths", 0),
+    ("paths", 1),
+    ("getpath", 1),
+    ("setpath", 2),
+    ("delpaths", 1),
+    ("to_entries", 0),
+    ("from_entries", 0),
+    ("with_entries", 1),
+    ("recurse", 0),
+    ("recurse", 1),
+    ("recurse", 2),
+    ("walk", 1),
+    ("env", 0),
+    ("leaf_paths", 0),
+    ("tostring", 0),
+    ("tonumber", 0),
+    ("split", 1),
+    ("split", 2),
+    ("splits", 1),
+    ("splits", 2),
+    ("join", 1),
+    ("ltrimstr", 1),
+    ("rtrimstr", 1),
+    ("startswith", 1),
+    ("endswith", 1),
+    ("ascii_downcase", 0),
+    ("ascii_upcase", 0),
+    ("explode", 0),
+    ("implode", 0),
+    ("test", 1),
+    ("test", 2),
+    ("match", 1),
+    ("match", 2),
+    ("capture", 1),
+    ("capture", 2),
+    ("scan", 1),
+    ("scan", 2),
+    ("sub", 2),
+    ("sub", 3),
+    ("gsub", 2),
+    ("gsub", 3),
+    ("sort", 0),
+    ("sort_by", 1),
+    ("group_by", 1),
+    ("unique", 0),
+    ("unique_by", 1),
+    ("min", 0),
+    ("max", 0),
+    ("min_by", 1),
+    ("max_by", 1),
+    ("reverse", 0),
+    ("flatten", 0),
+    ("flatten", 1),
+    ("index", 1),
+    ("rindex", 1),
+    ("indices", 1),
+    ("inside", 1),
+    ("contains", 1),
+    ("any", 0),
+    ("any", 1),
+    ("any", 2),
+    ("all", 0),
+    ("all", 1),
+    ("all", 2),
+    ("transpose", 0),
+    ("combinations", 0),
+    ("combinations", 1),
+    ("keys", 0),
+    ("keys_unsorted", 0),
+    ("has", 1),
+    ("in", 1),
+    ("values", 0),
+    ("nulls", 0),
+    ("booleans", 0),
+    ("numbers", 0),
+    ("strings", 0),
+    ("arrays", 0),
+    ("objects", 0),
+    ("iterables", 0),
+    ("scalars", 0),
+    ("type", 0),
+    ("now", 0),
+    ("gmtime", 0),
+    ("mktime", 0),
+    ("strftime", 1),
+    ("strptime", 1),
+    ("todate", 0),
+    ("fromdate", 0),
+    ("todateiso8601", 0),
+    ("fromdateiso8601", 0),
+    ("input", 0),
+    ("inputs", 0),
+];
+
+/// The builtins that pass on their input only if it has a certain type
+const SELECTORS: &[&str] = &[
+    "values",
+    "nulls",
+    "booleans",
+    "numbers",
+    "strings",
+    "arrays",
+    "objects",
+    "iterables",
+    "scalars",
+];
+
+/// Names of the variables that are always defined
+const VARIABLES: &[&str] = &["ENV"];
+
+/// Whether a builtin named `name` takes `arity` arguments
+pub fn is_defined(name: &str, arity: usize) -> bool {
+    BUILTINS.contains(&(name, arity))
+}
+
+/// Whether `$name` is a builtin variable
+pub fn is_variable(name: &str) -> bool {
+    VARIABLES.contains(&name)
+}
+
+/// The value of the builtin variable `$name`
+pub fn variable(name: &str) -> Option<Value> {
+    match name {
+        "ENV" => Some(environment()),
+        _ => None,
+    }
+}
+
+/// Call the builtin `name` with unevaluated `args` on `input`
+pub fn call<'a>(name: &str, args: &'a [Ast], env: &Env<'a>, input: Value) -> ValueStream<'a> {
+    match (name, args) {
+        ("add", []) => once(add(input)),
+        ("length", []) => once(value::length(&input)),
+        ("del", [f]) => once(del(f, env, input)),
+        ("not", []) => once(Ok(Value::Bool(!ops::is_truthy(&input)))),
+        ("select", [f]) => bind(eval(f, env, input.clone()), move |keep| {
+            if ops::is_truthy(&keep) {
+                once(Ok(input.clone()))
+            } else {
+                Box::new(iter::empty())
+            }
+        }),
+        ("map", [f]) => once(map(f, env, input)),
+        ("map_values", [f]) => once(map_values(f, env, input)),
+        ("empty", []) => Box::new(iter::empty()),
+        ("error", []) => once(Err(RuntimeError::User(input))),
+        ("error", [message]) => bind(eval(message, env, input), |message| {
+            once(Err(RuntimeError::User(message)))
+        }),
+        ("limit", [n, f]) => {
+            let env = env.clone();
+            bind(eval(n, &env, input.clone()), move |n| {
+                match limit_count(&n) {
+                    Ok(n) => Box::new(eval(f, &env, input.clone()).take(n)),
+                    Err(err) => once(Err(err)),
+                }
+            })
+        }
+        ("first", []) => once(value::index(&input, &Value::from(0))),
+        ("first", [f]) => Box::new(eval(f, env, input).take(1)),
+        ("last", []) => match input {
+            Value::Array(items) => once(Ok(items.last().cloned().unwrap_or(Value::Null))),
+            other => once(value::index(&other, &Value::from(-1))),
+        },
+        ("last", [f]) => match eval(f, env, input).try_fold(None, |_, result| result.map(Some)) {
+            Ok(Some(last)) => once(Ok(last)),
+            Ok(None) => Box::new(iter::empty()),
+            Err(err) => once(Err(err)),
+        },
+        ("until", [cond, update]) => {
+            let env = env.clone();
+            unfold(input, move |state| {
+                let env = env.clone();
+                Box::new(
+                    eval(cond, &env, state.clone()).flat_map(move |done| match done {
+                        Ok(done) if ops::is_truthy(&done) => step(Ok(Step::Emit(state.clone()))),
+                        Ok(_) => Box::new(
+                            eval(update, &env, state.clone()).map(|next| next.map(Step::Recurse)),
+                        ),
+                        Err(err) => step(Err(err)),
+                    }),
+                )
+            })
+        }
+        ("while", [cond, update]) => {
+            let env = env.clone();
+            unfold(input, move |state| {
+                let env = env.clone();
+                Box::new(eval(cond, &env, state.clone()).flat_map(
+                    move |keep| -> StepStream<'a, Value> {
+                        match keep {
+                            Ok(keep) if ops::is_truthy(&keep) => Box::new(
+                                iter::once(Ok(Step::Emit(state.clone()))).chain(
+                                    eval(update, &env, state.clone())
+                                        .map(|next| next.map(Step::Recurse)),
+                                ),
+                            ),
+                            Ok(_) => Box::new(iter::empty()),
+                            Err(err) => step(Err(err)),
+                        }
+                    },
+                ))
+            })
+        }
+        ("range", [upto]) => bind(eval(upto, env, input), |upto| {
+            range(Value::from(0), upto, Value::from(1))
+        }),
+        ("range", [from, upto]) => {
+            let env = env.clone();
+            bind(eval(from, &env, input.clone()), move |from| {
+                bind(eval(upto, &env, input.clone()), move |upto| {
+                    range(from.clone(), upto, Value::from(1))
+                })
+            })
+        }
+        ("range", [from, upto, by]) => {
+            let env = env.clone();
+            bind(eval(from, &env, input.clone()), move |from| {
+                let env = env.clone();
+                let input = input.clone();
+                bind(eval(upto, &env, input.clone()), move |upto| {
+                    let from = from.clone();
+                    bind(eval(by, &env, input.clone()), move |by| {
+                        range(from.clone(), upto.clone(), by)
+                    })
+                })
+            })
+        }
+        ("path", [f]) => Box::new(
+            eval_paths(f, env, (Vec::new(), input))
+                .map(|result| result.map(|(path, _)| Value::Array(path))),
+        ),
+        ("paths", []) => Box::new(
+            all_paths(&input)
+                .into_iter()
+                .map(|path| Ok(Value::Array(path))),
+        ),
+        ("paths", [f]) => {
+            let env = env.clone();
+            Box::new(all_paths(&input).into_iter().flat_map(move |keys| {
+                match path::get_path(&input, &keys) {
+                    Ok(node) => bind(eval(f, &env, node), move |keep| {
+                        if ops::is_truthy(&keep) {
+                            once(Ok(Value::Array(keys.clone())))
+                        } else {
+                            Box::new(iter::empty())
+                        }
+                    }),
+                    Err(err) => once(Err(err)),
+                }
+            }))
+        }
+        ("getpath", [p]) => bind(eval(p, env, input.clone()), move |p| {
+            once(path_keys(&p).and_then(|keys| path::get_path(&input, keys)))
+        }),
+        ("setpath", [p, v]) => {
+            let env = env.clone();
+            bind(eval(p, &env, input.clone()), move |p| {
+                let input = input.clone();
+                bind(eval(v, &env, input.clone()), move |v| {
+                    let mut value = input.clone();
+                    once(
+                        path_keys(&p)
+                            .and_then(|keys| path::set_path(&mut value, keys, v))
+                            .map(|_| value),
+                    )
+                })
+            })
+        }
+        ("delpaths", [ps]) => bind(eval(ps, env, input.clone()), move |ps| {
+            once(delpaths(input.clone(), ps))
+        }),
+        ("to_entries", []) => once(to_entries(input)),
+        ("from_entries", []) => once(from_entries(input)),
+        ("with_entries", [f]) => once(
+            to_entries(input)
+                .and_then(|entries| map(f, env, entries))
+                .and_then(from_entries),
+        ),
+        ("recurse", []) => unfold(input, |value| {
+            let children = children(&value);
+            Box::new(
+                iter::once(Ok(Step::Emit(value))).chain(
+                    children
+                        .into_iter()
+                        .map(|(_, child)| Ok(Step::Recurse(child))),
+                ),
+            )
+        }),
+        ("recurse", [f]) => {
+            let env = env.clone();
+            unfold(input, move |value| {
+                Box::new(
+                    iter::once(Ok(Step::Emit(value.clone())))
+                        .chain(eval(f, &env, value).map(|child| child.map(Step::Recurse))),
+                )
+            })
+        }
+        ("recurse", [f, cond]) => {
+            let env = env.clone();
+            unfold(input, move |value| {
+                let env = env.clone();
+                let children = bind(eval(f, &env, value.clone()), move |child| {
+                    bind(eval(cond, &env, child.clone()), move |keep| {
+                        if ops::is_truthy(&keep) {
+                            once(Ok(child.clone()))
+                        } else {
+                            Box::new(iter::empty())
+                        }
+                    })
+                });
+                Box::new(
+                    iter::once(Ok(Step::Emit(value)))
+                        .chain(children.map(|child| child.map(Step::Recurse))),
+                )
+            })
+        }
+        ("walk", [f]) => walk(f, env, input),
+        ("env", []) => once(Ok(environment())),
+        ("leaf_paths", []) => Box::new(
+            all_paths(&input)
+                .into_iter()
+                .filter(move |keys| {
+                    path::get_path(&input, keys)
+                        .is_ok_and(|node| !matches!(node, Value::Array(_) | Value::Object(_)))
+                })
+                .map(|keys| Ok(Value::Array(keys))),
+        ),
+        ("tostring", []) => once(Ok(Value::String(strings::tostring(&input)))),
+        ("tonumber", []) => once(strings::tonumber(&input)),
+        ("split", [separator]) => with_arg(separator, env, input, strings::split),
+        ("join", [separator]) => with_arg(separator, env, input, strings::join),
+        ("ltrimstr", [prefix]) => with_arg(prefix, env, input, |input, prefix| {
+            Ok(strings::ltrimstr(input, prefix))
+        }),
+        ("rtrimstr", [suffix]) => with_arg(suffix, env, input, |input, suffix| {
+            Ok(strings::rtrimstr(input, suffix))
+        }),
+        ("startswith", [prefix]) => with_arg(prefix, env, input, strings::startswith),
+        ("endswith", [suffix]) => with_arg(suffix, env, input, strings::endswith),
+        ("ascii_downcase", []) => once(strings::ascii_downcase(&input)),
+        ("ascii_upcase", []) => once(strings::ascii_upcase(&input)),
+        ("explode", []) => once(strings::explode(&input)),
+        ("implode", []) => once(strings::implode(&input)),
+        ("test", [re, flags @ ..]) => with_regex(re, flags.first(), env, input, |text, matcher| {
+            once(Ok(Value::Bool(matcher.is_match(text))))
+        }),
+        ("match", [re, flags @ ..]) => {
+            with_regex(re, flags.first(), env, input, |text, matcher| {
+                many(
+                    matcher
+                        .find(text)
+                        .into_iter()
+                        .map(|found| found.object)
+                        .collect(),
+                )
+            })
+        }
+        ("capture", [re, flags @ ..]) => {
+            with_regex(re, flags.first(), env, input, |text, matcher| {
+                many(matcher.find(text).iter().map(Found::captures).collect())
+            })
+        }
+        ("scan", [re, flags @ ..]) => with_regex(re, flags.first(), env, input, |text, matcher| {
+            many(
+                matcher
+                    .global()
+                    .find(text)
+                    .iter()
+                    .map(Found::scanned)
+                    .collect(),
+            )
+        }),
+        ("split", [re, flags]) => with_regex(re, Some(flags), env, input, |text, matcher| {
+            once(Ok(regexp::split(text, &matcher.global().find(text))))
+        }),
+        ("splits", [re, flags @ ..]) => with_regex(
+            re,
+            flags.first(),
+            env,
+            input,
+            |text, matcher| match regexp::split(text, &matcher.global().find(text)) {
+                Value::Array(pieces) => many(pieces),
+                other => once(Ok(other)),
+            },
+        ),
+        ("sub" | "gsub", [re, replacement, flags @ ..]) => {
+            let global = name == "gsub";
+            let replace_env = env.clone();
+            with_regex(re, flags.first(), env, input, move |text, matcher| {
+                let matcher = if global { matcher.global() } else { matcher };
+                match substitute(text, &matcher.find(text), replacement, &replace_env) {
+                    Ok(results) => many(results),
+                    Err(err) => once(Err(err)),
+                }
+            })
+        }
+        ("sort", []) => once(keyed(None, env, input).map(arrays::sort)),
+        ("sort_by", [f]) => once(keyed(Some(f), env, input).map(arrays::sort)),
+        ("group_by", [f]) => once(keyed(Some(f), env, input).map(arrays::group)),
+        ("unique", []) => once(keyed(None, env, input).map(arrays::unique)),
+        ("unique_by", [f]) => once(keyed(Some(f), env, input).map(arrays::unique)),
+        ("min", []) => once(keyed(None, env, input).map(arrays::min)),
+        ("max", []) => once(keyed(None, env, input).map(arrays::max)),
+        ("min_by", [f]) => once(keyed(Some(f), env, input).map(arrays::min)),
+        ("max_by", [f]) => once(keyed(Some(f), env, input).map(arrays::max)),
+        ("reverse", []) => once(arrays::reverse(&input)),
+        ("flatten", []) => once(arrays::flatten(&input, &Value::from(1e9))),
+        ("flatten", [depth]) => with_arg(depth, env, input, arrays::flatten),
+        ("index", [needle]) => with_arg(needle, env, input, arrays::index),
+        ("rindex", [needle]) => with_arg(needle, env, input, arrays::rindex),
+        ("indices", [needle]) => with_arg(needle, env, input, arrays::indices),
+        ("contains", [b]) => with_arg(b, env, input, |a, b| {
+            arrays::contains(a, b).map(Value::Bool)
+        }),
+        ("inside", [b]) => with_arg(b, env, input, |a, b| {
+            arrays::contains(b, a).map(Value::Bool)
+        }),
+        ("any" | "all", []) => once(
+            value::iterate(input).and_then(|items| quantify(name == "all", many(items), None, env)),
+        ),
+        ("any" | "all", [cond]) => once(
+            value::iterate(input)
+                .and_then(|items| quantify(name == "all", many(items), Some(cond), env)),
+        ),
+        ("any" | "all", [generator, cond]) => once(quantify(
+            name == "all",
+            eval(generator, env, input),
+            Some(cond),
+            env,
+        )),
+        ("transpose", []) => once(arrays::transpose(&input)),
+        ("combinations", []) => match arrays::combinations(input) {
+            Ok(combinations) => Box::new(combinations.map(Ok)),
+            Err(err) => once(Err(err)),
+        },
+        ("combinations", [n]) => bind(eval(n, env, input.clone()), move |n| {
+            let repeated = count(&n).and_then(|times| {
+                // Every copy holds the input's elements as well as the input
+                let len = match &input {
+                    Value::Array(items) => items.len() + 1,
+                    _ => 1,
+                };
+                if !value::fits(times, len * mem::size_of::<Value>()) {
+                    return Err(RuntimeError::Type(format!(
+                        "combinations/1 count {} is too large",
+                        n
+                    )));
+                }
+                Ok(Value::Array(vec![input.clone(); times]))
+            });
+            match repeated.and_then(arrays::combinations) {
+                Ok(combinations) => Box::new(combinations.map(Ok)),
+                Err(err) => once(Err(err)),
+            }
+        }),
+        ("keys" | "keys_unsorted", []) => once(value::keys(&input, name == "keys")),
+        ("has", [key]) => with_arg(key, env, input, value::has),
+        ("in", [object]) => with_arg(object, env, input, |key, object| value::has(object, key)),
+        (selector, []) if SELECTORS.contains(&selector) => {
+            if value::selected_by(selector, &input) {
+                once(Ok(input))
+            } else {
+                Box::new(iter::empty())
+            }
+        }
+        ("type", []) => once(Ok(Value::from(value::type_name(&input)))),
+        ("input", []) => once(
+            next_input().unwrap_or_else(|| Err(RuntimeError::Type("No more inputs".to_string()))),
+        ),
+        ("inputs", []) => Box::new(iter::from_fn(next_input)),
+        ("now", []) => once(Ok(time::now())),
+        ("gmtime", []) => once(time::gmtime(&input)),
+        ("mktime", []) => once(time::mktime(&input)),
+        ("strftime", [format]) => with_arg(format, env, input, time::strftime),
+        ("strptime", [format]) => with_arg(format, env, input, time::strptime),
+        ("todate" | "todateiso8601", []) => {
+            once(time::strftime(&input, &Value::from(time::ISO8601)))
+        }
+        ("fromdate" | "fromdateiso8601", []) => once(
+            time::strptime(&input, &Value::from(time::ISO8601)).and_then(|tm| time::mktime(&tm)),
+        ),
+        _ => once(Err(RuntimeError::Type(format!(
+            "{}/{} is not defined",
+            name,
+            args.len()
+        )))),
+    }
+}
+
+/// Call the builtin `name` as a path expression, or `None` if it cannot
+/// produce paths
+pub fn call_paths<'a>(
+    name: &str,
+    args: &'a [Ast],
+    env: &Env<'a>,
+    (path, current): PathValue,
+) -> Option<PathStream<'a>> {
+    let paths: PathStream<'a> = match (name, args) {
+        ("select", [f]) => path::bind_values(eval(f, env, current.clone()), move |keep| {
+            if ops::is_truthy(&keep) {
+                path::once(Ok((path.clone(), current.clone())))
+            } else {
+                Box::new(iter::empty())
+            }
+        }),
+        ("empty", []) => Box::new(iter::empty()),
+        (selector, []) if SELECTORS.contains(&selector) => {
+            if value::selected_by(selector, &current) {
+                path::once(Ok((path, current)))
+            } else {
+                Box::new(iter::empty())
+            }
+        }
+        ("limit", [n, f]) => {
+            let env = env.clone();
+            path::bind_values(eval(n, &env, current.clone()), move |n| {
+                match limit_count(&n) {
+                    Ok(n) => Box::new(eval_paths(f, &env, (path.clone(), current.clone())).take(n)),
+                    Err(err) => path::once(Err(err)),
+                }
+            })
+        }
+        ("first", [f]) => Box::new(eval_paths(f, env, (path, current)).take(1)),
+        ("last", [f]) => {
+            match eval_paths(f, env, (path, current)).try_fold(None, |_, result| result.map(Some)) {
+                Ok(Some(last)) => path::once(Ok(last)),
+                Ok(None) => Box::new(iter::empty()),
+                Err(err) => path::once(Err(err)),
+            }
+        }
+        ("getpath", [p]) => path::bind_values(eval(p, env, current.clone()), move |p| {
+            let found = path_keys(&p).and_then(|keys| {
+                let mut full = path.clone();
+                full.extend_from_slice(keys);
+                Ok((full, path::get_path(&current, keys)?))
+            });
+            path::once(found)
+        }),
+        ("recurse", []) => unfold((path, current), |(path, value)| {
+            let children = children(&value);
+            Box::new(iter::once(Ok(Step::Emit((path.clone(), value)))).chain(
+                children.into_iter().map(move |(key, child)| {
+                    let mut child_path = path.clone();
+                    child_path.push(key);
+                    Ok(Step::Recurse((child_path, child)))
+                }),
+            ))
+        }),
+        ("recurse", [f]) => {
+            let env = env.clone();
+            unfold((path, current), move |path_value| {
+                Box::new(
+                    iter::once(Ok(Step::Emit(path_value.clone()))).chain(
+                        eval_paths(f, &env, path_value).map(|child| child.map(Step::Recurse)),
+                    ),
+                )
+            })
+        }
+        ("recurse", [f, cond]) => {
+            let env = env.clone();
+            unfold((path, current), move |path_value| {
+                let env = env.clone();
+                let children =
+                    path::bind_paths(eval_paths(f, &env, path_value.clone()), move |child| {
+                        path::bind_values(eval(cond, &env, child.1.clone()), move |keep| {
+                            if ops::is_truthy(&keep) {
+                                path::once(Ok(child.clone()))
+                            } else {
+                                Box::new(iter::empty())
+                            }
+                        })
+                    });
+                Box::new(
+                    iter::once(Ok(Step::Emit(path_value)))
+                        .chain(children.map(|child| child.map(Step::Recurse))),
+                )
+            })
+        }
+        ("error", []) => path::once(Err(RuntimeError::User(current))),
+        ("error", [message]) => path::bind_values(eval(message, env, current), |message| {
+            path::once(Err(RuntimeError::User(message)))
+        }),
+        _ => return None,
+    };
+    Some(paths)
+}
+
+/// What a step of `unfold` produces: an output, or a value to expand further
+enum Step<T> {
+    Emit(T),
+    Recurse(T),
+}
+
+type StepStream<'a, T> = Box<dyn Iterator<Item = Result<Step<T>, RuntimeError>> + 'a>;
+
+fn step<'a, T: 'a>(result: Result<Step<T>, RuntimeError>) -> StepStream<'a, T> {
+    Box::new(iter::once(result))
+}
+
+/// Depth-first expansion of `start` with `expand`, kept on an explicit stack
+/// so that long-running loops like `until` do not grow the call stack
+struct Unfold<'a, T, F> {
+    stack: Vec<StepStream<'a, T>>,
+    expand: F,
+}
+
+impl<'a, T, F> Iterator for Unfold<'a, T, F>
+where
+    F: FnMut(T) -> StepStream<'a, T>,
+{
+    type Item = Result<T, RuntimeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.stack.last_mut()?.next() {
+                None => {
+                    self.stack.pop();
+                }
+                Some(Ok(Step::Emit(value))) => return Some(Ok(value)),
+                Some(Ok(Step::Recurse(value))) => {
+                    let children = (self.expand)(value);
+                    self.stack.push(children);
+                }
+                Some(Err(err)) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+fn unfold<'a, T: 'a, F>(start: T, mut expand: F) -> Stream<'a, T>
+where
+    F: FnMut(T) -> StepStream<'a, T> + 'a,
+{
+    let first = expand(start);
+    Box::new(Unfold {
+        stack: vec![first],
+        expand,
+    })
+}
+
+/// A non-negative count such as the first argument of `limit`
+fn count(n: &Value) -> Result<usize, RuntimeError> {
+    match value::as_f64(n) {
+        Some(n) if n > 0.0 => Ok(n.ceil() as usize),
+        Some(_) => Ok(0),
+        None => Err(RuntimeError::Type(format!(
+            "Invalid limit {}: must be a number",
+            value::describe(n)
+        ))),
+    }
+}
+
+/// How many outputs `limit` keeps. A negative limit keeps all of them, as
+/// in jq 1.7.
+fn limit_count(n: &Value) -> Result<usize, RuntimeError> {
+    match value::as_f64(n) {
+        Some(n) if n < 0.0 => Ok(usize::MAX),
+        _ => count(n),
+    }
+}
+
+/// The numbers from `from` up to (but excluding) `upto` in steps of `by`,
+/// produced lazily so that huge ranges can be cut short by `limit`. A zero
+/// step produces nothing, as in jq.
+fn range<'a>(from: Value, upto: Value, by: Value) -> ValueStream<'a> {
+    let (from, upto, by) = match (
+        value::as_f64(&from),
+        value::as_f64(&upto),
+        value::as_f64(&by),
+    ) {
+        (Some(from), Some(upto), Some(by)) => (from, upto, by),
+        _ => {
+            return once(Err(RuntimeError::Type(
+                "Range bounds must be numeric".to_string(),
+            )))
+        }
+    };
+    Box::new(
+        iter::successors(Some(from), move |n| Some(n + by))
+            .take_while(move |&n| {
+                if by > 0.0 {
+                    n < upto
+                } else if by < 0.0 {
+                    n > upto
+                } else {
+                    false
+                }
+            })
+            .map(|n| Ok(value::number(n))),
+    )
+}
+
+/// `[.[] | f]`
+fn map<'a>(f: &'a Ast, env: &Env<'a>, input: Value) -> Result<Value, RuntimeError> {
+    let mut mapped = Vec::new();
+    for item in value::iterate(input)? {
+        for result in eval(f, env, item) {
+            mapped.push(result?);
+        }
+    }
+    Ok(Value::Array(mapped))
+}
+
+/// Replace each value of an array or object with the first output of `f`,
+/// dropping entries for which `f` produces nothing
+fn map_values<'a>(f: &'a Ast, env: &Env<'a>, input: Value) -> Result<Value, RuntimeError> {
+    match input {
+        Value::Array(items) => {
+            let mut mapped = Vec::new();
+            for item in items {
+                if let Some(result) = eval(f, env, item).next() {
+                    mapped.push(result?);
+                }
+            }
+            Ok(Value::Array(mapped))
+        }
+        Value::Object(entries) => {
+            let mut mapped = Map::new();
+            for (key, item) in entries {
+                if let Some(result) = eval(f, env, item).next() {
+                    mapped.insert(key, result?);
+                }
+            }
+            Ok(Value::Object(mapped))
+        }
+        other => Err(RuntimeError::CannotIterate(value::describe(&other))),
+    }
+}
+
+/// Sum the elements of an array (or the values of an object) with `+`
+fn add(input: Value) -> Result<Value, RuntimeError> {
+    if input.is_null() {
+        return Ok(Value::Null);
+    }
+    value::iterate(input)?
+        .into_iter()
+        .try_fold(Value::Null, ops::add)
+}
+
+/// The elements of an array paired with their sort keys: the array of
+/// outputs of `f`, or the element itself when there is no `f`
+fn keyed<'a>(f: Option<&'a Ast>, env: &Env<'a>, input: Value) -> Result<Keyed, RuntimeError> {
+    arrays::elements(input)?
+        .into_iter()
+        .map(|item| {
+            let key = match f {
+                Some(f) => Value::Array(eval(f, env, item.clone()).collect::<Result<_, _>>()?),
+                None => item.clone(),
+            };
+            Ok((key, item))
+        })
+        .collect()
+}
+
+/// `any` (or `all` when `all` is set) over `values`, or over the outputs of
+/// `cond` for each of them, stopping at the first value that decides it
+fn quantify<'a>(
+    all: bool,
+    values: ValueStream<'a>,
+    cond: Option<&'a Ast>,
+    env: &Env<'a>,
+) -> Result<Value, RuntimeError> {
+    let truths = match cond {
+        Some(cond) => {
+            let env = env.clone();
+            bind(values, move |value| eval(cond, &env, value))
+        }
+        None => values,
+    };
+    for truth in truths {
+        if ops::is_truthy(&truth?) != all {
+            return Ok(Value::Bool(!all));
+        }
+    }
+    Ok(Value::Bool(all))
+}
+
+/// Call `f` with the input and each output of `arg`
+fn with_arg<'a>(
+    arg: &'a Ast,
+    env: &Env<'a>,
+    input: Value,
+    f: fn(&Value, &Value) -> Result<Value, RuntimeError>,
+) -> ValueStream<'a> {
+    bind(eval(arg, env, input.clone()), move |arg| {
+        once(f(&input, &arg))
+    })
+}
+
+/// Compile each combination of the regex and flags arguments of a regex
+/// builtin, calling `f` with the input string and the compiled regex
+fn with_regex<'a, F>(
+    re: &'a Ast,
+    flags: Option<&'a Ast>,
+    env: &Env<'a>,
+    input: Value,
+    f: F,
+) -> ValueStream<'a>
+where
+    F: Fn(&str, Matcher) -> ValueStream<'a> + 'a,
+{
+    let env = env.clone();
+    let f = Rc::new(f);
+    bind(eval(re, &env, input.clone()), move |re| {
+        let flags = match flags {
+            Some(flags) => eval(flags, &env, input.clone()),
+            None => once(Ok(Value::Null)),
+        };
+        let input = input.clone();
+        let f = f.clone();
+        bind(flags, move |flags| {
+            let compiled = Matcher::new(&re, &flags)
+                .and_then(|matcher| Ok((regexp::subject(&input)?, matcher)));
+            match compiled {
+                Ok((text, matcher)) => f(text, matcher),
+                Err(err) => once(Err(err)),
+            }
+        })
+    })
+}
+
+/// Replace each match in `text` with the output of `replacement`, which runs
+/// on an object of the match's named captures. A replacement with several
+/// outputs produces one string for each combination.
+fn substitute<'a>(
+    text: &str,
+    found: &[Found],
+    replacement: &'a Ast,
+    env: &Env<'a>,
+) -> Result<Vec<Value>, RuntimeError> {
+    let mut results = vec![String::new()];
+    let mut last = 0;
+    for found in found {
+        let outputs = eval(replacement, env, found.captures())
+            .map(|output| match output? {
+                Value::String(output) => Ok(output),
+                other => Err(RuntimeError::Type(format!(
+                    "{} cannot be added to a string",
+                    value::describe(&other)
+                ))),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let between = &text[last..found.start];
+        results = results
+            .iter()
+            .flat_map(|result| {
+                outputs
+                    .iter()
+                    .map(move |output| format!("{}{}{}", result, between, output))
+            })
+            .collect();
+        last = found.end;
+    }
+    Ok(results
+        .into_iter()
+        .map(|result| Value::String(result + &text[last..]))
+        .collect())
+}
+
+/// The keys and values directly inside an array or object
+fn children(value: &Value) -> Vec<(Value, Value)> {
+    match value {
+        Value::Array(items) => items
+            .iter()
+            .enumerate()
+            .map(|(i, item)| (Value::from(i), item.clone()))
+            .collect(),
+        Value::Object(entries) => entries
+            .iter()
+            .map(|(key, item)| (Value::String(key.clone()), item.clone()))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Apply `f` to every value inside `input`, innermost first, then to the
+/// rebuilt input itself
+fn walk<'a>(f: &'a Ast, env: &Env<'a>, input: Value) -> ValueStream<'a> {
+    let rebuilt = match input {
+        Value::Array(items) => items
+            .into_iter()
+            .map(|item| walk(f, env, item))
+            .try_fold(Vec::new(), |mut walked, outputs| {
+                for output in outputs {
+                    walked.push(output?);
+                }
+                Ok(walked)
+            })
+            .map(Value::Array),
+        Value::Object(entries) => entries
+            .into_iter()
+            .try_fold(Map::new(), |mut walked, (key, item)| {
+                if let Some(output) = walk(f, env, item).next() {
+                    walked.insert(key, output?);
+                }
+                Ok(walked)
+            })
+            .map(Value::Object),
+        other => Ok(other),
+    };
+    match rebuilt {
+        Ok(rebuilt) => eval(f, env, rebuilt),
+        Err(err) => once(Err(err)),
+    }
+}
+
+/// The next value not yet read by the main loop or by `input`
+fn next_input() -> Option<Result<Value, RuntimeError>> {
+    Some(
+        input::next()?
+            .map(|document| document.value)
+            .map_err(RuntimeError::Input),
+    )
+}
+
+/// The process environment as an object, for `$ENV` and `env`
+fn environment() -> Value {
+    Value::Object(
+        std::env::vars_os()
+            .map(|(name, value)| {
+                let value = value.to_string_lossy().into_owned();
+                (name.to_string_lossy().into_owned(), Value::String(value))
+            })
+            .collect(),
+    )
+}
+
+/// The keys of a path given as an argument to `getpath` or `setpath`
+fn path_keys(path: &Value) -> Result<&[Value], RuntimeError> {
+    match path {
+        Value::Array(keys) => Ok(keys),
+        _ => Err(RuntimeError::Type(
+            "Path must be specified as an array".to_string(),
+        )),
+    }
+}
+
+/// The path to every value inside `value`, parents before their children
+fn all_paths(value: &Value) -> Vec<Vec<Value>> {
+    fn visit(value: &Value, prefix: &mut Vec<Value>, out: &mut Vec<Vec<Value>>) {
+        let children: Vec<(Value, &Value)> = match value {
+            Value::Array(items) => items
+                .iter()
+                .enumerate()
+                .map(|(i, item)| (Value::from(i), item))
+                .collect(),
+            Value::Object(entries) => entries
+                .iter()
+                .map(|(key, item)| (Value::String(key.clone()), item))
+                .collect(),
+            _ => return,
+        };
+        for (key, child) in children {
+            prefix.push(key);
+            out.push(prefix.clone());
+            visit(child, prefix, out);
+            prefix.pop();
+        }
+    }
+    let mut out = Vec::new();
+    visit(value, &mut Vec::new(), &mut out);
+    out
+}
+
+/// Remove each path in the array `paths` from `input`
+fn delpaths(input: Value, paths: Value) -> Result<Value, RuntimeError> {
+    let paths = match paths {
+        Value::Array(paths) => paths,
+        _ => {
+            return Err(RuntimeError::Type(
+                "Paths must be specified as an array".to_string(),
+            ))
+        }
+    };
+    let paths = paths
+        .iter()
+        .map(|p| path_keys(p).map(<[Value]>::to_vec))
+        .collect::<Result<_, _>>()?;
+    path::delete_paths(input, paths)
+}
+
+/// `{"key": k, "value": v}` for each key of an object or index of an array
+fn to_entries(input: Value) -> Result<Value, RuntimeError> {
+    let entry = |key: Value, value: Value| {
+        let mut entry = Map::new();
+        entry.insert("key".to_string(), key);
+        entry.insert("value".to_string(), value);
+        Value::Object(entry)
+    };
+    let entries = match input {
+        Value::Object(map) => map
+            .into_iter()
+            .map(|(key, value)| entry(Value::String(key), value))
+            .collect(),
+        Value::Array(items) => items
+            .into_iter()
+            .enumerate()
+            .map(|(i, value)| entry(Value::from(i), value))
+            .collect(),
+        other => {
+            return Err(RuntimeError::Type(format!(
+                "{} has no keys",
+                value::describe(&other)
+            )))
+        }
+    };
+    Ok(Value::Array(entries))
+}
+
+/// Build an object from entries, accepting the same key and value names as jq
+/// (`key`, `k`, `name`, `Name`, `K`, `Key`; `value`, `v`)
+fn from_entries(input: Value) -> Result<Value, RuntimeError> {
+    let mut object = Map::new();
+    for entry in value::iterate(input)? {
+        let mut key = value::index(&entry, &Value::from("key"))?;
+        if key.is_null() {
+            for name in ["k", "name", "Name", "K", "Key"] {
+                key = value::index(&entry, &Value::from(name))?;
+                if ops::is_truthy(&key) {
+                    break;
+                }
+            }
+        }
+        let key = match key {
+            Value::String(key) => key,
+            other => other.to_string(),
+        };
+        let value = match &entry {
+            Value::Object(map) if map.contains_key("value") => map["value"].clone(),
+            _ => value::index(&entry, &Value::from("v"))?,
+        };
+        object.insert(key, value);
+    }
+    Ok(Value::Object(object))
+}
+
+/// Remove every path selected by `f` from the input
+fn del<'a>(f: &'a Ast, env: &Env<'a>, input: Value) -> Result<Value, RuntimeError> {
+    let paths = path::paths(f, env, input.clone())?;
+    path::delete_paths(input, paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::env::Env;
+    use crate::eval::eval;
+    use crate::parser::parse_program;
+    use serde_json::{json, Value};
+
+    fn run(filter: &str, input: Value) -> Result<Vec<Value>, String> {
+        eval(&parse_program(filter).unwrap().body, &Env::new(), input)
+            .collect::<Result<_, _>>()
+            .map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn test_add() {
+        assert_eq!(
+            run("add", json!(["one", "two", "three"])),
+            Ok(vec![json!("onetwothree")])
+        );
+        assert_eq!(run("add", json!([1, 2, 3.5])), Ok(vec![json!(6.5)]));
+        assert_eq!(run("add", json!([[1], [2]])), Ok(vec![json!([1, 2])]));
+        assert_eq!(run("add", json!({"a": 1, "b": 2})), Ok(vec![json!(3)]));
+        assert_eq!(run("add", json!([])), Ok(vec![json!(null)]));
+        assert_eq!(run("add", json!(null)), Ok(vec![json!(null)]));
+        assert_eq!(
+            run("add", json!([1, "a"])),
+            Err("number (1) and string (\"a\") cannot be added".to_string())
+        );
+        assert_eq!(
+            run("add", json!(5)),
+            Err("Cannot iterate over number (5)".to_string())
+        );
+    }
+
+    #[test]
+    fn test_length() {
+        assert_eq!(
+            run(". | length", json!(["one", "two", "three"])),
+            Ok(vec![json!(3)])
+        );
+        assert_eq!(
+            run(".[] | length", json!(["ab", null, -3])),
+            Ok(vec![json!(2), json!(0), json!(3)])
+        );
+    }
+
+    #[test]
+    fn test_del() {
+        assert_eq!(
+            run(
+                ". | del(.fizzes)",
+                json!({"fizz": "buzz", "fizzes": [1, 2]})
+            ),
+            Ok(vec![json!({"fizz": "buzz"})])
+        );
+        assert_eq!(run("del(.[0])", json!([1, 2, 3])), Ok(vec![json!([2, 3])]));
+        assert_eq!(run("del(.[1:])", json!([1, 2, 3])), Ok(vec![json!([1])]));
+        assert_eq!(run("del(.[])", json!([1, 2, 3])), Ok(vec![json!([])]));
+        assert_eq!(
+            run("del(.a.b)", json!({"a": {"b": 1, "c": 2}})),
+            Ok(vec![json!({"a": {"c": 2}})])
+        );
+        assert_eq!(
+            run("del(.a, .c)", json!({"a": 1, "b": 2, "c": 3})),
+            Ok(vec![json!({"b": 2})])
+        );
+        assert_eq!(run("del(.[0, 2])", json!([1, 2, 3])), Ok(vec![json!([2])]));
+        assert_eq!(
+            run("del(.missing)", json!({"a": 1})),
+            Ok(vec![json!({"a": 1})])
+        );
+        assert_eq!(
+            run("del(.a)", json!([1])),
+            Err("Cannot index array with \"a\"".to_string())
+        );
+    }
+
+    #[test]
+    fn test_paths() {
+        let input = json!({"a": [1, {"b": 2}], "c": null});
+        assert_eq!(
+            run("[path(.a[], .c)]", input.clone()),
+            Ok(vec![json!([["a", 0], ["a", 1], ["c"]])])
+        );
+        assert_eq!(
+            run("[paths]", input.clone()),
+            Ok(vec![json!([
+                ["a"],
+                ["a", 0],
+                ["a", 1],
+                ["a", 1, "b"],
+                ["c"]
+            ])])
+        );
+        assert_eq!(
+            run("[paths(. == 2)]", input),
+            Ok(vec![json!([["a", 1, "b"]])])
+        );
+        assert_eq!(run("[paths]", json!(1)), Ok(vec![json!([])]));
+        assert_eq!(
+            run("path(1)", json!(null)),
+            Err("Invalid path expression with result number (1)".to_string())
+        );
+    }
+
+    #[test]
+    fn test_getpath_setpath_delpaths() {
+        let input = json!({"a": {"b": [1, 2]}});
+        assert_eq!(
+            run("getpath([\"a\", \"b\", 1])", input.clone()),
+            Ok(vec![json!(2)])
+        );
+        assert_eq!(
+            run("getpath([\"x\", \"y\"])", input.clone()),
+            Ok(vec![json!(null)])
+        );
+        assert_eq!(
+            run("getpath([\"a\", 0])", input.clone()),
+            Err("Cannot index object with number".to_string())
+        );
+        assert_eq!(
+            run("setpath([\"a\", \"b\", 0]; 9)", input.clone()),
+            Ok(vec![json!({"a": {"b": [9, 2]}})])
+        );
+        assert_eq!(run("setpath([]; 1)", input.clone()), Ok(vec![json!(1)]));
+        assert_eq!(
+            run("delpaths([[\"a\", \"b\", 0], [\"z\"]])", input.clone()),
+            Ok(vec![json!({"a": {"b": [2]}})])
+        );
+        assert_eq!(
+            run("getpath(\"a\")", input.clone()),
+            Err("Path must be specified as an array".to_string())
+        );
+        assert_eq!(
+            run("delpaths([\"a\"])", input.clone()),
+            Err("Path must be specified as an array".to_string())
+        );
+        assert_eq!(
+            run("path(getpath([\"a\", \"b\"]))", input),
+            Ok(vec![json!(["a", "b"])])
+        );
+    }
+
+    #[test]
+    fn test_entries() {
+        assert_eq!(
+            run("to_entries", json!({"a": 1, "b": 2})),
+            Ok(vec![
+                json!([{"key": "a", "value": 1}, {"key": "b", "value": 2}])
+            ])
+        );
+        assert_eq!(
+            run("to_entries", json!(["x"])),
+            Ok(vec![json!([{"key": 0, "value": "x"}])])
+        );
+        assert_eq!(
+            run(
+                "from_entries",
+                json!([{"name": "a", "v": 1}, {"k": 2, "value": null}, {"key": null}])
+            ),
+            Ok(vec![json!({"a": 1, "2": null, "null": null})])
+        );
+        assert_eq!(
+            run("with_entries(.value += 1)", json!({"a": 1, "b": 2})),
+            Ok(vec![json!({"a": 2, "b": 3})])
+        );
+        assert_eq!(
+            run(
+                "with_entries(select(.key != \"b\"))",
+                json!({"a": 1, "b": 2})
+            ),
+            Ok(vec![json!({"a": 1})])
+        );
+        assert_eq!(
+            run("to_entries", json!(1)),
+            Err("number (1) has no keys".to_string())
+        );
+    }
+
+    #[test]
+    fn test_recurse() {
+        let annoying = json!({"really": {"annoying": {"dictionary": ["am", "i"]}}});
+        assert_eq!(run("[..] | length", annoying.clone()), Ok(vec![json!(6)]));
+        assert_eq!(
+            run("[.. | select(. == \"i\")]", annoying.clone()),
+            Ok(vec![json!(["i"])])
+        );
+        assert_eq!(
+            run("[path(..)]", json!({"a": [1]})),
+            Ok(vec![json!([[], ["a"], ["a", 0]])])
+        );
+        assert_eq!(
+            run("[recurse(if . < 3 then . + 1 else empty end)]", json!(0)),
+            Ok(vec![json!([0, 1, 2, 3])])
+        );
+        assert_eq!(
+            run("[recurse(. * .; . < 100)]", json!(2)),
+            Ok(vec![json!([2, 4, 16])])
+        );
+        assert_eq!(
+            run("(.. | select(. == 1)) |= 10", json!({"a": [1, {"b": 1}]})),
+            Ok(vec![json!({"a": [10, {"b": 10}]})])
+        );
+        // Deep chains are expanded without growing the call stack
+        assert_eq!(
+            run(
+                "last(recurse(if . < 100000 then . + 1 else empty end))",
+                json!(0)
+            ),
+            Ok(vec![json!(100000)])
+        );
+    }
+
+    #[test]
+    fn test_walk() {
+        assert_eq!(
+            run(
+                "walk(if . == 1 then 2 else . end)",
+                json!({"a": [1, {"b": 1}], "c": 3})
+            ),
+            Ok(vec![json!({"a": [2, {"b": 2}], "c": 3})])
+        );
+        // Children are walked before their parents
+        assert_eq!(
+            run("[walk(length)]", json!([[1, 2], [3]])),
+            Ok(vec![json!([2])])
+        );
+        assert_eq!(
+            run("walk(select(. != 2))", json!({"a": 2, "b": [2, 3]})),
+            Ok(vec![json!({"b": [3]})])
+        );
+    }
+
+    #[test]
+    fn test_env_and_leaf_paths() {
+        let path = std::env::var("PATH").unwrap_or_default();
+        assert_eq!(run("$ENV.PATH", json!(null)), Ok(vec![json!(path)]));
+        assert_eq!(run("env.PATH", json!(null)), Ok(vec![json!(path)]));
+        assert_eq!(
+            run("[leaf_paths]", json!({"a": [1, {"b": null}], "c": {}})),
+            Ok(vec![json!([["a", 0], ["a", 1, "b"]])])
+        );
+    }
+
+    #[test]
+    fn test_string_builtins() {
+        assert_eq!(
+            run("split(\", \") | join(\"/\")", json!("a, b, c")),
+            Ok(vec![json!("a/b/c")])
+        );
+        assert_eq!(
+            run(
+                "[.[] | ltrimstr(\"x\") | rtrimstr(\"y\")]",
+                json!(["xay", "b", 1])
+            ),
+            Ok(vec![json!(["a", "b", 1])])
+        );
+        assert_eq!(
+            run("startswith(\"ab\"), endswith(\"ab\")", json!("abc")),
+            Ok(vec![json!(true), json!(false)])
+        );
+        assert_eq!(
+            run("ascii_upcase, ascii_downcase", json!("Hi")),
+            Ok(vec![json!("HI"), json!("hi")])
+        );
+        assert_eq!(
+            run("explode | map(. + 1) | implode", json!("HAL")),
+            Ok(vec![json!("IBM")])
+        );
+        assert_eq!(
+            run("map(tostring)", json!([1, "1", [1]])),
+            Ok(vec![json!(["1", "1", "[1]"])])
+        );
+        assert_eq!(
+            run("map(tonumber)", json!(["4.0", 2])),
+            Ok(vec![json!([4.0, 2])])
+        );
+    }
+
+    #[test]
+    fn test_regex_builtins() {
+        assert_eq!(
+            run("[.[] | test(\"^a.c$\"; \"i\")]", json!(["ABC", "abd"])),
+            Ok(vec![json!([true, false])])
+        );
+        assert_eq!(
+            run("[match(\"a+\"; \"g\") | .offset]", json!("aa-a")),
+            Ok(vec![json!([0, 3])])
+        );
+        assert_eq!(
+            run(
+                "capture(\"(?<y>\\\\d{4})-(?<m>\\\\d{2})\")",
+                json!("2024-05")
+            ),
+            Ok(vec![json!({"y": "2024", "m": "05"})])
+        );
+        assert_eq!(
+            run("[scan(\"c\")], [scan(\"(a)(b)\")]", json!("abcabc")),
+            Ok(vec![json!(["c", "c"]), json!([["a", "b"], ["a", "b"]])])
+        );
+        assert_eq!(
+            run("split(\", *\"; null)", json!("a, b,c")),
+            Ok(vec![json!(["a", "b", "c"])])
+        );
+        assert_eq!(
+            run("sub(\"(?<x>[a-z]+)\"; \"<\\(.x)>\")", json!("ab cd")),
+            Ok(vec![json!("<ab> cd")])
+        );
+        assert_eq!(
+            run("gsub(\"(?<x>[a-z]+)\"; \"<\\(.x)>\")", json!("ab cd")),
+            Ok(vec![json!("<ab> <cd>")])
+        );
+        assert_eq!(
+            run("[sub(\"b\"; \"1\", \"2\")]", json!("abc")),
+            Ok(vec![json!(["a1c", "a2c"])])
+        );
+        assert_eq!(
+            run("gsub(\"\"; \"-\")", json!("ab")),
+            Ok(vec![json!("-a-b-")])
+        );
+        assert_eq!(
+            run("test(\"a\")", json!(1)),
+            Err("number (1) cannot be matched, as it is not a string".to_string())
+        );
+        assert_eq!(
+            run("sub(\"a\"; 1)", json!("a")),
+            Err("number (1) cannot be added to a string".to_string())
+        );
+    }
+
+    #[test]
+    fn test_sorting_builtins() {
+        let players = json!([
+            {"name": "b", "team": 2},
+            {"name": "a", "team": 1},
+            {"name": "c", "team": 2}
+        ]);
+        assert_eq!(
+            run("sort_by(.name) | map(.name)", players.clone()),
+            Ok(vec![json!(["a", "b", "c"])])
+        );
+        assert_eq!(
+            run("group_by(.team) | map(map(.name))", players.clone()),
+            Ok(vec![json!([["a"], ["b", "c"]])])
+        );
+        assert_eq!(
+            run("unique_by(.team) | map(.name)", players.clone()),
+            Ok(vec![json!(["a", "b"])])
+        );
+        assert_eq!(
+            run("min_by(.team).name, max_by(.team).name", players),
+            Ok(vec![json!("a"), json!("c")])
+        );
+        // Keys are the arrays of every output, so later outputs break ties
+        assert_eq!(
+            run("sort_by(.[0], .[1])", json!([[1, 2], [0, 5], [1, 1]])),
+            Ok(vec![json!([[0, 5], [1, 1], [1, 2]])])
+        );
+        assert_eq!(
+            run("sort, unique, min, max", json!([3, 1, 3])),
+            Ok(vec![json!([1, 3, 3]), json!([1, 3]), json!(1), json!(3)])
+        );
+        assert_eq!(
+            run("sort", json!({"a": 1})),
+            Err("object ({\"a\":1}) cannot be sorted, as it is not an array".to_string())
+        );
+    }
+
+    #[test]
+    fn test_array_builtins() {
+        assert_eq!(
+            run("reverse, flatten, flatten(1)", json!([1, [2, [3]]])),
+            Ok(vec![
+                json!([[2, [3]], 1]),
+                json!([1, 2, 3]),
+                json!([1, 2, [3]])
+            ])
+        );
+        assert_eq!(
+            run("index(\"b\"), rindex(\"b\"), indices(\"b\")", json!("abcb")),
+            Ok(vec![json!(1), json!(3), json!([1, 3])])
+        );
+        assert_eq!(
+            run(
+                "contains([\"ba\"]), inside([\"foo\", \"bar\", 1])",
+                json!(["foo", "bar"])
+            ),
+            Ok(vec![json!(true), json!(true)])
+        );
+        assert_eq!(
+            run("transpose", json!([[1, 2], [3]])),
+            Ok(vec![json!([[1, 3], [2, null]])])
+        );
+        assert_eq!(
+            run("[combinations]", json!([[1, 2], [3]])),
+            Ok(vec![json!([[1, 3], [2, 3]])])
+        );
+        assert_eq!(
+            run("[combinations(2)]", json!([0, 1])),
+            Ok(vec![json!([[0, 0], [0, 1], [1, 0], [1, 1]])])
+        );
+        assert_eq!(
+            run("[combinations(1e19)]", json!([0, 1])),
+            Err("combinations/1 count 1e+19 is too large".to_string())
+        );
+    }
+
+    #[test]
+    fn test_any_and_all() {
+        assert_eq!(
+            run("any, all", json!([true, false])),
+            Ok(vec![json!(true), json!(false)])
+        );
+        assert_eq!(
+            run("any, all", json!([])),
+            Ok(vec![json!(false), json!(true)])
+        );
+        assert_eq!(
+            run("any(. > 2), all(. > 0)", json!([1, 2, 3])),
+            Ok(vec![json!(true), json!(true)])
+        );
+        // The generator is cut short once the answer is known
+        assert_eq!(
+            run("any(range(10), error(\"x\"); . == 3)", json!(null)),
+            Ok(vec![json!(true)])
+        );
+        assert_eq!(
+            run("all(.[]; .a)", json!([{"a": 1}, {}])),
+            Ok(vec![json!(false)])
+        );
+    }
+
+    #[test]
+    fn test_object_builtins() {
+        let object = json!({"b": 1, "a": null});
+        assert_eq!(
+            run("keys, keys_unsorted", object.clone()),
+            Ok(vec![json!(["a", "b"]), json!(["b", "a"])])
+        );
+        assert_eq!(
+            run("has(\"a\"), has(\"c\")", object.clone()),
+            Ok(vec![json!(true), json!(false)])
+        );
+        assert_eq!(
+            run(".[] | in({\"b\": 0})", json!(["a", "b"])),
+            Ok(vec![json!(false), json!(true)])
+        );
+        assert_eq!(run("[.[] | values]", object), Ok(vec![json!([1])]));
+        assert_eq!(
+            run("map(type)", json!([null, true, 1, "s", [], {}])),
+            Ok(vec![json!([
+                "null", "boolean", "number", "string", "array", "object"
+            ])])
+        );
+    }
+
+    #[test]
+    fn test_type_selectors() {
+        let mixed = json!([null, false, 2, "s", [3], {"n": 4}]);
+        assert_eq!(
+            run(
+                "[.[] | nulls], [.[] | booleans], [.[] | numbers], [.[] | strings]",
+                mixed.clone()
+            ),
+            Ok(vec![
+                json!([null]),
+                json!([false]),
+                json!([2]),
+                json!(["s"])
+            ])
+        );
+        assert_eq!(
+            run("[.[] | iterables], [.[] | scalars | arrays]", mixed.clone()),
+            Ok(vec![json!([[3], {"n": 4}]), json!([])])
+        );
+        // Selectors work as paths, so they can pick what to update
+        assert_eq!(
+            run("(.. | numbers) |= . * 10", mixed),
+            Ok(vec![json!([null, false, 20, "s", [30], {"n": 40}])])
+        );
+    }
+
+    #[test]
+    fn test_time_builtins() {
+        let span = json!({"start": "2015-03-05T23:51:47Z", "end": "2015-03-06T01:02:03Z"});
+        assert_eq!(
+            run("(.end | fromdate) - (.start | fromdate)", span.clone()),
+            Ok(vec![json!(4216)])
+        );
+        assert_eq!(
+            run(".start | fromdate | todate", span.clone()),
+            Ok(vec![json!("2015-03-05T23:51:47Z")])
+        );
+        assert_eq!(
+            run(
+                ".end | strptime(\"%Y-%m-%dT%H:%M:%SZ\") | strftime(\"%a %d %b %H:%M\")",
+                span
+            ),
+            Ok(vec![json!("Fri 06 Mar 01:02")])
+        );
+        assert_eq!(
+            run("gmtime | mktime", json!(1425599621.75)),
+            Ok(vec![json!(1425599621)])
+        );
+        assert_eq!(
+            run("gmtime", json!(1425599621)),
+            Ok(vec![json!([2015, 2, 5, 23, 53, 41, 4, 63])])
+        );
+        assert_eq!(
+            run("try todate catch .", json!(1e300)),
+            Ok(vec![json!("strftime/1 time is out of range")])
+        );
+        assert_eq!(run("now | type", json!(null)), Ok(vec![json!("number")]));
+        assert_eq!(
+            run("try fromdate catch .", json!("yesterday")),
+            Ok(vec![json!(
+                "date \"yesterday\" does not match format \"%Y-%m-%dT%H:%M:%SZ\""
+            )])
+        );
+    }
+
+    #[test]
+    fn test_input_builtins() {
+        use crate::error::{InputError, RuntimeError};
+        use crate::input::{self, Document, Location};
+
+        let document = |value: Value, line| Document {
+            value,
+            location: Location {
+                source: "in.json".to_string(),
+                line,
+                column: 1,
+            },
+        };
+        input::provide(vec![Ok(document(json!(2), 1)), Ok(document(json!(3), 2))].into_iter());
+        assert_eq!(run("[., input]", json!(1)), Ok(vec![json!([1, 2])]));
+        assert_eq!(run("[inputs]", json!(null)), Ok(vec![json!([3])]));
+        assert_eq!(
+            run("try input catch .", json!(null)),
+            Ok(vec![json!("No more inputs")])
+        );
+        // Inputs that cannot be read end the run rather than being caught
+        let parse_error = || {
+            Err(InputError::Parse {
+                location: Location {
+                    source: "in.json".to_string(),
+                    line: 1,
+                    column: 3,
+                },
+                message: "EOF while parsing an object".to_string(),
+            })
+        };
+        let message = "could not parse in.json:1:3: EOF while parsing an object".to_string();
+        for filter in ["try input catch .", "input // 1", "[inputs]"] {
+            input::provide(vec![parse_error()].into_iter());
+            let ast = parse_program(filter).unwrap().body;
+            let first = eval(&ast, &Env::new(), json!(null)).next();
+            match first {
+                Some(Err(RuntimeError::Input(err))) => assert_eq!(err.to_string(), message),
+                other => panic!("{} produced {:?}", filter, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_select() {
+        let students = json!([
+            {"name": "Ann", "age": 22},
+            {"name": "Bob", "age": 19},
+            {"name": "Cid", "age": 31}
+        ]);
+        assert_eq!(
+            run(".[] | select(.age > 20) | .name", students),
+            Ok(vec![json!("Ann"), json!("Cid")])
+        );
+        assert_eq!(
+            run("select(true, true, false)", json!(1)),
+            Ok(vec![json!(1), json!(1)])
+        );
+        assert_eq!(
+            run("del(.[] | select(. == 2))", json!([1, 2, 3, 2])),
+            Ok(vec![json!([1, 3])])
+        );
+    }
+
+    #[test]
+    fn test_limit_first_last() {
+        assert_eq!(
+            run("[limit(3; .[])]", json!([1, 2, 3, 4])),
+            Ok(vec![json!([1, 2, 3])])
+        );
+        assert_eq!(run("[limit(0; 1, 2)]", json!(null)), Ok(vec![json!([])]));
+        // A negative limit keeps every output
+        assert_eq!(
+            run("[limit(-1; 1, 2)]", json!(null)),
+            Ok(vec![json!([1, 2])])
+        );
+        assert_eq!(
+            run("[path(limit(-1; .[]))]", json!([5, 6])),
+            Ok(vec![json!([[0], [1]])])
+        );
+        assert_eq!(run("first(.[])", json!([5, 6])), Ok(vec![json!(5)]));
+        assert_eq!(run("last(.[])", json!([5, 6])), Ok(vec![json!(6)]));
+        assert_eq!(run("[first(empty)]", json!(null)), Ok(vec![json!([])]));
+        assert_eq!(
+            run("first, last", json!([1, 2])),
+            Ok(vec![json!(1), json!(2)])
+        );
+        assert_eq!(run("last", json!([])), Ok(vec![json!(null)]));
+        // Later outputs are never computed, so the error is not reached
+        assert_eq!(
+            run("first(1, error(\"boom\"))", json!(null)),
+            Ok(vec![json!(1)])
+        );
+        assert_eq!(
+            run("del(first(.[] | select(. > 1)))", json!([1, 2, 3])),
+            Ok(vec![json!([1, 3])])
+        );
+    }
+
+    #[test]
+    fn test_until_and_while() {
+        assert_eq!(run("until(. > 100; . * 2)", json!(1)), Ok(vec![json!(128)]));
+        assert_eq!(
+            run("[while(. < 10; . + 3)]", json!(1)),
+            Ok(vec![json!([1, 4, 7])])
+        );
+        assert_eq!(
+            run("until(. == 100000; . + 1)", json!(0)),
+            Ok(vec![json!(100000)])
+        );
+    }
+
+    #[test]
+    fn test_range() {
+        assert_eq!(
+            run("[range(4)]", json!(null)),
+            Ok(vec![json!([0, 1, 2, 3])])
+        );
+        assert_eq!(
+            run("[range(2; 5)]", json!(null)),
+            Ok(vec![json!([2, 3, 4])])
+        );
+        assert_eq!(
+            run("[range(0; 1; 0.25)]", json!(null)),
+            Ok(vec![json!([0, 0.25, 0.5, 0.75])])
+        );
+        assert_eq!(
+            run("[range(5; 0; -2)]", json!(null)),
+            Ok(vec![json!([5, 3, 1])])
+        );
+        assert_eq!(
+            run("[range(0, 1; 3, 4)]", json!(null)),
+            Ok(vec![json!([0, 1, 2, 0, 1, 2, 3, 1, 2, 1, 2, 3])])
+        );
+        assert_eq!(
+            run("range(\"a\")", json!(null)),
+            Err("Range bounds must be numeric".to_string())
+        );
+        // Huge and endless ranges stop as soon as enough values were taken
+        assert_eq!(
+            run("[limit(3; range(1e18))]", json!(null)),
+            Ok(vec![json!([0, 1, 2])])
+        );
+        assert_eq!(run("[range(0; 1; 0)]", json!(null)), Ok(vec![json!([])]));
+        assert_eq!(run("[range(1; 0; 0)]", json!(null)), Ok(vec![json!([])]));
+    }
+
+    #[test]
+    fn test_map() {
+        assert_eq!(
+            run("map(. * 2)", json!([1, 2, 3])),
+            Ok(vec![json!([2, 4, 6])])
+        );
+        assert_eq!(
+            run("map(., .)", json!({"a": 1, "b": 2})),
+            Ok(vec![json!([1, 1, 2, 2])])
+        );
+        assert_eq!(
+            run("map(.)", json!(1)),
+            Err("Cannot iterate over number (1)".to_string())
+        );
+    }
+
+    #[test]
+    fn test_map_values() {
+        assert_eq!(
+            run("map_values(. + 1)", json!({"a": 1, "b": 2})),
+            Ok(vec![json!({"a": 2, "b": 3})])
+        );
+        assert_eq!(
+            run("map_values(empty)", json!({"a": 1})),
+            Ok(vec![json!({})])
+        );
+        assert_eq!(
+            run("map_values(., 10)", json!([1, 2])),
+            Ok(vec![json!([1, 2])])
+        );
+    }
+
+    #[test]
+    fn test_empty_and_error() {
+        assert_eq!(
+            run("1, empty, 2", json!(null)),
+            Ok(vec![json!(1), json!(2)])
+        );
+        assert_eq!(
+            run("error(\"bad input\")", json!(null)),
+            Err("bad input".to_string())
+        );
+        assert_eq!(
+            run("error", json!({"code": 1})),
+            Err("{\"code\":1} (not a string)".to_string())
+        );
+        assert_eq!(
+            run(
+                ".[] | if . > 1 then error(\"too big\") else . end",
+                json!([1, 2])
+            ),
+            Err("too big".to_string())
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use crate::ast::{Ast, FunctionDef};
+use serde_json::Value;
+use std::rc::Rc;
+
+/// The variables and functions in scope for a filter. Bindings form a
+/// persistent linked list, so extending an environment is cheap and never
+/// disturbs the branches that share its tail.
+#[derive(Debug, Clone, Default)]
+pub struct Env<'a>(Option<Rc<Frame<'a>>>);
+
+#[derive(Debug)]
+struct Frame<'a> {
+    binding: Binding<'a>,
+    parent: Env<'a>,
+}
+
+#[derive(Debug)]
+enum Binding<'a> {
+    Variable(String, Value),
+    Function(&'a FunctionDef),
+    /// A filter argument: `body` runs in the environment of the call site
+    Closure(&'a str, &'a Ast, Env<'a>),
+    /// The definitions of a module, called as `alias::name`, or by their
+    /// plain names when the module was included
+    Module(Option<&'a str>, Env<'a>),
+}
+
+/// A user-defined function or filter argument found by [`Env::function`]
+pub enum Function<'a> {
+    /// A `def`, along with the environment it was defined in (which includes
+    /// the definition itself, so it can recurse)
+    Defined(&'a FunctionDef, Env<'a>),
+    /// A filter argument, to be run in the caller's environment
+    Closure(&'a Ast, Env<'a>),
+}
+
+impl<'a> Env<'a> {
+    pub fn new() -> Self {
+        Env(None)
+    }
+
+    fn push(&self, binding: Binding<'a>) -> Env<'a> {
+        Env(Some(Rc::new(Frame {
+            binding,
+            parent: self.clone(),
+        })))
+    }
+
+    /// A new environment with `$name` bound to `value`, shadowing any outer binding
+    pub fn bind(&self, name: impl Into<String>, value: Value) -> Env<'a> {
+        self.push(Binding::Variable(name.into(), value))
+    }
+
+    /// A new environment in which `def` can be called
+    pub fn define(&self, def: &'a FunctionDef) -> Env<'a> {
+        self.push(Binding::Function(def))
+    }
+
+    /// A new environment exposing the functions defined in `module`
+    pub fn import(&self, alias: Option<&'a str>, module: Env<'a>) -> Env<'a> {
+        self.push(Binding::Module(alias, module))
+    }
+
+    /// A new environment where calling `name` runs `body` in `env`
+    pub fn bind_closure(&self, name: &'a str, body: &'a Ast, env: Env<'a>) -> Env<'a> {
+        self.push(Binding::Closure(name, body, env))
+    }
+
+    fn frames(&self) -> impl Iterator<Item = (&Binding<'a>, &Env<'a>)> {
+        let mut env = self;
+        std::iter::from_fn(move || {
+            let frame = env.0.as_ref()?;
+            let current = env;
+            env = &frame.parent;
+            Some((&frame.binding, current))
+        })
+    }
+
+    /// The innermost value bound to `$name`
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.frames().find_map(|(binding, _)| match binding {
+            Binding::Variable(bound, value) if bound == name => Some(value),
+            _ => None,
+        })
+    }
+
+    /// The innermost function called `name` that takes `arity` arguments
+    pub fn function(&self, name: &str, arity: usize) -> Option<Function<'a>> {
+        self.frames().find_map(|(binding, env)| match binding {
+            Binding::Function(def) if def.name == name && def.params.len() == arity => {
+                Some(Function::Defined(def, env.clone()))
+            }
+            Binding::Closure(bound, body, closure_env) if *bound == name && arity == 0 => {
+                Some(Function::Closure(body, closure_env.clone()))
+            }
+            Binding::Module(None, module) => module.function(name, arity),
+            Binding::Module(Some(alias), module) => name
+                .strip_prefix(alias)
+                .and_then(|rest| rest.strip_prefix("::"))
+                .and_then(|rest| module.function(rest, arity)),
+            _ => None,
+        })
+    }
+
+    /// Names of every bound variable, innermost first
+    pub fn variables(&self) -> Vec<&str> {
+        self.frames()
+            .filter_map(|(binding, _)| match binding {
+                Binding::Variable(name, _) => Some(name.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::{AstKind, Span};
+    use serde_json::json;
+
+    #[test]
+    fn test_shadowing() {
+        let outer = Env::new().bind("x", json!(1)).bind("y", json!(2));
+        let inner = outer.bind("x", json!(3));
+        assert_eq!(inner.get("x"), Some(&json!(3)));
+        assert_eq!(inner.get("y"), Some(&json!(2)));
+        assert_eq!(outer.get("x"), Some(&json!(1)));
+        assert_eq!(outer.get("z"), None);
+        assert_eq!(inner.variables(), vec!["x", "y", "x"]);
+    }
+
+    #[test]
+    fn test_functions_by_arity() {
+        let body = Ast::new(AstKind::Identity, Span::new(0, 1));
+        let def = FunctionDef {
+            name: "f".to_string(),
+            params: Vec::new(),
+            body: Box::new(body.clone()),
+        };
+        let env = Env::new().define(&def).bind("f", json!(1));
+        assert!(matches!(env.function("f", 0), Some(Function::Defined(..))));
+        assert!(env.function("f", 1).is_none());
+
+        let env = env.bind_closure("f", &body, Env::new());
+        assert!(matches!(env.function("f", 0), Some(Function::Closure(..))));
+        assert_eq!(env.variables(), vec!["f"]);
+    }
+
+    #[test]
+    fn test_modules() {
+        let def = FunctionDef {
+            name: "f".to_string(),
+            params: Vec::new(),
+            body: Box::new(Ast::new(AstKind::Identity, Span::new(0, 1))),
+        };
+        let module = Env::new().define(&def);
+        let imported = Env::new().import(Some("lib"), module.clone());
+        assert!(imported.function("lib::f", 0).is_some());
+        assert!(imported.function("f", 0).is_none());
+        assert!(imported.function("other::f", 0).is_none());
+        assert!(imported.function("libf", 0).is_none());
+
+        let included = Env::new().import(None, module);
+        assert!(included.function("f", 0).is_some());
+    }
+}
@@ -0,0 +1,513 @@
+use crate::ast::Span;
+use crate::input::Location;
+use serde_json::Value;
+use std::fmt;
+use std::path::PathBuf;
+
+/// What went wrong while reading a filter
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseErrorKind {
+    /// A character that cannot start any token
+    UnexpectedChar(char),
+    /// A numeric literal that could not be read
+    InvalidNumber(String),
+    /// A string literal with no closing quote
+    UnterminatedString,
+    /// A backslash escape that JSON does not define
+    InvalidEscape(String),
+    /// The grammar required one thing and the filter had another
+    Expected { expected: String, found: String },
+    /// A bracket or parenthesis that was never closed
+    Unclosed {
+        close: String,
+        construct: &'static str,
+        opened_column: usize,
+        found: String,
+    },
+    /// Two non-associative operators such as `==` used side by side
+    NonAssociative {
+        first: &'static str,
+        second: &'static str,
+    },
+    /// Tokens left over after a complete filter
+    Trailing(String),
+    /// A call to a function with no definition for that name and arity
+    UndefinedFunction { name: String, arity: usize },
+    /// A `$name` with no binding in scope
+    UndefinedVariable(String),
+    /// An `@name` that is not one of the known formats
+    UnknownFormat(String),
+}
+
+/// A filter that could not be tokenized or parsed, pointing at the offending bytes
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub span: Span,
+}
+
+impl ParseError {
+    pub fn new(kind: ParseErrorKind, span: Span) -> Self {
+        ParseError { kind, span }
+    }
+
+    /// Echo the filter with a caret under the offending span
+    pub fn render(&self, source: &str) -> String {
+        let (line_number, column) = line_col(source, self.span.start);
+        let line = source.lines().nth(line_number - 1).unwrap_or("");
+        let line_start = source[..self.span.start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = line_start + line.len();
+        let width = source[self.span.start.min(line_end)..self.span.end.min(line_end)]
+            .chars()
+            .count()
+            .max(1);
+        let gutter = " ".repeat(line_number.to_string().len());
+
+        format!(
+            "error: {}\n{gutter}--> {}:{}\n{gutter} |\n{} | {}\n{gutter} | {}{}",
+            self,
+            line_number,
+            column,
+            line_number,
+            line,
+            " ".repeat(column - 1),
+            "^".repeat(width),
+        )
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ParseErrorKind::UnexpectedChar(c) => write!(f, "unexpected character `{}`", c),
+            ParseErrorKind::InvalidNumber(text) => write!(f, "invalid number `{}`", text),
+            ParseErrorKind::UnterminatedString => write!(f, "unterminated string literal"),
+            ParseErrorKind::InvalidEscape(escape) => write!(f, "invalid escape `{}`", escape),
+            ParseErrorKind::Expected { expected, found } => {
+                write!(f, "expected {}, found {}", expected, found)
+            }
+            ParseErrorKind::Unclosed {
+                close,
+                construct,
+                opened_column,
+                found,
+            } => write!(
+                f,
+                "expected {} to close {} opened at column {}, found {}",
+                close, construct, opened_column, found
+            ),
+            ParseErrorKind::NonAssociative { first, second } => write!(
+                f,
+                "`{}` cannot be chained with `{}`; add parentheses",
+                first, second
+            ),
+            ParseErrorKind::Trailing(found) => write!(f, "unexpected {} after filter", found),
+            ParseErrorKind::UndefinedFunction { name, arity } => {
+                write!(f, "{}/{} is not defined", name, arity)
+            }
+            ParseErrorKind::UndefinedVariable(name) => write!(f, "${} is not defined", name),
+            ParseErrorKind::UnknownFormat(name) => write!(f, "@{} is not a valid format", name),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// An error raised while running a filter against a value
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeError {
+    /// A value was indexed with a key of the wrong type
+    CannotIndex { target: String, key: String },
+    /// `.[]` was applied to something that is not an array or object
+    CannotIterate(String),
+    /// An operation received a value of a type it does not support
+    Type(String),
+    /// An array index that cannot be assigned to, such as one before the start
+    IndexOutOfRange(String),
+    /// Raised by the filter itself through `error`
+    User(Value),
+    /// `input` or `inputs` could not read the next input. This ends the run
+    /// just like it would in the main loop, so it cannot be caught.
+    Input(InputError),
+}
+
+impl RuntimeError {
+    /// The value `catch` receives: the payload of `error`, or the message
+    pub fn value(&self) -> Value {
+        match self {
+            RuntimeError::User(value) => value.clone(),
+            other => Value::String(other.to_string()),
+        }
+    }
+
+    /// Whether `try` and `//` may handle the error
+    pub fn is_catchable(&self) -> bool {
+        !matches!(self, RuntimeError::Input(_))
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeError::CannotIndex { target, key } => {
+                write!(f, "Cannot index {} with {}", target, key)
+            }
+            RuntimeError::CannotIterate(value) => write!(f, "Cannot iterate over {}", value),
+            RuntimeError::Type(message) | RuntimeError::IndexOutOfRange(message) => {
+                write!(f, "{}", message)
+            }
+            RuntimeError::User(Value::String(message)) => write!(f, "{}", message),
+            RuntimeError::User(value) => write!(f, "{} (not a string)", value),
+            RuntimeError::Input(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// A module that could not be found, read or compiled
+#[derive(Debug)]
+pub enum LoadError {
+    NotFound {
+        name: String,
+        searched: Vec<PathBuf>,
+    },
+    /// A chain of modules that import each other, ending where it started
+    Cycle(Vec<PathBuf>),
+    Io {
+        path: PathBuf,
+        message: String,
+    },
+    Parse {
+        path: PathBuf,
+        source: String,
+        error: Box<ParseError>,
+    },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::NotFound { name, searched } => {
+                write!(f, "module not found: {:?} (searched ", name)?;
+                for (i, dir) in searched.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", dir.display())?;
+                }
+                write!(f, ")")
+            }
+            LoadError::Cycle(chain) => {
+                write!(f, "import cycle: ")?;
+                for (i, path) in chain.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " -> ")?;
+                    }
+                    write!(f, "{}", path.display())?;
+                }
+                Ok(())
+            }
+            LoadError::Io { path, message } => {
+                write!(f, "could not read module {}: {}", path.display(), message)
+            }
+            LoadError::Parse {
+                path,
+                source,
+                error,
+            } => write!(f, "in module {}:\n{}", path.display(), error.render(source)),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// An input that could not be read or is not valid JSON
+#[derive(Debug, Clone, PartialEq)]
+pub enum InputError {
+    Io { source: String, message: String },
+    Parse { location: Location, message: String },
+}
+
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InputError::Io { source, message } => {
+                write!(f, "could not read {}: {}", source, message)
+            }
+            InputError::Parse { location, message } => {
+                write!(f, "could not parse {}: {}", location, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for InputError {}
+
+/// A `JQ_COLORS` value that cannot be used, with entries counted from 1
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColorError {
+    /// More entries than there are kinds of text to color
+    TooManyEntries(usize),
+    /// An entry with a character other than a digit or `;`
+    InvalidChar {
+        entry: usize,
+        kind: &'static str,
+        text: String,
+        found: char,
+    },
+    /// An entry longer than jq accepts
+    TooLong {
+        entry: usize,
+        kind: &'static str,
+        text: String,
+        max: usize,
+    },
+}
+
+impl fmt::Display for ColorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ColorError::TooManyEntries(count) => write!(
+                f,
+                "JQ_COLORS has {} entries, but only 8 kinds of value can be colored",
+                count
+            ),
+            ColorError::InvalidChar {
+                entry,
+                kind,
+                text,
+                found,
+            } => write!(
+                f,
+                "JQ_COLORS entry {} (for {}) is {:?}, but {:?} is not a digit or `;`",
+                entry, kind, text, found
+            ),
+            ColorError::TooLong {
+                entry,
+                kind,
+                text,
+                max,
+            } => write!(
+                f,
+                "JQ_COLORS entry {} (for {}) is {:?}, which is longer than {} characters",
+                entry, kind, text, max
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ColorError {}
+
+/// Why a run of jq-rs was unsuccessful, which decides its exit code
+#[derive(Debug)]
+pub enum Failure {
+    /// Invalid command line arguments or environment, such as bad
+    /// `--argjson` text or `JQ_COLORS`
+    Usage(String),
+    /// The filter or a module it imports could not be compiled, already
+    /// rendered for the user
+    Compile(String),
+    /// An input could not be read or the output could not be written
+    Io(String),
+    /// An input is not valid JSON
+    Parse(String),
+    /// The filter raised an error for at least one input. Each error was
+    /// reported as it happened.
+    Runtime,
+    /// With `--exit-status`, the last output was `false` or `null`
+    FalsyOutput,
+    /// With `--exit-status`, there was no output at all
+    NoOutput,
+}
+
+impl Failure {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Failure::FalsyOutput => 1,
+            Failure::Usage(_) | Failure::Io(_) => 2,
+            Failure::Compile(_) => 3,
+            Failure::NoOutput => 4,
+            Failure::Runtime => 5,
+            Failure::Parse(_) => 6,
+        }
+    }
+}
+
+impl From<InputError> for Failure {
+    fn from(err: InputError) -> Self {
+        match err {
+            InputError::Io { .. } => Failure::Io(err.to_string()),
+            InputError::Parse { .. } => Failure::Parse(err.to_string()),
+        }
+    }
+}
+
+impl From<ColorError> for Failure {
+    fn from(err: ColorError) -> Self {
+        Failure::Usage(err.to_string())
+    }
+}
+
+impl From<std::io::Error> for Failure {
+    fn from(err: std::io::Error) -> Self {
+        Failure::Io(format!("could not write output: {}", err))
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Usage(message)
+            | Failure::Compile(message)
+            | Failure::Io(message)
+            | Failure::Parse(message) => write!(f, "{}", message),
+            Failure::Runtime => write!(f, "the filter raised an error"),
+            Failure::FalsyOutput => write!(f, "the last output was false or null"),
+            Failure::NoOutput => write!(f, "there was no output"),
+        }
+    }
+}
+
+impl std::error::Error for Failure {}
+
+/// 1-based line and character column of a byte offset
+pub fn line_col(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (line, before[line_start..].chars().count() + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_program;
+
+    #[test]
+    fn test_runtime_error_values() {
+        let err = RuntimeError::CannotIterate("number (1)".to_string());
+        assert_eq!(err.value(), Value::from("Cannot iterate over number (1)"));
+        let err = RuntimeError::User(serde_json::json!({"code": 1}));
+        assert_eq!(err.value(), serde_json::json!({"code": 1}));
+        assert_eq!(err.to_string(), "{\"code\":1} (not a string)");
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        let parse = InputError::Parse {
+            location: Location {
+                source: "in.json".to_string(),
+                line: 2,
+                column: 3,
+            },
+            message: "expected value".to_string(),
+        };
+        let failure = Failure::from(parse);
+        assert_eq!(
+            failure.to_string(),
+            "could not parse in.json:2:3: expected value"
+        );
+        let io = Failure::from(InputError::Io {
+            source: "in.json".to_string(),
+            message: "not found".to_string(),
+        });
+        let mut codes: Vec<i32> = [
+            Failure::Usage(String::new()),
+            Failure::Compile(String::new()),
+            io,
+            failure,
+            Failure::Runtime,
+            Failure::FalsyOutput,
+            Failure::NoOutput,
+        ]
+        .iter()
+        .map(Failure::exit_code)
+        .collect();
+        assert_eq!(codes, vec![2, 3, 2, 6, 5, 1, 4]);
+        // Every kind of failure other than bad usage can be told apart
+        codes.remove(0);
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), 6);
+    }
+
+    #[test]
+    fn test_line_col() {
+        assert_eq!(line_col(".a | .b", 0), (1, 1));
+        assert_eq!(line_col(".a | .b", 5), (1, 6));
+        assert_eq!(line_col(".a |\n  .b", 7), (2, 3));
+        assert_eq!(line_col("\"é\" | .b", 5), (1, 5));
+    }
+
+    #[test]
+    fn test_unclosed_index() {
+        let source = ".fizzes | .[1";
+        let err = parse_program(source).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "expected `]` to close index opened at column 12, found end of filter"
+        );
+        assert_eq!(
+            err.render(source),
+            "error: expected `]` to close index opened at column 12, found end of filter\n \
+             --> 1:14\n  |\n1 | .fizzes | .[1\n  |              ^"
+        );
+    }
+
+    #[test]
+    fn test_unclosed_slice() {
+        let source = ".[0: .a";
+        let err = parse_program(source).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "expected `]` to close slice opened at column 2, found end of filter"
+        );
+    }
+
+    #[test]
+    fn test_unclosed_call() {
+        let err = parse_program("del(.a; .b").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "expected `)` to close argument list opened at column 4, found end of filter"
+        );
+    }
+
+    #[test]
+    fn test_caret_covers_token() {
+        let source = ".a | .b ]";
+        let err = parse_program(source).unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::Trailing("`]`".to_string()));
+        assert!(err.render(source).ends_with("1 | .a | .b ]\n  |         ^"));
+
+        let source = ".a | length)";
+        assert!(parse_program(source)
+            .unwrap_err()
+            .render(source)
+            .ends_with("|            ^"));
+    }
+
+    #[test]
+    fn test_multiline_filter() {
+        let source = ".a |\n  .b &";
+        let err = parse_program(source).unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::UnexpectedChar('&'));
+        assert_eq!(
+            err.render(source),
+            "error: unexpected character `&`\n --> 2:6\n  |\n2 |   .b &\n  |      ^"
+        );
+    }
+
+    #[test]
+    fn test_missing_filter() {
+        let err = parse_program(".a |").unwrap_err();
+        assert_eq!(
+            err.kind,
+            ParseErrorKind::Expected {
+                expected: "a filter".to_string(),
+                found: "end of filter".to_string()
+            }
+        );
+    }
+}
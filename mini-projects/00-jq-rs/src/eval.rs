@@ -0,0 +1,1583 @@
+use crate::ast::{Ast, AstKind, BinaryOp, FunctionDef, Param, Pattern, StringPart};
+use crate::builtins;
+use crate::env::{Env, Function};
+use crate::error::{ParseError, ParseErrorKind, RuntimeError};
+use crate::formats;
+use crate::ops;
+use crate::path;
+use crate::strings;
+use crate::value;
+use serde_json::{Map, Value};
+use std::cell::Cell;
+use std::iter;
+use std::rc::Rc;
+
+/// The lazily produced outputs of a filter. Each item is pulled on demand, so
+/// a consumer sees early results before later ones are computed, and an error
+/// in one output does not discard the outputs before it.
+pub type Stream<'a, T> = Box<dyn Iterator<Item = Result<T, RuntimeError>> + 'a>;
+
+/// The outputs of a filter run for its values
+pub type ValueStream<'a> = Stream<'a, Value>;
+
+/// A stream with exactly one item
+pub fn once<'a>(result: Result<Value, RuntimeError>) -> ValueStream<'a> {
+    Box::new(iter::once(result))
+}
+
+/// A stream over already computed values
+pub fn many<'a>(values: Vec<Value>) -> ValueStream<'a> {
+    Box::new(values.into_iter().map(Ok))
+}
+
+/// How deeply calls to user-defined functions may nest before they raise an
+/// error rather than overflow the stack
+pub const MAX_CALL_DEPTH: usize = 3072;
+
+/// Stack to allow for each nested call, enough for a body with several
+/// nested constructs in a debug build
+pub const CALL_STACK_SIZE: usize = 16 << 10;
+
+thread_local! {
+    /// How many calls to user-defined functions are running right now
+    static CALL_DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Run `f` one call deeper, or fail if that is past `MAX_CALL_DEPTH`
+fn nested<T>(f: impl FnOnce() -> T) -> Result<T, RuntimeError> {
+    let depth = CALL_DEPTH.with(Cell::get);
+    if depth >= MAX_CALL_DEPTH {
+        return Err(RuntimeError::Type(format!(
+            "Maximum call depth of {} exceeded",
+            MAX_CALL_DEPTH
+        )));
+    }
+    CALL_DEPTH.with(|cell| cell.set(depth + 1));
+    let result = f();
+    CALL_DEPTH.with(|cell| cell.set(depth));
+    Ok(result)
+}
+
+/// The outputs of a call to a user-defined function, started by `start`.
+/// Both starting the call and pulling each output count as one level of
+/// nesting, so runaway recursion ends in a catchable error.
+pub fn call<'a, T: 'a>(start: impl FnOnce() -> Stream<'a, T>) -> Stream<'a, T> {
+    let mut stream = match nested(start) {
+        Ok(stream) => stream,
+        Err(err) => return Box::new(iter::once(Err(err))),
+    };
+    let mut failed = false;
+    Box::new(iter::from_fn(move || {
+        if failed {
+            return None;
+        }
+        nested(|| stream.next()).unwrap_or_else(|err| {
+            failed = true;
+            Some(Err(err))
+        })
+    }))
+}
+
+/// The outputs of `stream` up to its first error, followed by the outputs of
+/// `handler` run on that error. An error that cannot be caught is passed on
+/// instead.
+pub fn catching<'a, T: 'a, F>(mut stream: Stream<'a, T>, handler: F) -> Stream<'a, T>
+where
+    F: FnOnce(RuntimeError) -> Stream<'a, T> + 'a,
+{
+    let mut handler = Some(handler);
+    let mut handled: Option<Stream<'a, T>> = None;
+    Box::new(iter::from_fn(move || loop {
+        if let Some(handled) = &mut handled {
+            return handled.next();
+        }
+        match stream.next()? {
+            Ok(output) => return Some(Ok(output)),
+            Err(err) if !err.is_catchable() => {
+                stream = Box::new(iter::empty());
+                return Some(Err(err));
+            }
+            Err(err) => handled = handler.take().map(|handler| handler(err)),
+        }
+    }))
+}
+
+/// Feed every successful output of `stream` into `f`, passing errors through
+pub fn bind<'a, F>(stream: ValueStream<'a>, mut f: F) -> ValueStream<'a>
+where
+    F: FnMut(Value) -> ValueStream<'a> + 'a,
+{
+    Box::new(stream.flat_map(move |result| match result {
+        Ok(value) => f(value),
+        Err(err) => once(Err(err)),
+    }))
+}
+
+/// Run `ast` against `input` with the variables in `env`, producing its outputs lazily
+pub fn eval<'a>(ast: &'a Ast, env: &Env<'a>, input: Value) -> ValueStream<'a> {
+    match &ast.kind {
+        AstKind::Identity => once(Ok(input)),
+        AstKind::Literal(value) => once(Ok(value.clone())),
+        AstKind::Format(name) => once(formats::apply(name, &input).map(Value::String)),
+        AstKind::Interpolation { format, parts } => {
+            eval_interpolation(format.as_deref(), parts, env, input, String::new())
+        }
+        AstKind::Variable(name) => {
+            match env.get(name).cloned().or_else(|| builtins::variable(name)) {
+                Some(value) => once(Ok(value)),
+                None => once(Err(RuntimeError::Type(format!("${} is not defined", name)))),
+            }
+        }
+        AstKind::Field { target, name } => {
+            let key = Value::String(name.clone());
+            Box::new(eval(target, env, input).map(move |result| value::index(&result?, &key)))
+        }
+        AstKind::Index { target, index } => {
+            let env = env.clone();
+            bind(eval(index, &env, input.clone()), move |key| {
+                Box::new(
+                    eval(target, &env, input.clone())
+                        .map(move |result| value::index(&result?, &key)),
+                )
+            })
+        }
+        AstKind::Slice { target, start, end } => {
+            let env = env.clone();
+            let end_input = input.clone();
+            bind(
+                eval_bound(start.as_deref(), &env, input.clone()),
+                move |start| {
+                    let env = env.clone();
+                    let target_input = input.clone();
+                    bind(
+                        eval_bound(end.as_deref(), &env, end_input.clone()),
+                        move |end| {
+                            let start = start.clone();
+                            Box::new(
+                                eval(target, &env, target_input.clone())
+                                    .map(move |result| value::slice(&result?, &start, &end)),
+                            )
+                        },
+                    )
+                },
+            )
+        }
+        AstKind::Iterate { target } => {
+            bind(eval(target, env, input), |value| {
+                match value::iterate(value) {
+                    Ok(values) => many(values),
+                    Err(err) => once(Err(err)),
+                }
+            })
+        }
+        AstKind::Pipe { lhs, rhs } => {
+            let env = env.clone();
+            bind(eval(lhs, &env, input), move |value| eval(rhs, &env, value))
+        }
+        AstKind::Comma { lhs, rhs } => {
+            Box::new(eval(lhs, env, input.clone()).chain(eval(rhs, env, input)))
+        }
+        AstKind::Array(None) => once(Ok(Value::Array(Vec::new()))),
+        AstKind::Array(Some(body)) => once(
+            eval(body, env, input)
+                .collect::<Result<_, _>>()
+                .map(Value::Array),
+        ),
+        AstKind::Object(entries) => eval_object(entries, env, input, Map::new()),
+        AstKind::Binary { op, lhs, rhs } => eval_binary(*op, lhs, rhs, env, input),
+        AstKind::Neg(operand) => {
+            Box::new(eval(operand, env, input).map(|result| ops::negate(result?)))
+        }
+        AstKind::If {
+            cond,
+            then_branch,
+            else_branch,
+        } => {
+            let env = env.clone();
+            bind(eval(cond, &env, input.clone()), move |cond| {
+                if ops::is_truthy(&cond) {
+                    eval(then_branch, &env, input.clone())
+                } else {
+                    match else_branch {
+                        Some(else_branch) => eval(else_branch, &env, input.clone()),
+                        None => once(Ok(input.clone())),
+                    }
+                }
+            })
+        }
+        AstKind::Try { body, catch } => {
+            let env = env.clone();
+            catching(eval(body, &env, input), move |err| match catch {
+                Some(handler) => eval(handler, &env, err.value()),
+                None => Box::new(iter::empty()),
+            })
+        }
+        AstKind::Bind {
+            source,
+            pattern,
+            body,
+        } => {
+            let env = env.clone();
+            bind(eval(source, &env, input.clone()), move |value| {
+                let input = input.clone();
+                match destructure(pattern, &env, &input, value) {
+                    Ok(envs) => Box::new(
+                        envs.into_iter()
+                            .flat_map(move |env| eval(body, &env, input.clone())),
+                    ),
+                    Err(err) => once(Err(err)),
+                }
+            })
+        }
+        AstKind::Reduce {
+            source,
+            pattern,
+            init,
+            update,
+        } => {
+            let env = env.clone();
+            bind(eval(init, &env, input.clone()), move |state| {
+                once(reduce(source, pattern, update, &env, &input, state))
+            })
+        }
+        AstKind::Foreach {
+            source,
+            pattern,
+            init,
+            update,
+            extract,
+        } => {
+            let env = env.clone();
+            bind(eval(init, &env, input.clone()), move |state| {
+                let fold = Fold {
+                    source,
+                    pattern,
+                    update,
+                };
+                foreach(fold, extract.as_deref(), &env, input.clone(), state)
+            })
+        }
+        AstKind::Def { def, rest } => eval(rest, &env.define(def), input),
+        AstKind::FunctionCall { name, args } => match env.function(name, args.len()) {
+            Some(Function::Closure(body, closure_env)) => eval(body, &closure_env, input),
+            Some(Function::Defined(def, def_env)) => {
+                let body_input = input.clone();
+                let run = Rc::new(move |env: Env<'a>| eval(&def.body, &env, body_input.clone()));
+                call(|| with_params(&def.params, args, env.clone(), def_env, input, run))
+            }
+            None => builtins::call(name, args, env, input),
+        },
+    }
+}
+
+/// Bind the parameters of a user-defined function to the `args` of a call
+/// made from `caller`, then `run` its body in the extended environment.
+/// Filter parameters become closures over the call site; value parameters
+/// are bound once per output of their argument, earlier ones varying slowest.
+pub fn with_params<'a, T: 'a>(
+    params: &'a [Param],
+    args: &'a [Ast],
+    caller: Env<'a>,
+    env: Env<'a>,
+    input: Value,
+    run: Rc<dyn Fn(Env<'a>) -> Stream<'a, T> + 'a>,
+) -> Stream<'a, T> {
+    let ((param, params), (arg, args)) = match (params.split_first(), args.split_first()) {
+        (Some(param), Some(arg)) => (param, arg),
+        _ => return run(env),
+    };
+    let env = env.bind_closure(param.name(), arg, caller.clone());
+    match param {
+        Param::Filter(_) => with_params(params, args, caller, env, input, run),
+        Param::Value(name) => Box::new(eval(arg, &caller, input.clone()).flat_map(
+            move |result| -> Stream<'a, T> {
+                match result {
+                    Ok(value) => with_params(
+                        params,
+                        args,
+                        caller.clone(),
+                        env.bind(name.as_str(), value),
+                        input.clone(),
+                        run.clone(),
+                    ),
+                    Err(err) => Box::new(iter::once(Err(err))),
+                }
+            },
+        )),
+    }
+}
+
+/// The parts shared by `reduce` and `foreach`
+struct Fold<'a> {
+    source: &'a Ast,
+    pattern: &'a Pattern,
+    update: &'a Ast,
+}
+
+/// Run `update` once per output of `source`, keeping its last output as the
+/// new state; an update with no outputs resets the state to `null`
+fn reduce<'a>(
+    source: &'a Ast,
+    pattern: &'a Pattern,
+    update: &'a Ast,
+    env: &Env<'a>,
+    input: &Value,
+    mut state: Value,
+) -> Result<Value, RuntimeError> {
+    for value in eval(source, env, input.clone()) {
+        for env in destructure(pattern, env, input, value?)? {
+            let mut next = Value::Null;
+            for result in eval(update, &env, state) {
+                next = result?;
+            }
+            state = next;
+        }
+    }
+    Ok(state)
+}
+
+/// Like `reduce`, but lazily emit `extract` of every intermediate state.
+/// Each output of `update` becomes the new state in turn.
+fn foreach<'a>(
+    fold: Fold<'a>,
+    extract: Option<&'a Ast>,
+    env: &Env<'a>,
+    input: Value,
+    mut state: Value,
+) -> ValueStream<'a> {
+    let env = env.clone();
+    bind(eval(fold.source, &env, input.clone()), move |value| {
+        let envs = match destructure(fold.pattern, &env, &input, value) {
+            Ok(envs) => envs,
+            Err(err) => return once(Err(err)),
+        };
+        let mut states = Vec::new();
+        'envs: for env in envs {
+            for result in eval(fold.update, &env, state.clone()) {
+                match result {
+                    Ok(next) => {
+                        state = next.clone();
+                        states.push(Ok((env.clone(), next)));
+                    }
+                    Err(err) => {
+                        states.push(Err(err));
+                        break 'envs;
+                    }
+                }
+            }
+        }
+        Box::new(states.into_iter().flat_map(move |result| match result {
+            Ok((env, state)) => match extract {
+                Some(extract) => eval(extract, &env, state),
+                None => once(Ok(state)),
+            },
+            Err(err) => once(Err(err)),
+        }))
+    })
+}
+
+/// Match `value` against `pattern`, returning one environment per way the
+/// pattern's key expressions (evaluated against `input`) can bind it
+pub fn destructure<'a>(
+    pattern: &'a Pattern,
+    env: &Env<'a>,
+    input: &Value,
+    value: Value,
+) -> Result<Vec<Env<'a>>, RuntimeError> {
+    match pattern {
+        Pattern::Variable(name) => Ok(vec![env.bind(name.as_str(), value)]),
+        Pattern::Array(items) => {
+            if !matches!(value, Value::Array(_) | Value::Null) {
+                return Err(value::cannot_index(&value, &Value::from(0)));
+            }
+            let mut envs = vec![env.clone()];
+            for (i, item) in items.iter().enumerate() {
+                let element = value::index(&value, &Value::from(i))?;
+                let mut next = Vec::new();
+                for env in &envs {
+                    next.extend(destructure(item, env, input, element.clone())?);
+                }
+                envs = next;
+            }
+            Ok(envs)
+        }
+        Pattern::Object(entries) => {
+            let mut envs = vec![env.clone()];
+            for entry in entries {
+                let mut next = Vec::new();
+                for env in &envs {
+                    for key in eval(&entry.key, env, input.clone()) {
+                        let found = value::index(&value, &key?)?;
+                        let env = match &entry.variable {
+                            Some(name) => env.bind(name.as_str(), found.clone()),
+                            None => env.clone(),
+                        };
+                        match &entry.pattern {
+                            Some(pattern) => next.extend(destructure(pattern, &env, input, found)?),
+                            None => next.push(env),
+                        }
+                    }
+                }
+                envs = next;
+            }
+            Ok(envs)
+        }
+    }
+}
+
+fn eval_binary<'a>(
+    op: BinaryOp,
+    lhs: &'a Ast,
+    rhs: &'a Ast,
+    env: &Env<'a>,
+    input: Value,
+) -> ValueStream<'a> {
+    let env = env.clone();
+    match op {
+        // `and`/`or` only evaluate the right side when the left does not decide the result
+        BinaryOp::And | BinaryOp::Or => {
+            let deciding = op == BinaryOp::Or;
+            bind(eval(lhs, &env, input.clone()), move |left| {
+                if ops::is_truthy(&left) == deciding {
+                    return once(Ok(Value::Bool(deciding)));
+                }
+                Box::new(
+                    eval(rhs, &env, input.clone())
+                        .map(|right| Ok(Value::Bool(ops::is_truthy(&right?)))),
+                )
+            })
+        }
+        // `a // b` yields the truthy outputs of `a`, ignoring the errors `try`
+        // could catch, or `b` if there are none
+        BinaryOp::Alt => {
+            let found = Rc::new(Cell::new(false));
+            let seen = found.clone();
+            let left = eval(lhs, &env, input.clone())
+                .filter(|result| match result {
+                    Ok(value) => ops::is_truthy(value),
+                    Err(err) => !err.is_catchable(),
+                })
+                .inspect(move |result| {
+                    if result.is_ok() {
+                        seen.set(true);
+                    }
+                });
+            let right = iter::once(()).flat_map(move |_| -> ValueStream<'a> {
+                if found.get() {
+                    Box::new(iter::empty())
+                } else {
+                    eval(rhs, &env, input.clone())
+                }
+            });
+            Box::new(left.chain(right))
+        }
+        BinaryOp::Assign
+        | BinaryOp::Update
+        | BinaryOp::AddAssign
+        | BinaryOp::SubAssign
+        | BinaryOp::MulAssign
+        | BinaryOp::DivAssign
+        | BinaryOp::ModAssign
+        | BinaryOp::AltAssign => path::assign(op, lhs, rhs, &env, input),
+        // Every other operator pairs each right output with each left output,
+        // with the right side varying slowest
+        _ => bind(eval(rhs, &env, input.clone()), move |right| {
+            Box::new(
+                eval(lhs, &env, input.clone())
+                    .map(move |left| ops::apply(op, left?, right.clone())),
+            )
+        }),
+    }
+}
+
+/// Build strings from the remaining `parts` followed by `suffix`, one per
+/// combination of interpolated outputs, with later parts varying slowest
+fn eval_interpolation<'a>(
+    format: Option<&'a str>,
+    parts: &'a [StringPart],
+    env: &Env<'a>,
+    input: Value,
+    suffix: String,
+) -> ValueStream<'a> {
+    let (last, rest) = match parts.split_last() {
+        Some(split) => split,
+        None => return once(Ok(Value::String(suffix))),
+    };
+    match last {
+        StringPart::Literal(text) => {
+            eval_interpolation(format, rest, env, input, text.clone() + &suffix)
+        }
+        StringPart::Filter(filter) => {
+            let env = env.clone();
+            bind(eval(filter, &env, input.clone()), move |value| {
+                let text = match format {
+                    Some(format) => match formats::apply(format, &value) {
+                        Ok(text) => text,
+                        Err(err) => return once(Err(err)),
+                    },
+                    None => strings::tostring(&value),
+                };
+                eval_interpolation(format, rest, &env, input.clone(), text + &suffix)
+            })
+        }
+    }
+}
+
+/// Build objects from the remaining `entries`, one per combination of key
+/// and value outputs, with earlier entries varying slowest
+fn eval_object<'a>(
+    entries: &'a [(Ast, Ast)],
+    env: &Env<'a>,
+    input: Value,
+    built: Map<String, Value>,
+) -> ValueStream<'a> {
+    let ((key, value), rest) = match entries.split_first() {
+        Some(split) => split,
+        None => return once(Ok(Value::Object(built))),
+    };
+    let env = env.clone();
+    let value_input = input.clone();
+    bind(eval(key, &env, input.clone()), move |key| {
+        let key = match key {
+            Value::String(key) => key,
+            other => {
+                return once(Err(RuntimeError::Type(format!(
+                    "Object keys must be strings, not {}",
+                    value::describe(&other)
+                ))))
+            }
+        };
+        let env = env.clone();
+        let input = input.clone();
+        let built = built.clone();
+        bind(eval(value, &env, value_input.clone()), move |value| {
+            let mut built = built.clone();
+            built.insert(key.clone(), value);
+            eval_object(rest, &env, input.clone(), built)
+        })
+    })
+}
+
+/// Evaluate an optional slice bound, treating a missing bound as `null`
+fn eval_bound<'a>(bound: Option<&'a Ast>, env: &Env<'a>, input: Value) -> ValueStream<'a> {
+    match bound {
+        Some(bound) => eval(bound, env, input),
+        None => once(Ok(Value::Null)),
+    }
+}
+
+/// Reject calls to functions and references to variables that do not exist
+/// before anything runs. `globals` holds the variables bound on the command
+/// line and the functions of imported modules.
+pub fn check<'a>(ast: &'a Ast, globals: &'a Env<'a>) -> Result<(), ParseError> {
+    check_scoped(ast, &mut Scope::new(globals))
+}
+
+/// Check the definitions of a module, each of which can call itself and the
+/// definitions before it
+pub fn check_module<'a>(defs: &'a [FunctionDef], globals: &'a Env<'a>) -> Result<(), ParseError> {
+    let mut scope = Scope::new(globals);
+    defs.iter().try_for_each(|def| check_def(def, &mut scope))
+}
+
+/// The names visible at some point of a filter, for `check`
+struct Scope<'a> {
+    variables: Vec<&'a str>,
+    functions: Vec<(&'a str, usize)>,
+    globals: &'a Env<'a>,
+}
+
+impl<'a> Scope<'a> {
+    fn new(globals: &'a Env<'a>) -> Self {
+        Scope {
+            variables: globals.variables(),
+            functions: Vec::new(),
+            globals,
+        }
+    }
+
+    fn has_function(&self, name: &str, arity: usize) -> bool {
+        self.functions.contains(&(name, arity))
+            || self.globals.function(name, arity).is_some()
+            || builtins::is_defined(name, arity)
+    }
+
+    fn mark(&self) -> (usize, usize) {
+        (self.variables.len(), self.functions.len())
+    }
+
+    fn reset(&mut self, (variables, functions): (usize, usize)) {
+        self.variables.truncate(variables);
+        self.functions.truncate(functions);
+    }
+}
+
+fn check_scoped<'a>(ast: &'a Ast, scope: &mut Scope<'a>) -> Result<(), ParseError> {
+    match &ast.kind {
+        AstKind::Identity | AstKind::Literal(_) | AstKind::Format(_) => Ok(()),
+        AstKind::Interpolation { parts, .. } => parts.iter().try_for_each(|part| match part {
+            StringPart::Literal(_) => Ok(()),
+            StringPart::Filter(filter) => check_scoped(filter, scope),
+        }),
+        AstKind::Variable(name) => {
+            if scope.variables.contains(&name.as_str()) || builtins::is_variable(name) {
+                Ok(())
+            } else {
+                Err(ParseError::new(
+                    ParseErrorKind::UndefinedVariable(name.clone()),
+                    ast.span,
+                ))
+            }
+        }
+        AstKind::Field { target, .. } | AstKind::Iterate { target } => check_scoped(target, scope),
+        AstKind::Index { target, index } => {
+            check_scoped(target, scope)?;
+            check_scoped(index, scope)
+        }
+        AstKind::Slice { target, start, end } => {
+            check_scoped(target, scope)?;
+            for bound in [start, end].into_iter().flatten() {
+                check_scoped(bound, scope)?;
+            }
+            Ok(())
+        }
+        AstKind::Neg(operand) => check_scoped(operand, scope),
+        AstKind::Try { body, catch } => {
+            check_scoped(body, scope)?;
+            match catch {
+                Some(handler) => check_scoped(handler, scope),
+                None => Ok(()),
+            }
+        }
+        AstKind::If {
+            cond,
+            then_branch,
+            else_branch,
+        } => {
+            check_scoped(cond, scope)?;
+            check_scoped(then_branch, scope)?;
+            match else_branch {
+                Some(else_branch) => check_scoped(else_branch, scope),
+                None => Ok(()),
+            }
+        }
+        AstKind::Pipe { lhs, rhs }
+        | AstKind::Comma { lhs, rhs }
+        | AstKind::Binary { lhs, rhs, .. } => {
+            check_scoped(lhs, scope)?;
+            check_scoped(rhs, scope)
+        }
+        AstKind::Array(body) => match body {
+            Some(body) => check_scoped(body, scope),
+            None => Ok(()),
+        },
+        AstKind::Object(entries) => entries.iter().try_for_each(|(key, value)| {
+            check_scoped(key, scope)?;
+            check_scoped(value, scope)
+        }),
+        AstKind::Bind {
+            source,
+            pattern,
+            body,
+        } => {
+            check_scoped(source, scope)?;
+            check_pattern(pattern, scope)?;
+            let mark = scope.mark();
+            scope.variables.extend(pattern.variables());
+            let result = check_scoped(body, scope);
+            scope.reset(mark);
+            result
+        }
+        AstKind::Reduce {
+            source,
+            pattern,
+            init,
+            update,
+        } => check_fold(source, pattern, init, [Some(update.as_ref()), None], scope),
+        AstKind::Foreach {
+            source,
+            pattern,
+            init,
+            update,
+            extract,
+        } => check_fold(
+            source,
+            pattern,
+            init,
+            [Some(update.as_ref()), extract.as_deref()],
+            scope,
+        ),
+        AstKind::Def { def, rest } => {
+            let mark = scope.mark();
+            let result = check_def(def, scope).and_then(|_| check_scoped(rest, scope));
+            scope.reset(mark);
+            result
+        }
+        AstKind::FunctionCall { name, args } => {
+            if !scope.has_function(name, args.len()) {
+                return Err(ParseError::new(
+                    ParseErrorKind::UndefinedFunction {
+                        name: name.clone(),
+                        arity: args.len(),
+                    },
+                    ast.span,
+                ));
+            }
+            args.iter().try_for_each(|arg| check_scoped(arg, scope))
+        }
+    }
+}
+
+/// Check the body of `def` with its parameters in scope, leaving `def` itself
+/// in scope for whatever follows
+fn check_def<'a>(def: &'a FunctionDef, scope: &mut Scope<'a>) -> Result<(), ParseError> {
+    scope.functions.push((&def.name, def.params.len()));
+    let mark = scope.mark();
+    for param in &def.params {
+        scope.functions.push((param.name(), 0));
+        if let Param::Value(name) = param {
+            scope.variables.push(name);
+        }
+    }
+    let result = check_scoped(&def.body, scope);
+    scope.reset(mark);
+    result
+}
+
+/// Check a `reduce` or `foreach`, whose update and extract clauses see the
+/// pattern's variables while the source and initial state do not
+fn check_fold<'a>(
+    source: &'a Ast,
+    pattern: &'a Pattern,
+    init: &'a Ast,
+    clauses: [Option<&'a Ast>; 2],
+    scope: &mut Scope<'a>,
+) -> Result<(), ParseError> {
+    check_scoped(source, scope)?;
+    check_scoped(init, scope)?;
+    check_pattern(pattern, scope)?;
+    let mark = scope.mark();
+    scope.variables.extend(pattern.variables());
+    let result = clauses
+        .into_iter()
+        .flatten()
+        .try_for_each(|clause| check_scoped(clause, scope));
+    scope.reset(mark);
+    result
+}
+
+/// Check the key expressions of an object pattern, which can see variables
+/// bound by entries to their left
+fn check_pattern<'a>(pattern: &'a Pattern, scope: &mut Scope<'a>) -> Result<(), ParseError> {
+    let mark = scope.mark();
+    let result = (|| {
+        match pattern {
+            Pattern::Variable(_) => {}
+            Pattern::Array(items) => {
+                for item in items {
+                    check_pattern(item, scope)?;
+                }
+            }
+            Pattern::Object(entries) => {
+                for entry in entries {
+                    check_scoped(&entry.key, scope)?;
+                    scope.variables.extend(entry.variable.as_deref());
+                    if let Some(pattern) = &entry.pattern {
+                        check_pattern(pattern, scope)?;
+                        scope.variables.extend(pattern.variables());
+                    }
+                }
+            }
+        }
+        Ok(())
+    })();
+    scope.reset(mark);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_program;
+    use serde_json::json;
+
+    fn run(filter: &str, input: Value) -> Result<Vec<Value>, RuntimeError> {
+        let ast = parse_program(filter).unwrap().body;
+        check(&ast, &Env::new()).unwrap();
+        eval(&ast, &Env::new(), input).collect()
+    }
+
+    fn check_all(ast: &Ast) -> Result<(), ParseError> {
+        check(ast, &Env::new())
+    }
+
+    fn all_types() -> Value {
+        json!({
+            "fizz": "buzz",
+            "baz": null,
+            "fuzz": true,
+            "bizz": 22.0,
+            "biz": 42,
+            "fizzes": ["buzz", null, true, 22.0, 42.0]
+        })
+    }
+
+    fn annoying() -> Value {
+        json!({"really": {"annoying": {"dictionary": ["am", "i", "right", "?"]}}})
+    }
+
+    fn football() -> Value {
+        json!([
+            {"name": "Leo Lightning", "position": "Forward"},
+            {"name": "Maximus Defender", "position": "Defender"},
+            {"name": "Sophie Swift", "position": "Midfielder"}
+        ])
+    }
+
+    #[test]
+    fn test_identity() {
+        assert_eq!(run(".", all_types()).unwrap(), vec![all_types()]);
+    }
+
+    #[test]
+    fn test_field() {
+        assert_eq!(run(".fizz", all_types()).unwrap(), vec![json!("buzz")]);
+        assert_eq!(run(".missing", all_types()).unwrap(), vec![json!(null)]);
+        assert_eq!(run(".a.b", json!(null)).unwrap(), vec![json!(null)]);
+        assert_eq!(
+            run(".name", football()).unwrap_err().to_string(),
+            "Cannot index array with \"name\""
+        );
+    }
+
+    #[test]
+    fn test_index() {
+        let array = json!(["one", "two", "three"]);
+        assert_eq!(run(".[0]", array.clone()).unwrap(), vec![json!("one")]);
+        assert_eq!(run(".[3]", array.clone()).unwrap(), vec![json!(null)]);
+        assert_eq!(run(".[-1]", array.clone()).unwrap(), vec![json!("three")]);
+        assert_eq!(run(".[-4]", array.clone()).unwrap(), vec![json!(null)]);
+        assert_eq!(run(".fizzes[3]", all_types()).unwrap(), vec![json!(22.0)]);
+        assert!(run(".[0]", all_types()).is_err());
+    }
+
+    #[test]
+    fn test_slice() {
+        let array = json!(["one", "two", "three"]);
+        assert_eq!(
+            run(".[0:2]", array.clone()).unwrap(),
+            vec![json!(["one", "two"])]
+        );
+        assert_eq!(
+            run(".[1:]", array.clone()).unwrap(),
+            vec![json!(["two", "three"])]
+        );
+        assert_eq!(run(".[:1]", array.clone()).unwrap(), vec![json!(["one"])]);
+        assert_eq!(run(".[5:9]", array.clone()).unwrap(), vec![json!([])]);
+        assert_eq!(
+            run(".[:-1], .[-2:]", array).unwrap(),
+            vec![json!(["one", "two"]), json!(["two", "three"])]
+        );
+        assert_eq!(run(".[0:2]", json!(null)).unwrap(), vec![json!(null)]);
+        assert_eq!(
+            run(".name[0:3], .name[-2:]", json!({"name": "Zoë Ünal"})).unwrap(),
+            vec![json!("Zoë"), json!("al")]
+        );
+    }
+
+    #[test]
+    fn test_quoted_keys() {
+        let input = json!({"key with spaces": 1, "quoted-key": {"a b": 2}});
+        assert_eq!(
+            run(
+                ".[\"key with spaces\"], .\"quoted-key\".\"a b\"",
+                input.clone()
+            )
+            .unwrap(),
+            vec![json!(1), json!(2)]
+        );
+        assert_eq!(
+            run(".\"quoted-\\(\"key\")\"[\"a b\"]", input).unwrap(),
+            vec![json!(2)]
+        );
+        assert_eq!(
+            run(".really.\"annoying\".dictionary[-1]", annoying()).unwrap(),
+            vec![json!("?")]
+        );
+    }
+
+    #[test]
+    fn test_pipe() {
+        assert_eq!(
+            run(".fizzes | .[1]", all_types()).unwrap(),
+            vec![json!(null)]
+        );
+        assert_eq!(
+            run(".fizzes | .[0:1] | .[0]", all_types()).unwrap(),
+            vec![json!("buzz")]
+        );
+    }
+
+    #[test]
+    fn test_iterate() {
+        assert_eq!(
+            run(".[] | .name", football()).unwrap(),
+            vec![
+                json!("Leo Lightning"),
+                json!("Maximus Defender"),
+                json!("Sophie Swift")
+            ]
+        );
+        assert_eq!(
+            run(".[]", json!({"a": 1, "b": 2})).unwrap(),
+            vec![json!(1), json!(2)]
+        );
+        assert_eq!(
+            run(".[]", json!(1)).unwrap_err().to_string(),
+            "Cannot iterate over number (1)"
+        );
+    }
+
+    #[test]
+    fn test_outputs_are_lazy() {
+        let ast = parse_program(".[] | .[0]").unwrap().body;
+        let mut outputs = eval(&ast, &Env::new(), json!([[1], "oops", [3]]));
+        assert_eq!(outputs.next(), Some(Ok(json!(1))));
+        assert_eq!(
+            outputs.next().unwrap().unwrap_err().to_string(),
+            "Cannot index string with number"
+        );
+        assert_eq!(outputs.next(), Some(Ok(json!(3))));
+        assert_eq!(outputs.next(), None);
+    }
+
+    #[test]
+    fn test_large_array_streams() {
+        let ast = parse_program(".[]").unwrap().body;
+        let input = Value::Array((0..100_000).map(Value::from).collect());
+        let first: Vec<Value> = eval(&ast, &Env::new(), input)
+            .take(2)
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(first, vec![json!(0), json!(1)]);
+    }
+
+    #[test]
+    fn test_index_expressions_see_original_input() {
+        assert_eq!(
+            run(".items[.pick]", json!({"items": [10, 20], "pick": 1})).unwrap(),
+            vec![json!(20)]
+        );
+    }
+
+    #[test]
+    fn test_comma() {
+        assert_eq!(
+            run(".a, .b", json!({"a": 1, "b": 2})).unwrap(),
+            vec![json!(1), json!(2)]
+        );
+        assert_eq!(
+            run(".[] | .name, .position", football()).unwrap()[..2],
+            [json!("Leo Lightning"), json!("Forward")]
+        );
+        assert_eq!(
+            run(".[0, 2]", json!(["a", "b", "c"])).unwrap(),
+            vec![json!("a"), json!("c")]
+        );
+    }
+
+    #[test]
+    fn test_array_construction() {
+        assert_eq!(run("[]", json!(null)).unwrap(), vec![json!([])]);
+        assert_eq!(
+            run("[.[] | .name]", football()).unwrap(),
+            vec![json!(["Leo Lightning", "Maximus Defender", "Sophie Swift"])]
+        );
+        assert_eq!(
+            run("[.a, .b, [.c]]", json!({"a": 1, "b": 2, "c": 3})).unwrap(),
+            vec![json!([1, 2, [3]])]
+        );
+        assert!(run("[.[] | .a]", json!([{}, 1])).is_err());
+    }
+
+    #[test]
+    fn test_object_construction() {
+        assert_eq!(
+            run(".[0] | {name: .name, pos: .position}", football()).unwrap(),
+            vec![json!({"name": "Leo Lightning", "pos": "Forward"})]
+        );
+        assert_eq!(
+            run("{name, \"position\"}", football()[1].clone()).unwrap(),
+            vec![json!({"name": "Maximus Defender", "position": "Defender"})]
+        );
+        assert_eq!(
+            run("{(.k): .v}", json!({"k": "key", "v": 1})).unwrap(),
+            vec![json!({"key": 1})]
+        );
+        assert_eq!(run("{}", json!(null)).unwrap(), vec![json!({})]);
+        assert_eq!(
+            run("{a: .x | .y}", json!({"x": {"y": 1}})).unwrap(),
+            vec![json!({"a": 1})]
+        );
+        assert_eq!(
+            run("{(.a, .b): 0}", json!({"a": "x", "b": 1}))
+                .unwrap_err()
+                .to_string(),
+            "Object keys must be strings, not number (1)"
+        );
+    }
+
+    #[test]
+    fn test_object_construction_multiplies_outputs() {
+        let outputs: Vec<String> = run("{a: (1, 2), b: (3, 4)}", json!(null))
+            .unwrap()
+            .iter()
+            .map(Value::to_string)
+            .collect();
+        assert_eq!(
+            outputs,
+            vec![
+                r#"{"a":1,"b":3}"#,
+                r#"{"a":1,"b":4}"#,
+                r#"{"a":2,"b":3}"#,
+                r#"{"a":2,"b":4}"#
+            ]
+        );
+        assert_eq!(
+            run("{name: .names[]}", json!({"names": ["x", "y"]})).unwrap(),
+            vec![json!({"name": "x"}), json!({"name": "y"})]
+        );
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!(run("1 + 2 * 3", json!(null)).unwrap(), vec![json!(7)]);
+        assert_eq!(run(".a - 1", json!({"a": 3})).unwrap(), vec![json!(2)]);
+        assert_eq!(
+            run(".[] | .name + \" plays \" + .position", football()).unwrap()[0],
+            json!("Leo Lightning plays Forward")
+        );
+        assert_eq!(
+            run(".a + .b", json!({"a": [1], "b": [2]})).unwrap(),
+            vec![json!([1, 2])]
+        );
+        assert_eq!(run("-.a", json!({"a": 2})).unwrap(), vec![json!(-2)]);
+        assert_eq!(
+            run("{} - {}", json!(null)).unwrap_err().to_string(),
+            "object ({}) and object ({}) cannot be subtracted"
+        );
+    }
+
+    #[test]
+    fn test_number_literals_are_preserved() {
+        let input: Value =
+            serde_json::from_str(r#"{"id": 123456789012345678901234567890, "bizz": 22.0}"#)
+                .unwrap();
+        let outputs = run(".id, .bizz, .bizz + 1, 10000000000000000001 - 1", input).unwrap();
+        let texts: Vec<String> = outputs.iter().map(Value::to_string).collect();
+        assert_eq!(
+            texts,
+            [
+                "123456789012345678901234567890",
+                "22.0",
+                "23",
+                "10000000000000000000"
+            ]
+        );
+        assert_eq!(
+            run(".[0] == .[1]", json!([u64::MAX, u64::MAX - 1])).unwrap(),
+            vec![json!(false)]
+        );
+        // Filter literals keep their digits and sign; exponents are written
+        // the one way serde_json spells them
+        let outputs = run("1.50, -1.50, -0, 1e2, 1E2, -1e2, 1e1000", json!(null)).unwrap();
+        let texts: Vec<String> = outputs.iter().map(Value::to_string).collect();
+        assert_eq!(
+            texts,
+            ["1.50", "-1.50", "-0", "1e+2", "1e+2", "-1e+2", "1e+1000"]
+        );
+        assert_eq!(
+            run("-0 + 0, --1", json!(null)).unwrap(),
+            vec![json!(0), json!(1)]
+        );
+    }
+
+    #[test]
+    fn test_operators_multiply_outputs() {
+        assert_eq!(
+            run("(1, 2) + (10, 20)", json!(null)).unwrap(),
+            vec![json!(11), json!(12), json!(21), json!(22)]
+        );
+    }
+
+    #[test]
+    fn test_comparisons() {
+        assert_eq!(
+            run(".[] | .goals > 30", json!([{"goals": 32}, {"goals": 24}])).unwrap(),
+            vec![json!(true), json!(false)]
+        );
+        assert_eq!(run("1 == 1.0", json!(null)).unwrap(), vec![json!(true)]);
+        assert_eq!(run("null < false", json!(null)).unwrap(), vec![json!(true)]);
+        assert_eq!(
+            run("\"a\" != \"a\"", json!(null)).unwrap(),
+            vec![json!(false)]
+        );
+    }
+
+    #[test]
+    fn test_boolean_operators() {
+        assert_eq!(
+            run("(true, false) and (true, false)", json!(null)).unwrap(),
+            vec![json!(true), json!(false), json!(false)]
+        );
+        assert_eq!(
+            run("(true, false) or (true, false)", json!(null)).unwrap(),
+            vec![json!(true), json!(true), json!(false)]
+        );
+        // The right side is never evaluated when the left side decides
+        assert_eq!(run("false and .[]", json!(1)).unwrap(), vec![json!(false)]);
+        assert_eq!(run(".a or .b", json!({"a": 0})).unwrap(), vec![json!(true)]);
+        assert_eq!(
+            run(".[] | not", json!([null, false, 0, ""])).unwrap(),
+            vec![json!(true), json!(true), json!(false), json!(false)]
+        );
+    }
+
+    #[test]
+    fn test_alternative() {
+        assert_eq!(run(".a // 1", json!({"a": 2})).unwrap(), vec![json!(2)]);
+        assert_eq!(run(".a // 1", json!({"a": null})).unwrap(), vec![json!(1)]);
+        assert_eq!(run(".a // 1", json!({"a": false})).unwrap(), vec![json!(1)]);
+        assert_eq!(
+            run("(.[] | .a) // 0", json!([{"a": 1}, {}, {"a": 3}])).unwrap(),
+            vec![json!(1), json!(3)]
+        );
+        // Errors on the left are swallowed
+        assert_eq!(
+            run(".a.b // \"n/a\"", json!({"a": 1})).unwrap(),
+            vec![json!("n/a")]
+        );
+    }
+
+    #[test]
+    fn test_assignment() {
+        assert_eq!(
+            run(".fizzes[0] |= . + \"!\"", all_types()).unwrap()[0]["fizzes"][0],
+            json!("buzz!")
+        );
+        assert_eq!(
+            run(".a = (1, 2)", json!({})).unwrap(),
+            vec![json!({"a": 1}), json!({"a": 2})]
+        );
+        // The right side of `=` sees the original input, not the selected value
+        assert_eq!(
+            run(".a = .b", json!({"a": 1, "b": 2})).unwrap(),
+            vec![json!({"a": 2, "b": 2})]
+        );
+        assert_eq!(
+            run(".[] |= (. * 10, . * 100)", json!([1, 2])).unwrap(),
+            vec![json!([10, 20])]
+        );
+        assert_eq!(
+            run(".[] |= select(. != 2)", json!([1, 2, 3, 2])).unwrap(),
+            vec![json!([1, 3])]
+        );
+        assert_eq!(
+            run(".a.b[1] = true", json!(null)).unwrap(),
+            vec![json!({"a": {"b": [null, true]}})]
+        );
+    }
+
+    #[test]
+    fn test_arithmetic_assignment() {
+        assert_eq!(
+            run(".[] += 1", json!({"a": 1, "b": 2})).unwrap(),
+            vec![json!({"a": 2, "b": 3})]
+        );
+        assert_eq!(
+            run(".a -= .b", json!({"a": 5, "b": 2})).unwrap(),
+            vec![json!({"a": 3, "b": 2})]
+        );
+        assert_eq!(
+            run(".a *= (2, 3)", json!({"a": 5})).unwrap(),
+            vec![json!({"a": 10}), json!({"a": 15})]
+        );
+        assert_eq!(
+            run(".[] //= \"default\"", json!([1, null, false])).unwrap(),
+            vec![json!([1, "default", "default"])]
+        );
+        assert_eq!(
+            run(".a += 1", json!({"a": "x"})).unwrap_err().to_string(),
+            "string (\"x\") and number (1) cannot be added"
+        );
+        assert_eq!(
+            run("length |= 1", json!([1])).unwrap_err().to_string(),
+            "Invalid path expression with result number (1)"
+        );
+    }
+
+    #[test]
+    fn test_string_interpolation() {
+        assert_eq!(
+            run(".[] | \"\\(.name) plays \\(.position)\"", football()).unwrap(),
+            vec![
+                json!("Leo Lightning plays Forward"),
+                json!("Maximus Defender plays Defender"),
+                json!("Sophie Swift plays Midfielder")
+            ]
+        );
+        assert_eq!(
+            run("\"\\(.)|\\([.])|\\(null)\"", json!("s")).unwrap(),
+            vec![json!("s|[\"s\"]|null")]
+        );
+        // Later interpolations vary slowest, like the operands of `+`
+        assert_eq!(
+            run("\"\\(1, 2)-\\(3, 4)\"", json!(null)).unwrap(),
+            vec![json!("1-3"), json!("2-3"), json!("1-4"), json!("2-4")]
+        );
+        assert_eq!(
+            run("{\"k_\\(.)\": \"\\(\"in\\(. + 1)ner\")\"}", json!(1)).unwrap(),
+            vec![json!({"k_1": "in2ner"})]
+        );
+        assert!(check_all(&parse_program("\"\\($x)\"").unwrap().body).is_err());
+    }
+
+    #[test]
+    fn test_formats() {
+        assert_eq!(
+            run(".[] | [.name, .position, 23] | @csv", football()).unwrap()[0],
+            json!("\"Leo Lightning\",\"Forward\",23")
+        );
+        assert_eq!(
+            run("@json \"v=\\(.)\", @uri \"q=\\(.)\"", json!("a b")).unwrap(),
+            vec![json!("v=\"a b\""), json!("q=a%20b")]
+        );
+        assert_eq!(
+            run("@base64 | ., @base64d", json!("jq")).unwrap(),
+            vec![json!("anE="), json!("jq")]
+        );
+        assert_eq!(
+            run("@csv", json!("x")).unwrap_err().to_string(),
+            "string (\"x\") cannot be csv-formatted, only an array can be"
+        );
+    }
+
+    #[test]
+    fn test_if() {
+        let filter = "if . > 1 then \"big\" elif . == 1 then \"one\" else \"small\" end";
+        assert_eq!(
+            run(&format!(".[] | {}", filter), json!([5, 1, 0])).unwrap(),
+            vec![json!("big"), json!("one"), json!("small")]
+        );
+        assert_eq!(
+            run("if . then 1 end", json!(false)).unwrap(),
+            vec![json!(false)]
+        );
+        assert_eq!(
+            run("if (true, false) then 1 else 2 end", json!(null)).unwrap(),
+            vec![json!(1), json!(2)]
+        );
+    }
+
+    #[test]
+    fn test_try_catch() {
+        let football = football();
+        assert_eq!(
+            run(".name", football.clone()).unwrap_err(),
+            RuntimeError::CannotIndex {
+                target: "array".to_string(),
+                key: "\"name\"".to_string()
+            }
+        );
+        assert_eq!(
+            run(".name?", football.clone()).unwrap(),
+            Vec::<Value>::new()
+        );
+        assert_eq!(
+            run("try .name catch \"n/a\"", football.clone()).unwrap(),
+            vec![json!("n/a")]
+        );
+        assert_eq!(
+            run("try .name catch .", football).unwrap(),
+            vec![json!("Cannot index array with \"name\"")]
+        );
+        assert_eq!(
+            run("[.[]?], [.[]?.a?]", json!(3)).unwrap(),
+            vec![json!([]), json!([])]
+        );
+        // Outputs before the error are kept, and the rest of the body is skipped
+        assert_eq!(
+            run(
+                "[.[] | try (if . == 2 then error else . end) catch \"x\"]",
+                json!([1, 2, 3])
+            )
+            .unwrap(),
+            vec![json!([1, "x", 3])]
+        );
+        assert_eq!(
+            run("[try (1, error(\"e\"), 3) catch .]", json!(null)).unwrap(),
+            vec![json!([1, "e"])]
+        );
+        // The payload of `error` reaches the handler unchanged
+        assert_eq!(
+            run("try error({\"code\": 7}) catch .code", json!(null)).unwrap(),
+            vec![json!(7)]
+        );
+        assert_eq!(
+            run("try error(null) catch .", json!(1)).unwrap(),
+            vec![json!(null)]
+        );
+        // Errors raised by the handler, or after the `try`, are not caught
+        assert_eq!(
+            run("try error(\"a\") catch error(\"b\")", json!(null)).unwrap_err(),
+            RuntimeError::User(json!("b"))
+        );
+        assert!(run("try 1 catch . | error", json!(null)).is_err());
+        assert!(check_all(&parse_program("try $x catch .").unwrap().body).is_err());
+    }
+
+    #[test]
+    fn test_variables() {
+        assert_eq!(
+            run(".a as $x | .b | . + $x", json!({"a": 1, "b": 2})).unwrap(),
+            vec![json!(3)]
+        );
+        assert_eq!(
+            run(".[] as $x | $x * 10", json!([1, 2])).unwrap(),
+            vec![json!(10), json!(20)]
+        );
+        // Inner bindings shadow outer ones only within their body
+        assert_eq!(
+            run("1 as $x | [2 as $x | $x, $x]", json!(null)).unwrap(),
+            vec![json!([2, 2])]
+        );
+        assert_eq!(
+            run("(1 as $x | $x), 2 as $y | [$y]", json!(null)).unwrap(),
+            vec![json!(1), json!([2])]
+        );
+        assert_eq!(
+            run(
+                ".owner as $o | .items[] | {name, $o}",
+                json!({"owner": "al", "items": [{"name": "x"}]})
+            )
+            .unwrap(),
+            vec![json!({"name": "x", "o": "al"})]
+        );
+    }
+
+    #[test]
+    fn test_destructuring() {
+        let student = json!({"name": "Ann", "scores": [90, 80], "id": 7});
+        assert_eq!(
+            run(
+                ". as {name: $n, scores: [$first]} | [$n, $first]",
+                student.clone()
+            )
+            .unwrap(),
+            vec![json!(["Ann", 90])]
+        );
+        assert_eq!(
+            run(
+                ". as {$id, $scores: [$a, $b]} | [$id, $scores, $b]",
+                student.clone()
+            )
+            .unwrap(),
+            vec![json!([7, [90, 80], 80])]
+        );
+        assert_eq!(
+            run(". as [$a, [$b]] | {$a, $b}", json!([1])).unwrap(),
+            vec![json!({"a": 1, "b": null})]
+        );
+        assert_eq!(
+            run(". as {(\"a\", \"b\"): $v} | $v", json!({"a": 1, "b": 2})).unwrap(),
+            vec![json!(1), json!(2)]
+        );
+        assert_eq!(
+            run(". as [$a] | $a", json!({"a": 1}))
+                .unwrap_err()
+                .to_string(),
+            "Cannot index object with number"
+        );
+    }
+
+    #[test]
+    fn test_reduce() {
+        let teams = json!([
+            {"team": "red", "goals": 3},
+            {"team": "blue", "goals": 1},
+            {"team": "red", "goals": 2}
+        ]);
+        assert_eq!(
+            run(
+                "reduce .[] as {team: $t, goals: $n} ({}; . + {($t): ((.[$t] // 0) + $n)})",
+                teams
+            )
+            .unwrap(),
+            vec![json!({"red": 5, "blue": 1})]
+        );
+        assert_eq!(
+            run("reduce .[] as $x (0; . + $x)", json!([1, 2, 3])).unwrap(),
+            vec![json!(6)]
+        );
+        assert_eq!(
+            run("reduce empty as $x (0; . + 1)", json!(null)).unwrap(),
+            vec![json!(0)]
+        );
+        assert_eq!(
+            run("reduce .[] as $x (0; empty)", json!([1])).unwrap(),
+            vec![json!(null)]
+        );
+        assert_eq!(
+            run("reduce .[] as $x ((0, 10); . + $x)", json!([1, 2])).unwrap(),
+            vec![json!(3), json!(13)]
+        );
+    }
+
+    #[test]
+    fn test_foreach() {
+        assert_eq!(
+            run("foreach .[] as $x (0; . + $x)", json!([1, 2, 3])).unwrap(),
+            vec![json!(1), json!(3), json!(6)]
+        );
+        assert_eq!(
+            run("foreach .[] as $x (0; . + $x; [$x, .])", json!([1, 2])).unwrap(),
+            vec![json!([1, 1]), json!([2, 3])]
+        );
+        assert_eq!(
+            run("[foreach .[] as $x (0; (. + $x, . - $x))]", json!([1])).unwrap(),
+            vec![json!([1, -1])]
+        );
+    }
+
+    #[test]
+    fn test_definitions() {
+        assert_eq!(
+            run("def double: . * 2; map(double)", json!([1, 2])).unwrap(),
+            vec![json!([2, 4])]
+        );
+        // Filter arguments are closures evaluated against the callee's input
+        assert_eq!(
+            run("def twice(f): f | f; twice(. + 1)", json!(1)).unwrap(),
+            vec![json!(3)]
+        );
+        assert_eq!(
+            run("def apply(f): [.[] | f]; apply(. * 10)", json!([1, 2])).unwrap(),
+            vec![json!([10, 20])]
+        );
+        // Value parameters bind once per output, and can still be called
+        assert_eq!(
+            run("def f($a; $b): [$a, $b]; f(1, 2; 3, 4)", json!(null)).unwrap(),
+            vec![json!([1, 3]), json!([1, 4]), json!([2, 3]), json!([2, 4])]
+        );
+        assert_eq!(
+            run("def f($a): [a]; f(1, 2)", json!(null)).unwrap(),
+            vec![json!([1, 2]), json!([1, 2])]
+        );
+        // User definitions shadow builtins of the same arity only
+        assert_eq!(
+            check_all(&parse_program("def length: 42; length(.)").unwrap().body)
+                .unwrap_err()
+                .to_string(),
+            "length/1 is not defined"
+        );
+        assert_eq!(
+            run("def length: 42; [length]", json!([1])).unwrap(),
+            vec![json!([42])]
+        );
+    }
+
+    #[test]
+    fn test_recursion_and_scoping() {
+        assert_eq!(
+            run(
+                "def fact: if . <= 1 then 1 else . * (. - 1 | fact) end; fact",
+                json!(10)
+            )
+            .unwrap(),
+            vec![json!(3628800)]
+        );
+        // Closures see the variables and arguments of their definition site
+        assert_eq!(
+            run("1 as $x | def f: $x; 2 as $x | [f, $x]", json!(null)).unwrap(),
+            vec![json!([1, 2])]
+        );
+        assert_eq!(
+            run(
+                "def f(g): def h: g; 10 | h; def g: 99; . as $v | f($v + 1)",
+                json!(1)
+            )
+            .unwrap(),
+            vec![json!(2)]
+        );
+        // A later definition shadows an earlier one only for what follows it
+        assert_eq!(
+            run("def f: 1; def g: f; def f: 2; [f, g]", json!(null)).unwrap(),
+            vec![json!([2, 1])]
+        );
+        // Definitions inside a pipe stay local to it
+        assert!(check_all(&parse_program("(def f: 1; f) | f").unwrap().body).is_err());
+        assert!(check_all(&parse_program("def f(g): g; g").unwrap().body).is_err());
+        assert!(check_all(&parse_program("def f($x): x + $x; f(1)").unwrap().body).is_ok());
+    }
+
+    #[test]
+    fn test_recursion_depth() {
+        // On the stack `main` gives the filter, deep recursion works and
+        // runaway recursion is a catchable error
+        let deep = std::thread::Builder::new()
+            .stack_size(crate::STACK_SIZE)
+            .spawn(|| {
+                let counted = run(
+                    "def f: if . < 3000 then . + 1 | f else . end; 0 | f",
+                    json!(null),
+                );
+                let caught = run("def f: . + 1 | f; try f catch .", json!(0));
+                let paths = run("def f: f; try path(f) catch .", json!(null));
+                (counted, caught, paths)
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        let exceeded = json!(format!("Maximum call depth of {} exceeded", MAX_CALL_DEPTH));
+        assert_eq!(deep.0, Ok(vec![json!(3000)]));
+        assert_eq!(deep.1, Ok(vec![exceeded.clone()]));
+        assert_eq!(deep.2, Ok(vec![exceeded]));
+    }
+
+    #[test]
+    fn test_loc() {
+        assert_eq!(
+            run("$__loc__", json!(null)).unwrap(),
+            vec![json!({"file": "<top-level>", "line": 1})]
+        );
+    }
+
+    #[test]
+    fn test_check_rejects_unknown_variables() {
+        let ast = parse_program(". as $x | $y").unwrap().body;
+        let err = check(&ast, &Env::new()).unwrap_err();
+        assert_eq!(err.to_string(), "$y is not defined");
+        assert_eq!(err.span, crate::ast::Span::new(10, 12));
+        // Bindings do not leak out of their body
+        assert!(check_all(&parse_program("(1 as $x | $x) | $x").unwrap().body).is_err());
+        assert!(check_all(
+            &parse_program(". as {$a, (\"k\" + $a): $b} | $b")
+                .unwrap()
+                .body
+        )
+        .is_ok());
+        // Command-line variables are visible everywhere
+        let globals = Env::new().bind("name", json!("x"));
+        assert!(check(&parse_program("$name").unwrap().body, &globals).is_ok());
+    }
+
+    #[test]
+    fn test_check_rejects_unknown_functions() {
+        let ast = parse_program(".[0:x]").unwrap().body;
+        let err = check(&ast, &Env::new()).unwrap_err();
+        assert_eq!(err.to_string(), "x/0 is not defined");
+        assert_eq!(err.span, crate::ast::Span::new(4, 5));
+        assert!(check_all(&parse_program("del(.a) | length").unwrap().body).is_ok());
+        assert!(check_all(&parse_program("length(.a)").unwrap().body).is_err());
+    }
+}
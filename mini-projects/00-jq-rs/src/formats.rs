@@ -0,0 +1,239 @@
+use crate::error::RuntimeError;
+use crate::strings::tostring;
+use crate::value::describe;
+use serde_json::Value;
+
+/// Names of the `@format` filters
+const FORMATS: &[&str] = &[
+    "text", "json", "csv", "tsv", "html", "uri", "sh", "base64", "base64d",
+];
+
+/// Whether `@name` is a known format
+pub fn is_format(name: &str) -> bool {
+    FORMATS.contains(&name)
+}
+
+/// Format `value` as a string with the format `@name`
+pub fn apply(name: &str, value: &Value) -> Result<String, RuntimeError> {
+    match name {
+        "text" => Ok(tostring(value)),
+        "json" => Ok(value.to_string()),
+        "csv" => row(value, "csv", ",", csv_field),
+        "tsv" => row(value, "tsv", "\t", tsv_field),
+        "html" => Ok(html(&tostring(value))),
+        "uri" => Ok(uri(&tostring(value))),
+        "sh" => sh(value),
+        "base64" => Ok(base64_encode(tostring(value).as_bytes())),
+        "base64d" => base64_decode(&tostring(value)).ok_or_else(|| {
+            RuntimeError::Type(format!("{} is not valid base64 data", describe(value)))
+        }),
+        _ => Err(RuntimeError::Type(format!(
+            "{} is not a valid format",
+            name
+        ))),
+    }
+}
+
+/// Join the formatted elements of an array with `separator`
+fn row(
+    value: &Value,
+    name: &str,
+    separator: &str,
+    field: fn(&Value) -> Option<String>,
+) -> Result<String, RuntimeError> {
+    let items = match value {
+        Value::Array(items) => items,
+        _ => {
+            return Err(RuntimeError::Type(format!(
+                "{} cannot be {}-formatted, only an array can be",
+                describe(value),
+                name
+            )))
+        }
+    };
+    let fields = items
+        .iter()
+        .map(|item| {
+            field(item).ok_or_else(|| {
+                RuntimeError::Type(format!("{} is not valid in a {} row", describe(item), name))
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(fields.join(separator))
+}
+
+/// A CSV field: strings are quoted with `"` doubled, other scalars are bare
+fn csv_field(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(format!("\"{}\"", s.replace('"', "\"\""))),
+        scalar => bare_field(scalar),
+    }
+}
+
+/// A TSV field: backslashes, tabs and line breaks in strings are escaped
+fn tsv_field(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(
+            s.replace('\\', "\\\\")
+                .replace('\t', "\\t")
+                .replace('\n', "\\n")
+                .replace('\r', "\\r"),
+        ),
+        scalar => bare_field(scalar),
+    }
+}
+
+fn bare_field(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => Some(String::new()),
+        Value::Bool(_) | Value::Number(_) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+fn html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '&' => escaped.push_str("&amp;"),
+            '\'' => escaped.push_str("&#39;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Percent-encode every byte except the unreserved characters of RFC 3986
+fn uri(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-_.~".contains(&byte) {
+            escaped.push(byte as char);
+        } else {
+            escaped.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    escaped
+}
+
+/// Quote a string, or each element of an array, for a POSIX shell
+fn sh(value: &Value) -> Result<String, RuntimeError> {
+    let quote = |value: &Value| match value {
+        Value::String(s) => Ok(format!("'{}'", s.replace('\'', "'\\''"))),
+        Value::Array(_) | Value::Object(_) => Err(RuntimeError::Type(format!(
+            "{} can not be escaped for shell",
+            describe(value)
+        ))),
+        scalar => Ok(scalar.to_string()),
+    };
+    match value {
+        Value::Array(items) => Ok(items
+            .iter()
+            .map(quote)
+            .collect::<Result<Vec<_>, _>>()?
+            .join(" ")),
+        other => quote(other),
+    }
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, &byte)| {
+            group | (byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (group >> (18 - 6 * i)) & 0x3F;
+                encoded.push(BASE64_ALPHABET[index as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Decode base64 text, with or without padding. Bytes that are not valid
+/// UTF-8 are replaced, as jq does.
+fn base64_decode(text: &str) -> Option<String> {
+    let text = text.trim_end_matches('=');
+    let mut bytes = Vec::with_capacity(text.len() * 3 / 4);
+    let mut group = 0u32;
+    let mut bits = 0;
+    for c in text.bytes() {
+        let index = BASE64_ALPHABET.iter().position(|&a| a == c)?;
+        group = (group << 6) | index as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((group >> bits) as u8);
+        }
+    }
+    // A single leftover character cannot encode a whole byte
+    if text.len() % 4 == 1 {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_csv_and_tsv() {
+        let row = json!([1, "a \"b\"", null, true, "x\ty"]);
+        assert_eq!(
+            apply("csv", &row).unwrap(),
+            "1,\"a \"\"b\"\"\",,true,\"x\ty\""
+        );
+        assert_eq!(apply("tsv", &row).unwrap(), "1\ta \"b\"\t\ttrue\tx\\ty");
+        assert_eq!(
+            apply("csv", &json!({"a": 1})).unwrap_err().to_string(),
+            "object ({\"a\":1}) cannot be csv-formatted, only an array can be"
+        );
+        assert_eq!(
+            apply("tsv", &json!([[1]])).unwrap_err().to_string(),
+            "array ([1]) is not valid in a tsv row"
+        );
+    }
+
+    #[test]
+    fn test_escaping_formats() {
+        assert_eq!(apply("text", &json!([1])).unwrap(), "[1]");
+        assert_eq!(apply("json", &json!("a\"b")).unwrap(), "\"a\\\"b\"");
+        assert_eq!(
+            apply("html", &json!("<a href='x'>&\"</a>")).unwrap(),
+            "&lt;a href=&#39;x&#39;&gt;&amp;&quot;&lt;/a&gt;"
+        );
+        assert_eq!(apply("uri", &json!("a b/é~")).unwrap(), "a%20b%2F%C3%A9~");
+        assert_eq!(
+            apply("sh", &json!(["it's", 1, null])).unwrap(),
+            "'it'\\''s' 1 null"
+        );
+        assert!(apply("sh", &json!({})).is_err());
+    }
+
+    #[test]
+    fn test_base64() {
+        for (plain, encoded) in [("", ""), ("f", "Zg=="), ("fo", "Zm8="), ("foo", "Zm9v")] {
+            assert_eq!(apply("base64", &json!(plain)).unwrap(), encoded);
+            assert_eq!(apply("base64d", &json!(encoded)).unwrap(), plain);
+        }
+        assert_eq!(apply("base64", &json!("héllo")).unwrap(), "aMOpbGxv");
+        assert_eq!(apply("base64d", &json!("aMOpbGxv")).unwrap(), "héllo");
+        assert_eq!(apply("base64d", &json!("Zm9vYg")).unwrap(), "foob");
+        assert_eq!(
+            apply("base64d", &json!("Zm9v!")).unwrap_err().to_string(),
+            "string (\"Zm9v!\") is not valid base64 data"
+        );
+    }
+}
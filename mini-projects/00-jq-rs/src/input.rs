@@ -0,0 +1,516 @@
+use crate::error::InputError;
+use serde_json::de::{IoRead, StreamDeserializer};
+use serde_json::{Deserializer, Value};
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::vec;
+
+/// Somewhere to read JSON values from
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Stdin,
+    File(PathBuf),
+}
+
+impl Source {
+    fn open(&self) -> io::Result<Box<dyn BufRead>> {
+        match self {
+            Source::Stdin => Ok(Box::new(io::stdin().lock())),
+            Source::File(path) => Ok(Box::new(BufReader::new(File::open(path)?))),
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Stdin => write!(f, "<stdin>"),
+            Source::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// A 1-based line and character column in a named source
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub source: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.source, self.line, self.column)
+    }
+}
+
+/// One JSON value from an input, with where it starts
+#[derive(Debug, Clone, PartialEq)]
+pub struct Document {
+    pub value: Value,
+    pub location: Location,
+}
+
+/// How the text of a source is split into values
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// Whitespace-separated (or simply concatenated) JSON texts
+    Json,
+    /// Each line as a string, without its newline
+    Lines,
+    /// The whole text as one string
+    Text,
+}
+
+/// The values of each source in turn. A source is only read once the
+/// previous one is used up, and reading stops at the first error.
+pub struct Inputs {
+    sources: vec::IntoIter<Source>,
+    format: Format,
+    /// Where a slurped value is said to start
+    first: String,
+    current: Option<Documents>,
+    failed: bool,
+}
+
+impl Inputs {
+    pub fn new(sources: Vec<Source>, format: Format) -> Self {
+        Inputs {
+            first: sources.first().map(Source::to_string).unwrap_or_default(),
+            sources: sources.into_iter(),
+            format,
+            current: None,
+            failed: false,
+        }
+    }
+
+    /// Every value as one: an array of the JSON values, or the raw texts
+    /// joined into a single string
+    pub fn slurp(self) -> Result<Document, InputError> {
+        let location = Location {
+            source: self.first.clone(),
+            line: 1,
+            column: 1,
+        };
+        let value = match self.format {
+            Format::Json => Value::Array(
+                self.map(|document| document.map(|document| document.value))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Format::Lines | Format::Text => {
+                let mut text = String::new();
+                for document in self {
+                    if let Value::String(part) = document?.value {
+                        text.push_str(&part);
+                    }
+                }
+                Value::String(text)
+            }
+        };
+        Ok(Document { value, location })
+    }
+}
+
+impl Iterator for Inputs {
+    type Item = Result<Document, InputError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.failed {
+            if let Some(document) = self.current.as_mut().and_then(Documents::next) {
+                self.failed = document.is_err();
+                return Some(document);
+            }
+            let source = self.sources.next()?;
+            match source.open() {
+                Ok(reader) => {
+                    self.current = Some(Documents::new(&source.to_string(), reader, self.format))
+                }
+                Err(err) => {
+                    self.failed = true;
+                    return Some(Err(InputError::Io {
+                        source: source.to_string(),
+                        message: err.to_string(),
+                    }));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Bytes JSON allows between values
+const WHITESPACE: [u8; 4] = [b' ', b'\t', b'\n', b'\r'];
+
+/// How far a [`Tracked`] reader has read, which is how values parsed from it
+/// learn where they start
+#[derive(Debug)]
+struct Position {
+    /// Line and character column of the next byte
+    line: usize,
+    column: usize,
+    /// Bytes read so far
+    offset: usize,
+    /// Where the last byte read is, if it is not whitespace
+    last: Option<(usize, usize)>,
+    /// Where the value being parsed starts, once its first byte is read
+    start: Option<(usize, usize)>,
+}
+
+impl Position {
+    fn new() -> Self {
+        Position {
+            line: 1,
+            column: 1,
+            offset: 0,
+            last: None,
+            start: None,
+        }
+    }
+
+    fn read(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            let at = (self.line, self.column);
+            self.last = (!WHITESPACE.contains(&byte)).then_some(at);
+            if self.start.is_none() {
+                self.start = self.last;
+            }
+            if byte == b'\n' {
+                self.line += 1;
+                self.column = 1;
+            } else if byte & 0xC0 != 0x80 {
+                // Only the first byte of a UTF-8 character starts a column
+                self.column += 1;
+            }
+        }
+        self.offset += bytes.len();
+    }
+
+    /// Take the start of a value that ended at byte `end`. The parser may
+    /// have read one byte past the end, which could start the next value.
+    fn finish(&mut self, end: usize) -> Option<(usize, usize)> {
+        let start = self.start.take();
+        if self.offset > end {
+            self.start = self.last;
+        }
+        start
+    }
+}
+
+/// A reader that keeps a [`Position`] up to date as it is read
+struct Tracked {
+    reader: Box<dyn BufRead>,
+    position: Rc<RefCell<Position>>,
+}
+
+impl Read for Tracked {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.position.borrow_mut().read(&buf[..len]);
+        Ok(len)
+    }
+}
+
+/// How the values of one source are read
+enum Reader {
+    Json {
+        values: StreamDeserializer<'static, IoRead<Tracked>, Value>,
+        position: Rc<RefCell<Position>>,
+    },
+    Raw(Box<dyn BufRead>),
+    Done,
+}
+
+/// The values of one source, each read only when it is asked for, so that
+/// values arriving on a pipe are seen as soon as they are complete
+struct Documents {
+    source: String,
+    format: Format,
+    reader: Reader,
+    /// Line of the next raw line
+    line: usize,
+}
+
+impl Documents {
+    fn new(source: &str, reader: Box<dyn BufRead>, format: Format) -> Self {
+        let reader = match format {
+            Format::Json => {
+                let position = Rc::new(RefCell::new(Position::new()));
+                let tracked = Tracked {
+                    reader,
+                    position: position.clone(),
+                };
+                Reader::Json {
+                    values: Deserializer::from_reader(tracked).into_iter(),
+                    position,
+                }
+            }
+            Format::Lines | Format::Text => Reader::Raw(reader),
+        };
+        Documents {
+            source: source.into(),
+            format,
+            reader,
+            line: 1,
+        }
+    }
+
+    fn location(&self, line: usize, column: usize) -> Location {
+        Location {
+            source: self.source.clone(),
+            line,
+            column,
+        }
+    }
+
+    fn io_error(&mut self, err: impl fmt::Display) -> InputError {
+        self.reader = Reader::Done;
+        InputError::Io {
+            source: self.source.clone(),
+            message: err.to_string(),
+        }
+    }
+
+    /// The next line without its newline, or with `whole` everything left.
+    /// Bytes that are not UTF-8 become U+FFFD rather than failing the run.
+    fn raw(&mut self, whole: bool) -> Option<Result<Document, InputError>> {
+        let Reader::Raw(reader) = &mut self.reader else {
+            return None;
+        };
+        let mut bytes = Vec::new();
+        let read = if whole {
+            reader.read_to_end(&mut bytes)
+        } else {
+            reader.read_until(b'\n', &mut bytes)
+        };
+        match read {
+            Ok(0) => None,
+            Ok(_) => {
+                let location = self.location(self.line, 1);
+                if !whole && bytes.last() == Some(&b'\n') {
+                    bytes.pop();
+                    self.line += 1;
+                }
+                let text = String::from_utf8_lossy(&bytes).into_owned();
+                Some(Ok(Document {
+                    value: Value::String(text),
+                    location,
+                }))
+            }
+            Err(err) => Some(Err(self.io_error(err))),
+        }
+    }
+
+    fn json(&mut self) -> Option<Result<Document, InputError>> {
+        let Reader::Json { values, position } = &mut self.reader else {
+            return None;
+        };
+        let next = values.next()?;
+        let end = values.byte_offset();
+        let start = position.borrow_mut().finish(end);
+        match next {
+            Ok(value) => {
+                let (line, column) = start.unwrap_or((1, 1));
+                Some(Ok(Document {
+                    value,
+                    location: self.location(line, column),
+                }))
+            }
+            Err(err) if err.is_io() => Some(Err(self.io_error(err))),
+            Err(err) => {
+                let suffix = format!(" at line {} column {}", err.line(), err.column());
+                let message = err.to_string();
+                let message = message
+                    .strip_suffix(&suffix)
+                    .unwrap_or(&message)
+                    .to_string();
+                // serde reports column 0 when the text ends after a newline
+                let location = self.location(err.line(), err.column().max(1));
+                self.reader = Reader::Done;
+                Some(Err(InputError::Parse { location, message }))
+            }
+        }
+    }
+}
+
+impl Iterator for Documents {
+    type Item = Result<Document, InputError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.format {
+            Format::Json => self.json(),
+            Format::Lines => self.raw(false),
+            Format::Text => self.raw(true),
+        }
+    }
+}
+
+type Queue = Box<dyn Iterator<Item = Result<Document, InputError>>>;
+
+thread_local! {
+    /// The inputs not yet read, shared by the main loop and the `input` and
+    /// `inputs` builtins
+    static PENDING: RefCell<Option<Queue>> = const { RefCell::new(None) };
+}
+
+/// Make `inputs` the values returned by [`next`]
+pub fn provide(inputs: impl Iterator<Item = Result<Document, InputError>> + 'static) {
+    PENDING.with(|pending| *pending.borrow_mut() = Some(Box::new(inputs)));
+}
+
+/// The next input not yet read by anyone
+pub fn next() -> Option<Result<Document, InputError>> {
+    PENDING.with(|pending| pending.borrow_mut().as_mut()?.next())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::fs;
+
+    fn documents(text: &str) -> Vec<Result<(Value, usize, usize), String>> {
+        parse(text, Format::Json)
+    }
+
+    fn parse(text: &str, format: Format) -> Vec<Result<(Value, usize, usize), String>> {
+        let reader = Box::new(io::Cursor::new(text.as_bytes().to_vec()));
+        Documents::new("in.json", reader, format)
+            .map(|document| {
+                document
+                    .map(|d| (d.value, d.location.line, d.location.column))
+                    .map_err(|err| err.to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_value_streams() {
+        assert_eq!(
+            documents("{\"a\": 1}\n{\"a\": 2}\n"),
+            vec![Ok((json!({"a": 1}), 1, 1)), Ok((json!({"a": 2}), 2, 1))]
+        );
+        assert_eq!(
+            documents("1 2\t\"three\"[4]{}\r\n  null"),
+            vec![
+                Ok((json!(1), 1, 1)),
+                Ok((json!(2), 1, 3)),
+                Ok((json!("three"), 1, 5)),
+                Ok((json!([4]), 1, 12)),
+                Ok((json!({}), 1, 15)),
+                Ok((json!(null), 2, 3)),
+            ]
+        );
+        assert_eq!(documents(""), vec![]);
+        assert_eq!(documents(" \n\n "), vec![]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            documents("{\"a\": 1}\n  {\"a\": tru}\n{}"),
+            vec![
+                Ok((json!({"a": 1}), 1, 1)),
+                Err("could not parse in.json:2:12: expected ident".to_string()),
+            ]
+        );
+        assert_eq!(
+            documents("[1,\n2,\n"),
+            vec![Err(
+                "could not parse in.json:3:1: EOF while parsing a value".to_string()
+            )]
+        );
+    }
+
+    /// A reader that fails the test if it is ever read
+    struct Unreadable;
+
+    impl Read for Unreadable {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            panic!("read past the values that were asked for");
+        }
+    }
+
+    #[test]
+    fn test_values_are_read_lazily() {
+        for (text, format) in [("1 [2]\n", Format::Json), ("one\ntwo\n", Format::Lines)] {
+            let reader = io::Cursor::new(text.as_bytes()).chain(Unreadable);
+            let mut documents = Documents::new("<stdin>", Box::new(BufReader::new(reader)), format);
+            assert!(documents.next().unwrap().is_ok());
+            assert!(documents.next().unwrap().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_invalid_utf8() {
+        let read = |bytes: &'static [u8], format| {
+            Documents::new("in.json", Box::new(bytes), format)
+                .map(|document| document.map(|d| d.value).map_err(|err| err.to_string()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            read(b"caf\xe9\nok\n", Format::Lines),
+            vec![Ok(json!("caf\u{fffd}")), Ok(json!("ok"))]
+        );
+        // Values before the invalid bytes are still read
+        let values = read(b"1 \"\xff\"", Format::Json);
+        assert_eq!(values[0], Ok(json!(1)));
+        assert!(values[1]
+            .as_ref()
+            .unwrap_err()
+            .starts_with("could not parse in.json:1:"));
+    }
+
+    #[test]
+    fn test_missing_file() {
+        let mut inputs = Inputs::new(
+            vec![
+                Source::File(PathBuf::from("does/not/exist.json")),
+                Source::Stdin,
+            ],
+            Format::Json,
+        );
+        let err = inputs.next().unwrap().unwrap_err().to_string();
+        assert!(err.starts_with("could not read does/not/exist.json: "));
+        // Nothing is read after an error
+        assert!(inputs.next().is_none());
+    }
+
+    #[test]
+    fn test_raw_input() {
+        assert_eq!(
+            parse("one\n\n  two {\nthree", Format::Lines),
+            vec![
+                Ok((json!("one"), 1, 1)),
+                Ok((json!(""), 2, 1)),
+                Ok((json!("  two {"), 3, 1)),
+                Ok((json!("three"), 4, 1)),
+            ]
+        );
+        assert_eq!(
+            parse("one\ntwo\n", Format::Text),
+            vec![Ok((json!("one\ntwo\n"), 1, 1))]
+        );
+        assert_eq!(parse("", Format::Lines), vec![]);
+    }
+
+    #[test]
+    fn test_slurp() {
+        let dir = std::env::temp_dir().join(format!("jq-rs-slurp-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (dir.join("a.json"), dir.join("b.json"));
+        fs::write(&a, "1 [2]\n").unwrap();
+        fs::write(&b, "{\"c\": 3}").unwrap();
+        let sources = vec![Source::File(a.clone()), Source::File(b)];
+
+        let slurped = Inputs::new(sources.clone(), Format::Json).slurp().unwrap();
+        assert_eq!(slurped.value, json!([1, [2], {"c": 3}]));
+        assert_eq!(slurped.location.to_string(), format!("{}:1:1", a.display()));
+        let slurped = Inputs::new(sources, Format::Text).slurp().unwrap();
+        assert_eq!(slurped.value, json!("1 [2]\n{\"c\": 3}"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
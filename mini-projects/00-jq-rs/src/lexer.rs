@@ -0,0 +1,644 @@
+use crate::ast::Span;
+use crate::error::{ParseError, ParseErrorKind};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    /// `.`
+    Dot,
+    /// `..`
+    DotDot,
+    /// `.name`
+    Field(String),
+    /// A bare word such as a builtin name, possibly qualified as `lib::name`
+    Ident(String),
+    /// `$name`
+    Variable(String),
+    /// `@name`
+    Format(String),
+    /// A numeric literal, kept as written
+    Number(String),
+    /// A string literal with its escapes already decoded
+    Str(String),
+    /// A string literal containing `\(...)` interpolations
+    Template(Vec<Segment>),
+    LBracket,
+    RBracket,
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+    Colon,
+    Semicolon,
+    Comma,
+    /// `?`
+    Question,
+    Pipe,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    /// `//`
+    Alt,
+    /// `=`
+    Assign,
+    /// `|=`
+    PipeAssign,
+    /// `+=`, `-=`, `*=`, `/=`, `%=` and `//=`
+    PlusAssign,
+    MinusAssign,
+    StarAssign,
+    SlashAssign,
+    PercentAssign,
+    AltAssign,
+    EqEq,
+    NotEq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eof,
+}
+
+/// A piece of a string literal with interpolations
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment {
+    Text(String),
+    /// The tokens between `\(` and the matching `)`, ending with `Eof`
+    Interpolated(Vec<Token>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+struct Lexer<'a> {
+    source: &'a str,
+    pos: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn peek(&self) -> Option<char> {
+        self.source[self.pos..].chars().next()
+    }
+
+    fn peek_second(&self) -> Option<char> {
+        self.source[self.pos..].chars().nth(1)
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn eat_char(&mut self, expected: char) -> bool {
+        if self.peek() == Some(expected) {
+            self.bump();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Skip whitespace and `#` comments
+    fn skip_trivia(&mut self) {
+        while let Some(c) = self.peek() {
+            if c.is_whitespace() {
+                self.bump();
+            } else if c == '#' {
+                while let Some(c) = self.bump() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn take_while(&mut self, pred: impl Fn(char) -> bool) -> &'a str {
+        let start = self.pos;
+        while self.peek().is_some_and(&pred) {
+            self.bump();
+        }
+        &self.source[start..self.pos]
+    }
+
+    fn number(&mut self) -> Result<TokenKind, ParseError> {
+        let start = self.pos;
+        self.take_while(|c| c.is_ascii_digit());
+        if self.peek() == Some('.') && self.peek_second().is_some_and(|c| c.is_ascii_digit()) {
+            self.bump();
+            self.take_while(|c| c.is_ascii_digit());
+        }
+        if matches!(self.peek(), Some('e' | 'E')) {
+            self.bump();
+            if matches!(self.peek(), Some('+' | '-')) {
+                self.bump();
+            }
+            if self.take_while(|c| c.is_ascii_digit()).is_empty() {
+                return Err(ParseError::new(
+                    ParseErrorKind::InvalidNumber(self.source[start..self.pos].to_string()),
+                    Span::new(start, self.pos),
+                ));
+            }
+        }
+        Ok(TokenKind::Number(self.source[start..self.pos].to_string()))
+    }
+
+    /// Read the rest of a string literal whose opening quote started at `start`
+    fn string(&mut self, start: usize) -> Result<TokenKind, ParseError> {
+        let mut text = String::new();
+        let mut segments = Vec::new();
+        loop {
+            match self.bump() {
+                None => return Err(self.unterminated(start)),
+                Some('"') if segments.is_empty() => return Ok(TokenKind::Str(text)),
+                Some('"') => {
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(text));
+                    }
+                    return Ok(TokenKind::Template(segments));
+                }
+                Some('\\') if self.eat_char('(') => {
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(std::mem::take(&mut text)));
+                    }
+                    segments.push(Segment::Interpolated(self.interpolation(start)?));
+                }
+                Some('\\') => text.push(self.escape()?),
+                Some(c) => text.push(c),
+            }
+        }
+    }
+
+    /// Read the tokens of an interpolation up to its closing parenthesis, for
+    /// a string literal that started at `start`
+    fn interpolation(&mut self, start: usize) -> Result<Vec<Token>, ParseError> {
+        let mut tokens = Vec::new();
+        let mut depth = 0;
+        loop {
+            let token = self.next_token()?;
+            match token.kind {
+                TokenKind::Eof => return Err(self.unterminated(start)),
+                TokenKind::LParen => depth += 1,
+                TokenKind::RParen if depth == 0 => {
+                    tokens.push(Token {
+                        kind: TokenKind::Eof,
+                        span: token.span,
+                    });
+                    return Ok(tokens);
+                }
+                TokenKind::RParen => depth -= 1,
+                _ => {}
+            }
+            tokens.push(token);
+        }
+    }
+
+    fn unterminated(&self, start: usize) -> ParseError {
+        ParseError::new(
+            ParseErrorKind::UnterminatedString,
+            Span::new(start, self.pos),
+        )
+    }
+
+    /// Decode the escape sequence following a backslash
+    fn escape(&mut self) -> Result<char, ParseError> {
+        let start = self.pos - 1;
+        let decoded = match self.bump() {
+            Some('"') => '"',
+            Some('\\') => '\\',
+            Some('/') => '/',
+            Some('b') => '\u{8}',
+            Some('f') => '\u{c}',
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some('u') => {
+                let high = self.hex4(start)?;
+                let code = if (0xD800..0xDC00).contains(&high)
+                    && self.source[self.pos..].starts_with("\\u")
+                {
+                    self.pos += 2;
+                    let low = self.hex4(start)?;
+                    0x10000 + ((high - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF)
+                } else {
+                    high
+                };
+                char::from_u32(code).ok_or_else(|| self.invalid_escape(start))?
+            }
+            _ => return Err(self.invalid_escape(start)),
+        };
+        Ok(decoded)
+    }
+
+    fn hex4(&mut self, start: usize) -> Result<u32, ParseError> {
+        let digits = self.source.get(self.pos..self.pos + 4).unwrap_or("");
+        let code = u32::from_str_radix(digits, 16).map_err(|_| self.invalid_escape(start))?;
+        self.pos += 4;
+        Ok(code)
+    }
+
+    fn invalid_escape(&self, start: usize) -> ParseError {
+        let end = self.pos.min(self.source.len());
+        ParseError::new(
+            ParseErrorKind::InvalidEscape(self.source[start..end].to_string()),
+            Span::new(start, end),
+        )
+    }
+
+    fn next_token(&mut self) -> Result<Token, ParseError> {
+        self.skip_trivia();
+        let start = self.pos;
+        let c = match self.peek() {
+            Some(c) => c,
+            None => {
+                return Ok(Token {
+                    kind: TokenKind::Eof,
+                    span: Span::new(start, start),
+                })
+            }
+        };
+
+        // `.5` is a number, as in jq, rather than `.` followed by one
+        let kind = if c.is_ascii_digit()
+            || (c == '.' && self.peek_second().is_some_and(|c| c.is_ascii_digit()))
+        {
+            self.number()?
+        } else if c == '.' {
+            self.bump();
+            if self.peek().is_some_and(is_ident_start) {
+                TokenKind::Field(self.take_while(is_ident_continue).to_string())
+            } else if self.eat_char('.') {
+                TokenKind::DotDot
+            } else {
+                TokenKind::Dot
+            }
+        } else if is_ident_start(c) {
+            self.take_while(is_ident_continue);
+            // `lib::name` refers to a function of an imported module
+            while self.source[self.pos..].starts_with("::")
+                && self.source[self.pos + 2..]
+                    .chars()
+                    .next()
+                    .is_some_and(is_ident_start)
+            {
+                self.pos += 2;
+                self.take_while(is_ident_continue);
+            }
+            TokenKind::Ident(self.source[start..self.pos].to_string())
+        } else if c == '@' && self.peek_second().is_some_and(is_ident_start) {
+            self.bump();
+            TokenKind::Format(self.take_while(is_ident_continue).to_string())
+        } else if c == '$' && self.peek_second().is_some_and(is_ident_start) {
+            self.bump();
+            TokenKind::Variable(self.take_while(is_ident_continue).to_string())
+        } else if c == '"' {
+            self.bump();
+            self.string(start)?
+        } else {
+            self.bump();
+            match c {
+                '[' => TokenKind::LBracket,
+                ']' => TokenKind::RBracket,
+                '(' => TokenKind::LParen,
+                ')' => TokenKind::RParen,
+                '{' => TokenKind::LBrace,
+                '}' => TokenKind::RBrace,
+                ':' => TokenKind::Colon,
+                ';' => TokenKind::Semicolon,
+                ',' => TokenKind::Comma,
+                '?' => TokenKind::Question,
+                '|' if self.eat_char('=') => TokenKind::PipeAssign,
+                '|' => TokenKind::Pipe,
+                '+' if self.eat_char('=') => TokenKind::PlusAssign,
+                '+' => TokenKind::Plus,
+                '-' if self.eat_char('=') => TokenKind::MinusAssign,
+                '-' => TokenKind::Minus,
+                '*' if self.eat_char('=') => TokenKind::StarAssign,
+                '*' => TokenKind::Star,
+                '%' if self.eat_char('=') => TokenKind::PercentAssign,
+                '%' => TokenKind::Percent,
+                '/' if self.eat_char('/') => {
+                    if self.eat_char('=') {
+                        TokenKind::AltAssign
+                    } else {
+                        TokenKind::Alt
+                    }
+                }
+                '/' if self.eat_char('=') => TokenKind::SlashAssign,
+                '/' => TokenKind::Slash,
+                '=' if self.eat_char('=') => TokenKind::EqEq,
+                '=' => TokenKind::Assign,
+                '!' if self.eat_char('=') => TokenKind::NotEq,
+                '<' if self.eat_char('=') => TokenKind::Le,
+                '<' => TokenKind::Lt,
+                '>' if self.eat_char('=') => TokenKind::Ge,
+                '>' => TokenKind::Gt,
+                _ => {
+                    return Err(ParseError::new(
+                        ParseErrorKind::UnexpectedChar(c),
+                        Span::new(start, self.pos),
+                    ))
+                }
+            }
+        };
+
+        Ok(Token {
+            kind,
+            span: Span::new(start, self.pos),
+        })
+    }
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_ident_continue(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Split a filter string into tokens, always ending with `Eof`
+pub fn tokenize(source: &str) -> Result<Vec<Token>, ParseError> {
+    let mut lexer = Lexer { source, pos: 0 };
+    let mut tokens = Vec::new();
+    loop {
+        let token = lexer.next_token()?;
+        let done = token.kind == TokenKind::Eof;
+        tokens.push(token);
+        if done {
+            return Ok(tokens);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(source: &str) -> Vec<TokenKind> {
+        tokenize(source)
+            .unwrap()
+            .into_iter()
+            .map(|token| token.kind)
+            .collect()
+    }
+
+    #[test]
+    fn test_identity() {
+        assert_eq!(kinds("."), vec![TokenKind::Dot, TokenKind::Eof]);
+        assert_eq!(kinds("  .  "), vec![TokenKind::Dot, TokenKind::Eof]);
+        assert_eq!(
+            kinds("...a"),
+            vec![
+                TokenKind::DotDot,
+                TokenKind::Field("a".to_string()),
+                TokenKind::Eof
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fields() {
+        assert_eq!(
+            kinds(".fizz.buzz_2"),
+            vec![
+                TokenKind::Field("fizz".to_string()),
+                TokenKind::Field("buzz_2".to_string()),
+                TokenKind::Eof
+            ]
+        );
+    }
+
+    #[test]
+    fn test_index_and_slice() {
+        assert_eq!(
+            kinds(".[0:12]"),
+            vec![
+                TokenKind::Dot,
+                TokenKind::LBracket,
+                TokenKind::Number("0".to_string()),
+                TokenKind::Colon,
+                TokenKind::Number("12".to_string()),
+                TokenKind::RBracket,
+                TokenKind::Eof
+            ]
+        );
+    }
+
+    #[test]
+    fn test_numbers() {
+        assert_eq!(
+            kinds("1.5 2e10 3E-2 .25"),
+            vec![
+                TokenKind::Number("1.5".to_string()),
+                TokenKind::Number("2e10".to_string()),
+                TokenKind::Number("3E-2".to_string()),
+                TokenKind::Number(".25".to_string()),
+                TokenKind::Eof
+            ]
+        );
+        assert!(tokenize("1e").is_err());
+    }
+
+    #[test]
+    fn test_call_and_pipe() {
+        assert_eq!(
+            kinds(". | del(.a) # trailing comment"),
+            vec![
+                TokenKind::Dot,
+                TokenKind::Pipe,
+                TokenKind::Ident("del".to_string()),
+                TokenKind::LParen,
+                TokenKind::Field("a".to_string()),
+                TokenKind::RParen,
+                TokenKind::Eof
+            ]
+        );
+    }
+
+    #[test]
+    fn test_spans() {
+        let tokens = tokenize(".fizzes | .[1]").unwrap();
+        let spans: Vec<Span> = tokens.iter().map(|token| token.span).collect();
+        assert_eq!(
+            spans,
+            vec![
+                Span::new(0, 7),
+                Span::new(8, 9),
+                Span::new(10, 11),
+                Span::new(11, 12),
+                Span::new(12, 13),
+                Span::new(13, 14),
+                Span::new(14, 14)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_strings() {
+        assert_eq!(
+            kinds(r#""a b" "q\"\\\n" "\u00e9\ud83d\ude00""#),
+            vec![
+                TokenKind::Str("a b".to_string()),
+                TokenKind::Str("q\"\\\n".to_string()),
+                TokenKind::Str("é😀".to_string()),
+                TokenKind::Eof
+            ]
+        );
+        let err = tokenize(r#"."abc"#).unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::UnterminatedString);
+        assert_eq!(err.span, Span::new(1, 5));
+        let err = tokenize(r#""a\qb""#).unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::InvalidEscape("\\q".to_string()));
+        assert_eq!(err.span, Span::new(2, 4));
+    }
+
+    #[test]
+    fn test_interpolation() {
+        let tokens = tokenize(r#""a\(.b + ("c" | "\(1)"))d""#).unwrap();
+        let segments = match &tokens[0].kind {
+            TokenKind::Template(segments) => segments,
+            other => panic!("expected a template, found {:?}", other),
+        };
+        assert_eq!(segments.len(), 3);
+        assert_eq!(segments[0], Segment::Text("a".to_string()));
+        assert_eq!(segments[2], Segment::Text("d".to_string()));
+        let inner = match &segments[1] {
+            Segment::Interpolated(inner) => inner,
+            other => panic!("expected an interpolation, found {:?}", other),
+        };
+        assert_eq!(inner.len(), 8);
+        assert!(matches!(inner[5].kind, TokenKind::Template(_)));
+        assert_eq!(inner[7].kind, TokenKind::Eof);
+        assert_eq!(tokens[1].kind, TokenKind::Eof);
+
+        let err = tokenize(r#""a\(1"#).unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::UnterminatedString);
+    }
+
+    #[test]
+    fn test_formats() {
+        assert_eq!(
+            kinds("@csv \"x\""),
+            vec![
+                TokenKind::Format("csv".to_string()),
+                TokenKind::Str("x".to_string()),
+                TokenKind::Eof
+            ]
+        );
+        let err = tokenize("@ csv").unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::UnexpectedChar('@'));
+    }
+
+    #[test]
+    fn test_constructors() {
+        assert_eq!(
+            kinds("{a: [1, 2]}"),
+            vec![
+                TokenKind::LBrace,
+                TokenKind::Ident("a".to_string()),
+                TokenKind::Colon,
+                TokenKind::LBracket,
+                TokenKind::Number("1".to_string()),
+                TokenKind::Comma,
+                TokenKind::Number("2".to_string()),
+                TokenKind::RBracket,
+                TokenKind::RBrace,
+                TokenKind::Eof
+            ]
+        );
+    }
+
+    #[test]
+    fn test_operators() {
+        assert_eq!(
+            kinds("+ - * / % // == != < <= > >="),
+            vec![
+                TokenKind::Plus,
+                TokenKind::Minus,
+                TokenKind::Star,
+                TokenKind::Slash,
+                TokenKind::Percent,
+                TokenKind::Alt,
+                TokenKind::EqEq,
+                TokenKind::NotEq,
+                TokenKind::Lt,
+                TokenKind::Le,
+                TokenKind::Gt,
+                TokenKind::Ge,
+                TokenKind::Eof
+            ]
+        );
+        assert_eq!(
+            kinds("= |= += -= *= /= %= //= =="),
+            vec![
+                TokenKind::Assign,
+                TokenKind::PipeAssign,
+                TokenKind::PlusAssign,
+                TokenKind::MinusAssign,
+                TokenKind::StarAssign,
+                TokenKind::SlashAssign,
+                TokenKind::PercentAssign,
+                TokenKind::AltAssign,
+                TokenKind::EqEq,
+                TokenKind::Eof
+            ]
+        );
+        assert_eq!(
+            tokenize(".a ! .b").unwrap_err().kind,
+            ParseErrorKind::UnexpectedChar('!')
+        );
+    }
+
+    #[test]
+    fn test_qualified_names() {
+        assert_eq!(
+            kinds("lib::f(1) | a::b"),
+            vec![
+                TokenKind::Ident("lib::f".to_string()),
+                TokenKind::LParen,
+                TokenKind::Number("1".to_string()),
+                TokenKind::RParen,
+                TokenKind::Pipe,
+                TokenKind::Ident("a::b".to_string()),
+                TokenKind::Eof
+            ]
+        );
+        assert_eq!(
+            kinds("{a::1}")[1..3],
+            [TokenKind::Ident("a".to_string()), TokenKind::Colon]
+        );
+    }
+
+    #[test]
+    fn test_variables() {
+        assert_eq!(
+            kinds(". as $x | $__loc__"),
+            vec![
+                TokenKind::Dot,
+                TokenKind::Ident("as".to_string()),
+                TokenKind::Variable("x".to_string()),
+                TokenKind::Pipe,
+                TokenKind::Variable("__loc__".to_string()),
+                TokenKind::Eof
+            ]
+        );
+        assert_eq!(
+            tokenize("$ x").unwrap_err().kind,
+            ParseErrorKind::UnexpectedChar('$')
+        );
+    }
+
+    #[test]
+    fn test_unexpected_character() {
+        let err = tokenize(".a & .b").unwrap_err();
+        assert_eq!(err.span, Span::new(3, 4));
+    }
+}
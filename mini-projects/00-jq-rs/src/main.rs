@@ -1,3 +1,313 @@
+mod arrays;
+mod ast;
+mod builtins;
+mod env;
+mod error;
+mod eval;
+mod formats;
+mod input;
+mod lexer;
+mod modules;
+mod ops;
+mod parser;
+mod path;
+mod printer;
+mod regexp;
+mod strings;
+mod time;
+mod value;
+
+use clap::Parser;
+use env::Env;
+use error::{Failure, RuntimeError};
+use input::{Format, Inputs, Source};
+use modules::Loader;
+use printer::{Colors, Indent, Printer};
+use serde_json::Value;
+use std::io::{self, IsTerminal, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+
+/// The stack of the thread that runs the filter, deep enough for
+/// `eval::MAX_CALL_DEPTH` nested calls to user-defined functions, with room
+/// to spare for whatever runs outside of them
+const STACK_SIZE: usize = eval::MAX_CALL_DEPTH * eval::CALL_STACK_SIZE + (8 << 20);
+
+/// A jq clone for querying JSON files
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// Filter to apply to the input
+    #[arg(allow_hyphen_values = true)]
+    filter: String,
+
+    /// JSON files to read, each holding any number of values [default: stdin]
+    files: Vec<PathBuf>,
+
+    /// Bind `$NAME` to the string VALUE
+    #[arg(long = "arg", num_args = 2, value_names = ["NAME", "VALUE"])]
+    string_args: Vec<String>,
+
+    /// Bind `$NAME` to the JSON value TEXT
+    #[arg(long = "argjson", num_args = 2, value_names = ["NAME", "TEXT"])]
+    json_args: Vec<String>,
+
+    /// Read every input into one array, or with -R into one string
+    #[arg(short, long)]
+    slurp: bool,
+
+    /// Run the filter once with null as its input, leaving the inputs to
+    /// `input` and `inputs`
+    #[arg(short, long)]
+    null_input: bool,
+
+    /// Read each line of input as a string instead of as JSON
+    #[arg(short = 'R', long)]
+    raw_input: bool,
+
+    /// Sort the keys of every object, both as read and as printed
+    #[arg(short = 'S', long)]
+    sort_keys: bool,
+
+    /// Color the output using `JQ_COLORS` [default: when writing to a
+    /// terminal]
+    #[arg(short = 'C', long, conflicts_with = "monochrome_output")]
+    color_output: bool,
+
+    /// Never color the output
+    #[arg(short = 'M', long)]
+    monochrome_output: bool,
+
+    /// Print each output on a single line
+    #[arg(short = 'c', long, conflicts_with_all = ["indent", "tab"])]
+    compact_output: bool,
+
+    /// Indent nested values by N spaces, from 0 to 7 [default: 2]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u8).range(0..=7))]
+    indent: Option<u8>,
+
+    /// Indent nested values with a tab per level
+    #[arg(long, conflicts_with = "indent")]
+    tab: bool,
+
+    /// Print strings without quotes or escapes
+    #[arg(short, long)]
+    raw_output: bool,
+
+    /// Like -r, without a newline after each output
+    #[arg(short, long)]
+    join_output: bool,
+
+    /// Like -r, with a NUL byte rather than a newline after each output
+    #[arg(long = "raw-output0", conflicts_with = "join_output")]
+    raw_output0: bool,
+
+    /// Escape every non-ASCII character, quoting even raw strings
+    #[arg(short, long)]
+    ascii_output: bool,
+
+    /// Exit with 1 if the last output was false or null, or 4 if there was
+    /// no output
+    #[arg(short, long)]
+    exit_status: bool,
+
+    /// Search DIR for modules named by `import` and `include` [default: .]
+    #[arg(short = 'L', value_name = "DIR")]
+    library_paths: Vec<PathBuf>,
+}
+
+impl Args {
+    fn printer(&self) -> Result<Printer, Failure> {
+        let indent = if self.compact_output {
+            Indent::Compact
+        } else if self.tab {
+            Indent::Tab
+        } else {
+            Indent::Spaces(self.indent.map_or(2, usize::from))
+        };
+        let color = self.color_output || (!self.monochrome_output && io::stdout().is_terminal());
+        Ok(Printer {
+            indent,
+            colors: if color {
+                Some(Colors::from_env()?)
+            } else {
+                None
+            },
+            sort_keys: self.sort_keys,
+            ascii: self.ascii_output,
+            raw: self.raw_output || self.join_output || self.raw_output0,
+        })
+    }
+
+    /// What follows each output
+    fn terminator(&self) -> &'static str {
+        if self.raw_output0 {
+            "\0"
+        } else if self.join_output {
+            ""
+        } else {
+            "\n"
+        }
+    }
+}
+
 fn main() {
-    println!("Hello World!");
+    let args = Args::parse();
+    // Deep recursion in the filter needs more stack than the main thread has
+    let result = thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || jq(&args))
+        .map_err(Failure::from)
+        .and_then(|filter| {
+            filter
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+        });
+    if let Err(failure) = result {
+        match &failure {
+            Failure::Compile(rendered) => eprintln!("{}", rendered),
+            // Runtime errors were reported as they happened, and the exit
+            // statuses of --exit-status are not errors
+            Failure::Runtime | Failure::FalsyOutput | Failure::NoOutput => {}
+            other => eprintln!("jq-rs: error: {}", other),
+        }
+        process::exit(failure.exit_code());
+    }
+}
+
+/// Compile the filter and run it over the inputs
+fn jq(args: &Args) -> Result<(), Failure> {
+    let globals = globals(args)?;
+    let program = parser::parse_program(&args.filter)
+        .map_err(|err| Failure::Compile(err.render(&args.filter)))?;
+
+    let search_paths = if args.library_paths.is_empty() {
+        vec![PathBuf::from(".")]
+    } else {
+        args.library_paths.clone()
+    };
+    let mut loader = Loader::new(search_paths);
+    let deps = loader
+        .load(&program.imports, Path::new("."))
+        .and_then(|deps| loader.check().map(|_| deps))
+        .map_err(|err| Failure::Compile(format!("jq-rs: error: {}", err)))?;
+    let env = loader.bind(globals, &deps);
+    eval::check(&program.body, &env).map_err(|err| Failure::Compile(err.render(&args.filter)))?;
+
+    run(&program.body, &env, args)
+}
+
+/// Variables bound on the command line with `--arg` and `--argjson`
+fn globals(args: &Args) -> Result<Env<'static>, Failure> {
+    let mut env = Env::new();
+    for pair in args.string_args.chunks(2) {
+        env = env.bind(pair[0].as_str(), Value::String(pair[1].clone()));
+    }
+    for pair in args.json_args.chunks(2) {
+        let value = serde_json::from_str(&pair[1]).map_err(|err| {
+            Failure::Usage(format!(
+                "invalid JSON text passed to --argjson {}: {}",
+                pair[0], err
+            ))
+        })?;
+        env = env.bind(pair[0].as_str(), value);
+    }
+    Ok(env)
+}
+
+/// Run the filter over each input value, printing outputs as they are
+/// produced. An error stops the filter for that value only, and makes the
+/// whole run a [`Failure::Runtime`] once every input has been seen.
+fn run(ast: &ast::Ast, globals: &Env, args: &Args) -> Result<(), Failure> {
+    let sources = if args.files.is_empty() {
+        vec![Source::Stdin]
+    } else {
+        args.files.iter().cloned().map(Source::File).collect()
+    };
+    let format = match (args.raw_input, args.slurp) {
+        (false, _) => Format::Json,
+        (true, false) => Format::Lines,
+        (true, true) => Format::Text,
+    };
+    let inputs = Inputs::new(sources, format);
+    if args.slurp {
+        input::provide(iter::once(inputs.slurp()));
+    } else {
+        input::provide(inputs);
+    }
+
+    let mut output = Output {
+        printer: args.printer()?,
+        out: io::stdout().lock(),
+        last_truthy: None,
+        failed: false,
+    };
+    if args.null_input {
+        apply(ast, globals, args, Value::Null, "<unknown>", &mut output)?;
+    } else {
+        while let Some(document) = input::next() {
+            let document = document?;
+            let at = document.location.to_string();
+            apply(ast, globals, args, document.value, &at, &mut output)?;
+        }
+    }
+    output.out.flush()?;
+
+    match output.last_truthy {
+        _ if output.failed => Err(Failure::Runtime),
+        None if args.exit_status => Err(Failure::NoOutput),
+        Some(false) if args.exit_status => Err(Failure::FalsyOutput),
+        _ => Ok(()),
+    }
+}
+
+/// Where outputs are printed, and what has been printed so far across
+/// every input
+struct Output<W> {
+    printer: Printer,
+    out: W,
+    /// Whether the last output was anything but `false` or `null`
+    last_truthy: Option<bool>,
+    /// Whether the filter raised an error for any input
+    failed: bool,
+}
+
+/// Print the outputs of the filter for one input, reporting an error as
+/// coming from the input at `at`
+fn apply(
+    ast: &ast::Ast,
+    globals: &Env,
+    args: &Args,
+    mut input: Value,
+    at: &str,
+    output: &mut Output<impl Write>,
+) -> Result<(), Failure> {
+    // Otherwise objects keep the key order of the source file
+    if args.sort_keys {
+        input = value::sort_keys(input);
+    }
+    for result in eval::eval(ast, globals, input) {
+        let result = result.and_then(|value| match value {
+            Value::String(s) if args.raw_output0 && s.contains('\0') => Err(RuntimeError::Type(
+                "Cannot dump a string containing NUL with --raw-output0 option".to_string(),
+            )),
+            value => Ok(value),
+        });
+        match result {
+            Ok(value) => {
+                output.printer.print(&mut output.out, &value)?;
+                write!(output.out, "{}", args.terminator())?;
+                output.last_truthy = Some(ops::is_truthy(&value));
+            }
+            Err(RuntimeError::Input(err)) => return Err(Failure::from(err)),
+            Err(err) => {
+                eprintln!("jq-rs: error (at {}): {}", at, err);
+                output.failed = true;
+                break;
+            }
+        }
+    }
+    Ok(())
 }
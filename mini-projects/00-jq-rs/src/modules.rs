@@ -0,0 +1,276 @@
+use crate::ast::{Import, Module};
+use crate::env::Env;
+use crate::error::LoadError;
+use crate::eval;
+use crate::parser::parse_module;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The modules a program depends on, each loaded from disk once
+pub struct Loader {
+    search_paths: Vec<PathBuf>,
+    /// Every loaded module, stored after the modules it imports
+    modules: Vec<Loaded>,
+    by_path: HashMap<PathBuf, usize>,
+    /// The chain of modules currently being loaded, for cycle detection
+    loading: Vec<PathBuf>,
+}
+
+struct Loaded {
+    path: PathBuf,
+    source: String,
+    module: Module,
+    deps: Vec<Dependency>,
+}
+
+/// A loaded module along with the alias it was imported under
+pub struct Dependency {
+    alias: Option<String>,
+    index: usize,
+}
+
+impl Loader {
+    /// A loader that looks for modules in `search_paths`, in order
+    pub fn new(search_paths: Vec<PathBuf>) -> Self {
+        Loader {
+            search_paths,
+            modules: Vec::new(),
+            by_path: HashMap::new(),
+            loading: Vec::new(),
+        }
+    }
+
+    /// Load every module named by `imports`, and everything they import in
+    /// turn. Paths starting with `./` or `../` are resolved against `base`.
+    pub fn load(&mut self, imports: &[Import], base: &Path) -> Result<Vec<Dependency>, LoadError> {
+        imports
+            .iter()
+            .map(|import| {
+                Ok(Dependency {
+                    alias: import.alias.clone(),
+                    index: self.load_one(&import.path, base)?,
+                })
+            })
+            .collect()
+    }
+
+    fn load_one(&mut self, name: &str, base: &Path) -> Result<usize, LoadError> {
+        let path = self.resolve(name, base)?;
+        if let Some(start) = self.loading.iter().position(|loading| *loading == path) {
+            let mut chain = self.loading[start..].to_vec();
+            chain.push(path);
+            return Err(LoadError::Cycle(chain));
+        }
+        if let Some(&index) = self.by_path.get(&path) {
+            return Ok(index);
+        }
+
+        let source = fs::read_to_string(&path).map_err(|err| LoadError::Io {
+            path: path.clone(),
+            message: err.to_string(),
+        })?;
+        let module = parse_module(&source, &path.display().to_string()).map_err(|error| {
+            LoadError::Parse {
+                path: path.clone(),
+                source: source.clone(),
+                error: Box::new(error),
+            }
+        })?;
+
+        self.loading.push(path.clone());
+        let dir = path.parent().unwrap_or(Path::new(".")).to_path_buf();
+        let deps = self.load(&module.imports, &dir);
+        self.loading.pop();
+        let deps = deps?;
+
+        let index = self.modules.len();
+        self.modules.push(Loaded {
+            path: path.clone(),
+            source,
+            module,
+            deps,
+        });
+        self.by_path.insert(path, index);
+        Ok(index)
+    }
+
+    /// Find the file for module `name`: `name.jq`, or `name/<last component>.jq`
+    fn resolve(&self, name: &str, base: &Path) -> Result<PathBuf, LoadError> {
+        let dirs = if name.starts_with("./") || name.starts_with("../") {
+            vec![base.to_path_buf()]
+        } else {
+            self.search_paths.clone()
+        };
+        let last = name.rsplit('/').next().unwrap_or(name);
+        for dir in &dirs {
+            for candidate in [
+                dir.join(format!("{}.jq", name)),
+                dir.join(name).join(format!("{}.jq", last)),
+            ] {
+                if candidate.is_file() {
+                    return Ok(fs::canonicalize(&candidate).unwrap_or(candidate));
+                }
+            }
+        }
+        Err(LoadError::NotFound {
+            name: name.to_string(),
+            searched: dirs,
+        })
+    }
+
+    /// Reject calls to undefined functions inside any loaded module
+    pub fn check(&self) -> Result<(), LoadError> {
+        for loaded in &self.modules {
+            let env = self.bind(Env::new(), &loaded.deps);
+            eval::check_module(&loaded.module.defs, &env).map_err(|error| LoadError::Parse {
+                path: loaded.path.clone(),
+                source: loaded.source.clone(),
+                error: Box::new(error),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Extend `env` with the functions of every module in `deps`
+    pub fn bind<'a>(&'a self, env: Env<'a>, deps: &'a [Dependency]) -> Env<'a> {
+        deps.iter().fold(env, |env, dep| {
+            env.import(dep.alias.as_deref(), self.module_env(dep.index))
+        })
+    }
+
+    /// The definitions of a module, each seeing the module's imports and the
+    /// definitions before it
+    fn module_env(&self, index: usize) -> Env<'_> {
+        let loaded = &self.modules[index];
+        loaded
+            .module
+            .defs
+            .iter()
+            .fold(self.bind(Env::new(), &loaded.deps), |env, def| {
+                env.define(def)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::eval;
+    use crate::parser::parse_program;
+    use serde_json::{json, Value};
+
+    /// A fresh directory containing the given module files
+    fn library(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("jq-rs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for (file, source) in files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, source).unwrap();
+        }
+        dir
+    }
+
+    fn run(dir: &Path, filter: &str, input: Value) -> Result<Vec<Value>, String> {
+        let program = parse_program(filter).map_err(|err| err.to_string())?;
+        let mut loader = Loader::new(vec![dir.to_path_buf()]);
+        let deps = loader
+            .load(&program.imports, dir)
+            .and_then(|deps| loader.check().map(|_| deps))
+            .map_err(|err| err.to_string())?;
+        let env = loader.bind(Env::new(), &deps);
+        eval::check(&program.body, &env).map_err(|err| err.to_string())?;
+        eval(&program.body, &env, input)
+            .collect::<Result<_, _>>()
+            .map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn test_import_and_include() {
+        let dir = library(
+            "import",
+            &[
+                (
+                    "util.jq",
+                    "def double: . * 2; def twice(f): f | f;\ndef loc: $__loc__;",
+                ),
+                ("stats/stats.jq", "include \"./helpers\"; def total: sum;"),
+                ("stats/helpers.jq", "def sum: reduce .[] as $x (0; . + $x);"),
+            ],
+        );
+        assert_eq!(
+            run(&dir, "import \"util\" as u; u::twice(u::double)", json!(3)),
+            Ok(vec![json!(12)])
+        );
+        assert_eq!(
+            run(&dir, "include \"util\"; double", json!(3)),
+            Ok(vec![json!(6)])
+        );
+        assert_eq!(
+            run(
+                &dir,
+                "import \"stats\" as s; s::total, s::sum",
+                json!([1, 2])
+            ),
+            Ok(vec![json!(3), json!(3)])
+        );
+        // `$__loc__` names the file it appears in
+        assert_eq!(
+            run(&dir, "include \"util\"; loc, $__loc__", json!(null)),
+            Ok(vec![
+                json!({"file": dir.join("util.jq").display().to_string(), "line": 2}),
+                json!({"file": "<top-level>", "line": 1}),
+            ])
+        );
+        assert_eq!(
+            run(&dir, "import \"util\" as u; double", json!(3)),
+            Err("double/0 is not defined".to_string())
+        );
+    }
+
+    #[test]
+    fn test_modules_are_cached() {
+        let dir = library(
+            "cache",
+            &[
+                ("a.jq", "import \"c\" as c; def a: c::c;"),
+                ("b.jq", "import \"c\" as c; def b: c::c;"),
+                ("c.jq", "def c: 1;"),
+            ],
+        );
+        let program = parse_program("import \"a\" as a; import \"b\" as b; .").unwrap();
+        let mut loader = Loader::new(vec![dir.clone()]);
+        loader.load(&program.imports, &dir).unwrap();
+        assert_eq!(loader.modules.len(), 3);
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let dir = library(
+            "errors",
+            &[
+                ("a.jq", "import \"b\" as b; def a: 1;"),
+                ("b.jq", "import \"a\" as a; def b: 2;"),
+                ("bad.jq", "def f: 1; f"),
+                ("undefined.jq", "def f: g;"),
+            ],
+        );
+        let err = run(&dir, "import \"a\" as a; .", json!(null)).unwrap_err();
+        assert!(err.starts_with("import cycle: "), "{}", err);
+        assert!(err.ends_with("a.jq"), "{}", err);
+        assert!(err.contains("b.jq -> "), "{}", err);
+
+        assert_eq!(
+            run(&dir, "import \"missing\" as m; .", json!(null)),
+            Err(format!(
+                "module not found: \"missing\" (searched {})",
+                dir.display()
+            ))
+        );
+        let err = run(&dir, "include \"bad\"; .", json!(null)).unwrap_err();
+        assert!(err.contains("expected a function definition, found `f`"));
+        let err = run(&dir, "include \"undefined\"; .", json!(null)).unwrap_err();
+        assert!(err.contains("g/0 is not defined"));
+    }
+}
@@ -0,0 +1,385 @@
+use crate::ast::BinaryOp;
+use crate::error::RuntimeError;
+use crate::value::{compare, describe, fits, from_integer, integer, number, to_f64};
+use serde_json::{Map, Number, Value};
+use std::cmp::Ordering;
+
+/// Whether jq treats a value as true: everything except `false` and `null`
+pub fn is_truthy(value: &Value) -> bool {
+    !matches!(value, Value::Null | Value::Bool(false))
+}
+
+/// Apply an arithmetic or comparison operator to two values
+pub fn apply(op: BinaryOp, a: Value, b: Value) -> Result<Value, RuntimeError> {
+    match op {
+        BinaryOp::Add => add(a, b),
+        BinaryOp::Sub => subtract(a, b),
+        BinaryOp::Mul => multiply(a, b),
+        BinaryOp::Div => divide(a, b),
+        BinaryOp::Mod => modulo(a, b),
+        BinaryOp::Eq => Ok(Value::Bool(compare(&a, &b) == Ordering::Equal)),
+        BinaryOp::Ne => Ok(Value::Bool(compare(&a, &b) != Ordering::Equal)),
+        BinaryOp::Lt => Ok(Value::Bool(compare(&a, &b) == Ordering::Less)),
+        BinaryOp::Le => Ok(Value::Bool(compare(&a, &b) != Ordering::Greater)),
+        BinaryOp::Gt => Ok(Value::Bool(compare(&a, &b) == Ordering::Greater)),
+        BinaryOp::Ge => Ok(Value::Bool(compare(&a, &b) != Ordering::Less)),
+        BinaryOp::And | BinaryOp::Or | BinaryOp::Alt => {
+            unreachable!("{:?} short-circuits and is handled by the evaluator", op)
+        }
+        _ => unreachable!(
+            "{:?} assigns through paths and is handled by the evaluator",
+            op
+        ),
+    }
+}
+
+fn type_error(a: &Value, b: &Value, verb: &str) -> RuntimeError {
+    RuntimeError::Type(format!(
+        "{} and {} cannot be {}",
+        describe(a),
+        describe(b),
+        verb
+    ))
+}
+
+/// Combine two numbers. Integers (as written, so `22.0` is not one) within
+/// ±`u64::MAX` are combined exactly, and the result is kept exact if it is
+/// also within that range. Anything else, including an overflowing result,
+/// is computed in f64 like jq does, so it may be rounded.
+fn numeric(
+    x: &Number,
+    y: &Number,
+    int_op: fn(i128, i128) -> Option<i128>,
+    float_op: fn(f64, f64) -> f64,
+) -> Value {
+    if let (Some(x), Some(y)) = (integer(x), integer(y)) {
+        if let Some(result) = int_op(x, y).and_then(from_integer) {
+            return result;
+        }
+    }
+    number(float_op(to_f64(x), to_f64(y)))
+}
+
+/// `a + b`: null is the identity, and numbers, strings, arrays and objects
+/// combine with values of the same type
+pub fn add(a: Value, b: Value) -> Result<Value, RuntimeError> {
+    match (a, b) {
+        (Value::Null, b) => Ok(b),
+        (a, Value::Null) => Ok(a),
+        (Value::Number(x), Value::Number(y)) => {
+            Ok(numeric(&x, &y, i128::checked_add, |x, y| x + y))
+        }
+        (Value::String(x), Value::String(y)) => Ok(Value::String(x + &y)),
+        (Value::Array(mut x), Value::Array(y)) => {
+            x.extend(y);
+            Ok(Value::Array(x))
+        }
+        (Value::Object(mut x), Value::Object(y)) => {
+            x.extend(y);
+            Ok(Value::Object(x))
+        }
+        (a, b) => Err(type_error(&a, &b, "added")),
+    }
+}
+
+/// `a - b`: numeric difference, or `a` without any element that appears in `b`
+fn subtract(a: Value, b: Value) -> Result<Value, RuntimeError> {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => {
+            Ok(numeric(&x, &y, i128::checked_sub, |x, y| x - y))
+        }
+        (Value::Array(x), Value::Array(y)) => Ok(Value::Array(
+            x.into_iter()
+                .filter(|item| {
+                    !y.iter()
+                        .any(|other| compare(item, other) == Ordering::Equal)
+                })
+                .collect(),
+        )),
+        (a, b) => Err(type_error(&a, &b, "subtracted")),
+    }
+}
+
+/// `a * b`: numeric product, string repetition, or a recursive object merge
+fn multiply(a: Value, b: Value) -> Result<Value, RuntimeError> {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => {
+            Ok(numeric(&x, &y, i128::checked_mul, |x, y| x * y))
+        }
+        (Value::String(s), Value::Number(n)) | (Value::Number(n), Value::String(s)) => {
+            let times = to_f64(&n);
+            if times <= 0.0 {
+                return Ok(Value::Null);
+            }
+            let times = (times as usize).max(1);
+            if !fits(s.len(), times) {
+                return Err(RuntimeError::Type(
+                    "Repeat string result too long".to_string(),
+                ));
+            }
+            Ok(Value::String(s.repeat(times)))
+        }
+        (Value::Object(x), Value::Object(y)) => Ok(Value::Object(deep_merge(x, y))),
+        (a, b) => Err(type_error(&a, &b, "multiplied")),
+    }
+}
+
+fn deep_merge(mut base: Map<String, Value>, overlay: Map<String, Value>) -> Map<String, Value> {
+    for (key, value) in overlay {
+        let merged = match (base.remove(&key), value) {
+            (Some(Value::Object(x)), Value::Object(y)) => Value::Object(deep_merge(x, y)),
+            (_, value) => value,
+        };
+        base.insert(key, merged);
+    }
+    base
+}
+
+/// `a / b`: numeric quotient, or `a` split on the separator `b`
+fn divide(a: Value, b: Value) -> Result<Value, RuntimeError> {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => {
+            let divisor = to_f64(&y);
+            if divisor == 0.0 {
+                return Err(zero_divisor(&Value::Number(x), &Value::Number(y)));
+            }
+            Ok(number(to_f64(&x) / divisor))
+        }
+        (Value::String(x), Value::String(y)) => Ok(split(&x, &y)),
+        (a, b) => Err(type_error(&a, &b, "divided")),
+    }
+}
+
+/// `a % b` on the integer parts of two numbers
+fn modulo(a: Value, b: Value) -> Result<Value, RuntimeError> {
+    match (&a, &b) {
+        (Value::Number(x), Value::Number(y)) => {
+            let truncate = |n: &Number| integer(n).unwrap_or(to_f64(n) as i128);
+            let (x, y) = (truncate(x), truncate(y));
+            if y == 0 {
+                return Err(zero_divisor(&a, &b));
+            }
+            let remainder = x.wrapping_rem(y);
+            Ok(from_integer(remainder).unwrap_or_else(|| number(remainder as f64)))
+        }
+        _ => Err(type_error(&a, &b, "divided")),
+    }
+}
+
+fn zero_divisor(a: &Value, b: &Value) -> RuntimeError {
+    RuntimeError::Type(format!(
+        "{} and {} cannot be divided because the divisor is zero",
+        describe(a),
+        describe(b)
+    ))
+}
+
+/// Split `s` on every occurrence of `separator`; an empty string splits into nothing
+pub fn split(s: &str, separator: &str) -> Value {
+    if s.is_empty() {
+        return Value::Array(Vec::new());
+    }
+    if separator.is_empty() {
+        return Value::Array(s.chars().map(|c| Value::String(c.to_string())).collect());
+    }
+    Value::Array(
+        s.split(separator)
+            .map(|part| Value::String(part.to_string()))
+            .collect(),
+    )
+}
+
+/// Unary minus
+pub fn negate(value: Value) -> Result<Value, RuntimeError> {
+    match &value {
+        Value::Number(n) => match integer(n).and_then(|i| from_integer(-i)) {
+            Some(negated) => Ok(negated),
+            None => Ok(number(-to_f64(n))),
+        },
+        _ => Err(RuntimeError::Type(format!(
+            "{} cannot be negated",
+            describe(&value)
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn err(result: Result<Value, RuntimeError>) -> String {
+        result.unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_add() {
+        assert_eq!(add(json!(1), json!(2)).unwrap(), json!(3));
+        assert_eq!(add(json!(1.5), json!(2)).unwrap(), json!(3.5));
+        assert_eq!(add(json!(null), json!("a")).unwrap(), json!("a"));
+        assert_eq!(add(json!("one"), json!("two")).unwrap(), json!("onetwo"));
+        assert_eq!(add(json!([1]), json!([2, 3])).unwrap(), json!([1, 2, 3]));
+        assert_eq!(
+            add(json!({"a": 1, "b": 2}), json!({"b": 3})).unwrap(),
+            json!({"a": 1, "b": 3})
+        );
+        assert_eq!(
+            err(add(json!(1), json!("a"))),
+            "number (1) and string (\"a\") cannot be added"
+        );
+    }
+
+    #[test]
+    fn test_subtract() {
+        assert_eq!(subtract(json!(5), json!(7)).unwrap(), json!(-2));
+        assert_eq!(
+            subtract(json!([1, 2, 3, 2]), json!([2])).unwrap(),
+            json!([1, 3])
+        );
+        assert_eq!(
+            err(subtract(json!({"a": 1}), json!({"a": 1}))),
+            "object ({\"a\":1}) and object ({\"a\":1}) cannot be subtracted"
+        );
+        assert!(subtract(json!("ab"), json!("b")).is_err());
+    }
+
+    #[test]
+    fn test_multiply() {
+        assert_eq!(multiply(json!(4), json!(2.5)).unwrap(), json!(10));
+        assert_eq!(multiply(json!("ab"), json!(3)).unwrap(), json!("ababab"));
+        assert_eq!(multiply(json!(2), json!("x")).unwrap(), json!("xx"));
+        assert_eq!(multiply(json!("ab"), json!(0)).unwrap(), json!(null));
+        // Repetitions too long to allocate fail instead of aborting
+        for times in [json!(1e12), json!(1e19), json!(1e300)] {
+            assert_eq!(
+                err(multiply(json!("ab"), times)),
+                "Repeat string result too long"
+            );
+        }
+        assert_eq!(
+            multiply(
+                json!({"a": {"b": 1, "c": 2}}),
+                json!({"a": {"b": 3}, "d": 4})
+            )
+            .unwrap(),
+            json!({"a": {"b": 3, "c": 2}, "d": 4})
+        );
+        assert!(multiply(json!([1]), json!(2)).is_err());
+    }
+
+    #[test]
+    fn test_divide() {
+        assert_eq!(divide(json!(10), json!(4)).unwrap(), json!(2.5));
+        assert_eq!(divide(json!(10), json!(5)).unwrap(), json!(2));
+        assert_eq!(
+            divide(json!("a,b,c"), json!(",")).unwrap(),
+            json!(["a", "b", "c"])
+        );
+        assert_eq!(
+            err(divide(json!(1), json!(0))),
+            "number (1) and number (0) cannot be divided because the divisor is zero"
+        );
+        assert!(divide(json!([]), json!(1)).is_err());
+    }
+
+    #[test]
+    fn test_modulo() {
+        assert_eq!(modulo(json!(7), json!(3)).unwrap(), json!(1));
+        assert_eq!(modulo(json!(-7), json!(3)).unwrap(), json!(-1));
+        assert_eq!(modulo(json!(7.9), json!(2.1)).unwrap(), json!(1));
+        assert!(modulo(json!(7), json!(0)).is_err());
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let cmp = |op, a, b| apply(op, a, b).unwrap();
+        assert_eq!(cmp(BinaryOp::Eq, json!(1), json!(1.0)), json!(true));
+        assert_eq!(cmp(BinaryOp::Ne, json!("a"), json!("b")), json!(true));
+        assert_eq!(cmp(BinaryOp::Lt, json!(null), json!(false)), json!(true));
+        assert_eq!(cmp(BinaryOp::Le, json!([1]), json!([1])), json!(true));
+        assert_eq!(cmp(BinaryOp::Gt, json!({}), json!([])), json!(true));
+        assert_eq!(cmp(BinaryOp::Ge, json!("a"), json!("b")), json!(false));
+    }
+
+    #[test]
+    fn test_large_integers() {
+        let parse = |text: &str| serde_json::from_str::<Value>(text).unwrap();
+        let max = parse("18446744073709551615");
+        // Exact across the whole signed and unsigned 64-bit range
+        assert_eq!(
+            subtract(max.clone(), json!(1)).unwrap().to_string(),
+            "18446744073709551614"
+        );
+        assert_eq!(
+            add(json!(i64::MAX), json!(1)).unwrap().to_string(),
+            "9223372036854775808"
+        );
+        assert_eq!(
+            negate(json!(i64::MIN)).unwrap().to_string(),
+            "9223372036854775808"
+        );
+        assert_eq!(modulo(max.clone(), json!(10)).unwrap(), json!(5));
+        // The range is symmetric, so going below `i64::MIN` stays exact too
+        assert_eq!(
+            subtract(json!(i64::MIN), json!(1)).unwrap().to_string(),
+            "-9223372036854775809"
+        );
+        let min = negate(max.clone()).unwrap();
+        assert_eq!(
+            add(min.clone(), json!(1)).unwrap().to_string(),
+            "-18446744073709551614"
+        );
+        // Past ±`u64::MAX` the result is computed in f64, and printed in
+        // plain digits like jq does
+        assert_eq!(
+            add(max, json!(1)).unwrap().to_string(),
+            "18446744073709552000"
+        );
+        assert_eq!(
+            subtract(min, json!(1)).unwrap().to_string(),
+            "-18446744073709552000"
+        );
+    }
+
+    #[test]
+    fn test_out_of_range_numbers() {
+        let parse = |text: &str| serde_json::from_str::<Value>(text).unwrap();
+        let max = "1.7976931348623157e+308";
+        // Numbers past f64 saturate instead of turning into 0
+        assert_eq!(
+            apply(BinaryOp::Eq, parse("1e1000"), json!(0)).unwrap(),
+            json!(false)
+        );
+        assert_eq!(
+            apply(BinaryOp::Lt, parse("1e400"), json!(1)).unwrap(),
+            json!(false)
+        );
+        assert_eq!(multiply(parse("1e400"), json!(2)).unwrap().to_string(), max);
+        assert_eq!(
+            negate(parse("1e400")).unwrap().to_string(),
+            format!("-{}", max)
+        );
+        // And so do results that overflow
+        assert_eq!(multiply(json!(1e308), json!(10)).unwrap().to_string(), max);
+        assert_eq!(
+            subtract(json!(-1e308), json!(1e308)).unwrap().to_string(),
+            format!("-{}", max)
+        );
+    }
+
+    #[test]
+    fn test_negate() {
+        assert_eq!(negate(json!(3)).unwrap(), json!(-3));
+        assert_eq!(negate(json!(-1.5)).unwrap(), json!(1.5));
+        assert_eq!(err(negate(json!("a"))), "string (\"a\") cannot be negated");
+    }
+
+    #[test]
+    fn test_truthiness() {
+        assert!(!is_truthy(&json!(null)));
+        assert!(!is_truthy(&json!(false)));
+        assert!(is_truthy(&json!(0)));
+        assert!(is_truthy(&json!("")));
+        assert!(is_truthy(&json!([])));
+    }
+}
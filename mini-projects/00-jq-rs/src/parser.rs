@@ -0,0 +1,1625 @@
+use crate::ast::{
+    Assoc, Ast, AstKind, BinaryOp, FunctionDef, Import, Module, ObjectPattern, Param, Pattern,
+    Program, Span, StringPart,
+};
+use crate::error::{line_col, ParseError, ParseErrorKind};
+use crate::formats;
+use crate::lexer::{tokenize, Segment, Token, TokenKind};
+use serde_json::Value;
+
+/// The file `$__loc__` names in the filter given on the command line
+pub const TOP_LEVEL: &str = "<top-level>";
+
+struct Parser<'a> {
+    source: &'a str,
+    /// Where `source` came from, as reported by `$__loc__`
+    file: &'a str,
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a str, file: &'a str) -> Result<Self, ParseError> {
+        Ok(Parser {
+            source,
+            file,
+            tokens: tokenize(source)?,
+            pos: 0,
+        })
+    }
+
+    /// Fail if anything is left after a complete filter
+    fn finish(&self) -> Result<(), ParseError> {
+        let trailing = self.peek();
+        if trailing.kind != TokenKind::Eof {
+            return Err(ParseError::new(
+                ParseErrorKind::Trailing(describe(&trailing.kind)),
+                trailing.span,
+            ));
+        }
+        Ok(())
+    }
+
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos]
+    }
+
+    fn peek_kind_at(&self, offset: usize) -> &TokenKind {
+        let index = (self.pos + offset).min(self.tokens.len() - 1);
+        &self.tokens[index].kind
+    }
+
+    fn bump(&mut self) -> Token {
+        let token = self.tokens[self.pos].clone();
+        if token.kind != TokenKind::Eof {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn eat(&mut self, kind: &TokenKind) -> Option<Token> {
+        if &self.peek().kind == kind {
+            Some(self.bump())
+        } else {
+            None
+        }
+    }
+
+    fn expect(&mut self, kind: &TokenKind, expected: &str) -> Result<Token, ParseError> {
+        self.eat(kind).ok_or_else(|| {
+            ParseError::new(
+                ParseErrorKind::Expected {
+                    expected: expected.to_string(),
+                    found: describe(&self.peek().kind),
+                },
+                self.peek().span,
+            )
+        })
+    }
+
+    /// Expect the bracket that closes `construct`, which was opened by the token at `open`
+    fn expect_close(
+        &mut self,
+        close: TokenKind,
+        construct: &'static str,
+        open: Span,
+    ) -> Result<Token, ParseError> {
+        self.eat(&close).ok_or_else(|| {
+            ParseError::new(
+                ParseErrorKind::Unclosed {
+                    close: describe(&close),
+                    construct,
+                    opened_column: line_col(self.source, open.start).1,
+                    found: describe(&self.peek().kind),
+                },
+                self.peek().span,
+            )
+        })
+    }
+
+    /// pipe := def pipe | comma ('|' pipe)?
+    fn pipe(&mut self) -> Result<Ast, ParseError> {
+        if self.at_keyword("def") {
+            let (def, start) = self.definition()?;
+            let rest = self.pipe()?;
+            let span = start.to(rest.span);
+            return Ok(Ast::new(
+                AstKind::Def {
+                    def,
+                    rest: Box::new(rest),
+                },
+                span,
+            ));
+        }
+        let lhs = self.comma()?;
+        if self.eat(&TokenKind::Pipe).is_some() {
+            let rhs = self.pipe()?;
+            let span = lhs.span.to(rhs.span);
+            return Ok(Ast::new(
+                AstKind::Pipe {
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                },
+                span,
+            ));
+        }
+        Ok(lhs)
+    }
+
+    /// comma := binary (',' binary)*
+    fn comma(&mut self) -> Result<Ast, ParseError> {
+        let mut lhs = self.binary(0)?;
+        while self.eat(&TokenKind::Comma).is_some() {
+            let rhs = self.binary(0)?;
+            let span = lhs.span.to(rhs.span);
+            lhs = Ast::new(
+                AstKind::Comma {
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                },
+                span,
+            );
+        }
+        Ok(lhs)
+    }
+
+    /// binary := unary (op binary)*, climbing operators whose precedence is at
+    /// least `min_precedence`
+    fn binary(&mut self, min_precedence: u8) -> Result<Ast, ParseError> {
+        let mut lhs = self.unary()?;
+        while let Some(op) = binary_op(&self.peek().kind) {
+            let (precedence, assoc) = op.precedence();
+            if precedence < min_precedence {
+                break;
+            }
+            self.bump();
+            let next_min = match assoc {
+                Assoc::Right => precedence,
+                Assoc::Left | Assoc::None => precedence + 1,
+            };
+            let rhs = self.binary(next_min)?;
+            if assoc == Assoc::None {
+                if let Some(next) = binary_op(&self.peek().kind) {
+                    if next.precedence().0 == precedence {
+                        return Err(ParseError::new(
+                            ParseErrorKind::NonAssociative {
+                                first: op.symbol(),
+                                second: next.symbol(),
+                            },
+                            self.peek().span,
+                        ));
+                    }
+                }
+            }
+            let span = lhs.span.to(rhs.span);
+            lhs = Ast::new(
+                AstKind::Binary {
+                    op,
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                },
+                span,
+            );
+        }
+        Ok(lhs)
+    }
+
+    /// unary := '-' unary | term
+    fn unary(&mut self) -> Result<Ast, ParseError> {
+        let minus = match self.eat(&TokenKind::Minus) {
+            Some(minus) => minus,
+            None => return self.term_or_binding(),
+        };
+        let operand = self.unary()?;
+        let span = minus.span.to(operand.span);
+        // Fold `-1` into a literal so constant indices like `.[-1]` stay simple.
+        // Negating the text rather than the number keeps `-0` and `-1.50`.
+        if let AstKind::Literal(Value::Number(n)) = &operand.kind {
+            let text = n.to_string();
+            let negated = match text.strip_prefix('-') {
+                Some(positive) => positive.to_string(),
+                None => format!("-{}", text),
+            };
+            if let Ok(negated @ Value::Number(_)) = serde_json::from_str(&negated) {
+                return Ok(Ast::new(AstKind::Literal(negated), span));
+            }
+        }
+        Ok(Ast::new(AstKind::Neg(Box::new(operand)), span))
+    }
+
+    /// imports := (('import' STRING 'as' IDENT | 'include' STRING) ';')*
+    fn imports(&mut self) -> Result<Vec<Import>, ParseError> {
+        let mut imports = Vec::new();
+        loop {
+            let is_import = self.at_keyword("import");
+            let start = match self
+                .eat_keyword("import")
+                .or_else(|| self.eat_keyword("include"))
+            {
+                Some(token) => token.span,
+                None => return Ok(imports),
+            };
+            let token = self.bump();
+            let path = match token.kind {
+                TokenKind::Str(path) => path,
+                other => {
+                    return Err(ParseError::new(
+                        ParseErrorKind::Expected {
+                            expected: "a module path".to_string(),
+                            found: describe(&other),
+                        },
+                        token.span,
+                    ))
+                }
+            };
+            let alias = if is_import {
+                self.expect_keyword("as")?;
+                Some(self.name("a module name")?)
+            } else {
+                None
+            };
+            let end = self.expect(&TokenKind::Semicolon, "`;`")?.span;
+            imports.push(Import {
+                path,
+                alias,
+                span: start.to(end),
+            });
+        }
+    }
+
+    /// def := 'def' IDENT ('(' param (';' param)* ')')? ':' pipe ';'
+    fn definition(&mut self) -> Result<(FunctionDef, Span), ParseError> {
+        let start = self.expect_keyword("def")?.span;
+        let name = self.name("a function name")?;
+        let mut params = Vec::new();
+        if let Some(open) = self.eat(&TokenKind::LParen) {
+            loop {
+                let token = self.bump();
+                params.push(match token.kind {
+                    TokenKind::Ident(name) if !is_keyword(&name) => Param::Filter(name),
+                    TokenKind::Variable(name) => Param::Value(name),
+                    other => {
+                        return Err(ParseError::new(
+                            ParseErrorKind::Expected {
+                                expected: "a parameter".to_string(),
+                                found: describe(&other),
+                            },
+                            token.span,
+                        ))
+                    }
+                });
+                if self.eat(&TokenKind::Semicolon).is_none() {
+                    break;
+                }
+            }
+            self.expect_close(TokenKind::RParen, "parameter list", open.span)?;
+        }
+        self.expect(&TokenKind::Colon, "`:`")?;
+        let body = self.pipe()?;
+        let end = self.expect(&TokenKind::Semicolon, "`;`")?.span;
+        let def = FunctionDef {
+            name,
+            params,
+            body: Box::new(body),
+        };
+        Ok((def, start.to(end)))
+    }
+
+    /// An identifier that is not a keyword
+    fn name(&mut self, expected: &str) -> Result<String, ParseError> {
+        match &self.peek().kind {
+            TokenKind::Ident(name) if !is_keyword(name) => {
+                let name = name.clone();
+                self.bump();
+                Ok(name)
+            }
+            other => Err(ParseError::new(
+                ParseErrorKind::Expected {
+                    expected: expected.to_string(),
+                    found: describe(other),
+                },
+                self.peek().span,
+            )),
+        }
+    }
+
+    /// binding := term ('as' pattern '|' pipe)?, where the body extends as far
+    /// right as possible
+    fn term_or_binding(&mut self) -> Result<Ast, ParseError> {
+        let source = self.term()?;
+        if self.eat_keyword("as").is_none() {
+            return Ok(source);
+        }
+        let pattern = self.pattern()?;
+        self.expect(&TokenKind::Pipe, "`|`")?;
+        let body = self.pipe()?;
+        let span = source.span.to(body.span);
+        Ok(Ast::new(
+            AstKind::Bind {
+                source: Box::new(source),
+                pattern,
+                body: Box::new(body),
+            },
+            span,
+        ))
+    }
+
+    /// pattern := VARIABLE | '[' pattern (',' pattern)* ']' | '{' objpat (',' objpat)* '}'
+    fn pattern(&mut self) -> Result<Pattern, ParseError> {
+        let token = self.bump();
+        match token.kind {
+            TokenKind::Variable(name) => Ok(Pattern::Variable(name)),
+            TokenKind::LBracket => {
+                let mut items = Vec::new();
+                loop {
+                    items.push(self.pattern()?);
+                    if self.eat(&TokenKind::Comma).is_none() {
+                        break;
+                    }
+                }
+                self.expect_close(TokenKind::RBracket, "array pattern", token.span)?;
+                Ok(Pattern::Array(items))
+            }
+            TokenKind::LBrace => {
+                let mut entries = Vec::new();
+                loop {
+                    entries.push(self.object_pattern()?);
+                    if self.eat(&TokenKind::Comma).is_none() {
+                        break;
+                    }
+                }
+                self.expect_close(TokenKind::RBrace, "object pattern", token.span)?;
+                Ok(Pattern::Object(entries))
+            }
+            other => Err(ParseError::new(
+                ParseErrorKind::Expected {
+                    expected: "a pattern".to_string(),
+                    found: describe(&other),
+                },
+                token.span,
+            )),
+        }
+    }
+
+    /// objpat := VARIABLE (':' pattern)? | (IDENT | STRING | '(' pipe ')') ':' pattern
+    fn object_pattern(&mut self) -> Result<ObjectPattern, ParseError> {
+        let token = self.bump();
+        let key = match token.kind {
+            TokenKind::Variable(name) => {
+                let key = Ast::new(AstKind::Literal(Value::String(name.clone())), token.span);
+                let pattern = match self.eat(&TokenKind::Colon) {
+                    Some(_) => Some(self.pattern()?),
+                    None => None,
+                };
+                return Ok(ObjectPattern {
+                    key,
+                    variable: Some(name),
+                    pattern,
+                });
+            }
+            TokenKind::Ident(name) | TokenKind::Str(name) => {
+                Ast::new(AstKind::Literal(Value::String(name)), token.span)
+            }
+            TokenKind::LParen => {
+                let mut key = self.pipe()?;
+                let close = self.expect_close(TokenKind::RParen, "parenthesis", token.span)?;
+                key.span = token.span.to(close.span);
+                key
+            }
+            other => {
+                return Err(ParseError::new(
+                    ParseErrorKind::Expected {
+                        expected: "an object pattern key".to_string(),
+                        found: describe(&other),
+                    },
+                    token.span,
+                ))
+            }
+        };
+        self.expect(&TokenKind::Colon, "`:`")?;
+        Ok(ObjectPattern {
+            key,
+            variable: None,
+            pattern: Some(self.pattern()?),
+        })
+    }
+
+    /// term := primary (FIELD | '.' STRING | '[' ... ']' | '.[' ... ']' | '?')*
+    fn term(&mut self) -> Result<Ast, ParseError> {
+        let mut term = self.primary()?;
+        loop {
+            match self.peek().kind.clone() {
+                TokenKind::Field(name) => {
+                    let token = self.bump();
+                    let span = term.span.to(token.span);
+                    term = Ast::new(
+                        AstKind::Field {
+                            target: Box::new(term),
+                            name,
+                        },
+                        span,
+                    );
+                }
+                TokenKind::LBracket => term = self.bracket(term)?,
+                TokenKind::Question => {
+                    let token = self.bump();
+                    let span = term.span.to(token.span);
+                    term = Ast::new(
+                        AstKind::Try {
+                            body: Box::new(term),
+                            catch: None,
+                        },
+                        span,
+                    );
+                }
+                TokenKind::Dot if self.peek_kind_at(1) == &TokenKind::LBracket => {
+                    self.bump();
+                    term = self.bracket(term)?;
+                }
+                TokenKind::Dot if is_string(self.peek_kind_at(1)) => {
+                    self.bump();
+                    term = self.quoted_field(term)?;
+                }
+                _ => return Ok(term),
+            }
+        }
+    }
+
+    fn primary(&mut self) -> Result<Ast, ParseError> {
+        let token = self.bump();
+        match token.kind {
+            TokenKind::Dot if is_string(&self.peek().kind) => {
+                self.quoted_field(Ast::new(AstKind::Identity, token.span))
+            }
+            TokenKind::Dot => Ok(Ast::new(AstKind::Identity, token.span)),
+            // `..` is shorthand for calling `recurse`, as in jq
+            TokenKind::DotDot => Ok(Ast::new(
+                AstKind::FunctionCall {
+                    name: "recurse".to_string(),
+                    args: Vec::new(),
+                },
+                token.span,
+            )),
+            TokenKind::Field(name) => {
+                let dot = Span::new(token.span.start, token.span.start + 1);
+                Ok(Ast::new(
+                    AstKind::Field {
+                        target: Box::new(Ast::new(AstKind::Identity, dot)),
+                        name,
+                    },
+                    token.span,
+                ))
+            }
+            // Literals keep their digits, so `1.50` and integers too large
+            // for f64 print as written, but serde_json spells exponents in
+            // one canonical way: `1e2` and `1E2` both print as `1e+2`
+            TokenKind::Number(text) => {
+                // JSON has no `.5`, only `0.5`
+                let json = match text.strip_prefix('.') {
+                    Some(fraction) => format!("0.{}", fraction),
+                    None => text.clone(),
+                };
+                let value: Value = serde_json::from_str(&json).map_err(|_| {
+                    ParseError::new(ParseErrorKind::InvalidNumber(text.clone()), token.span)
+                })?;
+                Ok(Ast::new(AstKind::Literal(value), token.span))
+            }
+            TokenKind::Str(text) => Ok(Ast::new(AstKind::Literal(Value::String(text)), token.span)),
+            TokenKind::Template(segments) => self.template(None, segments, token.span),
+            TokenKind::Format(name) => self.format(name, token.span),
+            TokenKind::Variable(name) if name == "__loc__" => {
+                let (line, _) = line_col(self.source, token.span.start);
+                let location = serde_json::json!({"file": self.file, "line": line});
+                Ok(Ast::new(AstKind::Literal(location), token.span))
+            }
+            TokenKind::Variable(name) => Ok(Ast::new(AstKind::Variable(name), token.span)),
+            TokenKind::Ident(name) if name == "if" => self.if_then(token.span),
+            TokenKind::Ident(name) if name == "try" => self.try_catch(token.span),
+            TokenKind::Ident(name) if name == "reduce" || name == "foreach" => {
+                self.fold(name == "foreach", token.span)
+            }
+            TokenKind::Ident(name) if is_keyword(&name) => Err(ParseError::new(
+                ParseErrorKind::Expected {
+                    expected: "a filter".to_string(),
+                    found: format!("keyword `{}`", name),
+                },
+                token.span,
+            )),
+            TokenKind::Ident(name) if matches!(name.as_str(), "true" | "false" | "null") => {
+                let value = match name.as_str() {
+                    "true" => Value::Bool(true),
+                    "false" => Value::Bool(false),
+                    _ => Value::Null,
+                };
+                Ok(Ast::new(AstKind::Literal(value), token.span))
+            }
+            TokenKind::Ident(name) => self.call(name, token.span),
+            TokenKind::LBracket => {
+                if let Some(close) = self.eat(&TokenKind::RBracket) {
+                    return Ok(Ast::new(AstKind::Array(None), token.span.to(close.span)));
+                }
+                let body = self.pipe()?;
+                let close = self.expect_close(TokenKind::RBracket, "array", token.span)?;
+                Ok(Ast::new(
+                    AstKind::Array(Some(Box::new(body))),
+                    token.span.to(close.span),
+                ))
+            }
+            TokenKind::LBrace => self.object(token.span),
+            TokenKind::LParen => {
+                let mut inner = self.pipe()?;
+                let close = self.expect_close(TokenKind::RParen, "parenthesis", token.span)?;
+                inner.span = token.span.to(close.span);
+                Ok(inner)
+            }
+            other => Err(ParseError::new(
+                ParseErrorKind::Expected {
+                    expected: "a filter".to_string(),
+                    found: describe(&other),
+                },
+                token.span,
+            )),
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<Token, ParseError> {
+        match &self.peek().kind {
+            TokenKind::Ident(word) if word == keyword => Ok(self.bump()),
+            other => Err(ParseError::new(
+                ParseErrorKind::Expected {
+                    expected: format!("`{}`", keyword),
+                    found: describe(other),
+                },
+                self.peek().span,
+            )),
+        }
+    }
+
+    fn at_keyword(&self, keyword: &str) -> bool {
+        matches!(&self.peek().kind, TokenKind::Ident(word) if word == keyword)
+    }
+
+    /// if := 'if' pipe 'then' pipe ('elif' pipe 'then' pipe)* ('else' pipe)? 'end',
+    /// with the leading `if` or `elif` already consumed
+    fn if_then(&mut self, start: Span) -> Result<Ast, ParseError> {
+        let cond = self.pipe()?;
+        self.expect_keyword("then")?;
+        let then_branch = self.pipe()?;
+        let (else_branch, end) = if let Some(elif) = self.eat_keyword("elif") {
+            let nested = self.if_then(elif.span)?;
+            let end = nested.span;
+            (Some(Box::new(nested)), end)
+        } else {
+            let else_branch = if self.eat_keyword("else").is_some() {
+                Some(Box::new(self.pipe()?))
+            } else {
+                None
+            };
+            (else_branch, self.expect_keyword("end")?.span)
+        };
+        Ok(Ast::new(
+            AstKind::If {
+                cond: Box::new(cond),
+                then_branch: Box::new(then_branch),
+                else_branch,
+            },
+            start.to(end),
+        ))
+    }
+
+    /// The string after `target."`, naming a key that is not a plain identifier.
+    /// An interpolated string becomes an index computed from the input.
+    fn quoted_field(&mut self, target: Ast) -> Result<Ast, ParseError> {
+        let token = self.bump();
+        let span = target.span.to(token.span);
+        let target = Box::new(target);
+        let kind = match token.kind {
+            TokenKind::Str(name) => AstKind::Field { target, name },
+            TokenKind::Template(segments) => AstKind::Index {
+                target,
+                index: Box::new(self.template(None, segments, token.span)?),
+            },
+            _ => unreachable!("quoted_field is only called before a string"),
+        };
+        Ok(Ast::new(kind, span))
+    }
+
+    /// try := 'try' term ('catch' term)?, with the leading `try` already
+    /// consumed. Both the body and the handler are postfix terms, so
+    /// `try .a catch . | f` pipes the result of the whole `try` into `f`.
+    fn try_catch(&mut self, start: Span) -> Result<Ast, ParseError> {
+        let body = Box::new(self.term()?);
+        let mut end = body.span;
+        let catch = if self.eat_keyword("catch").is_some() {
+            let handler = self.term()?;
+            end = handler.span;
+            Some(Box::new(handler))
+        } else {
+            None
+        };
+        Ok(Ast::new(AstKind::Try { body, catch }, start.to(end)))
+    }
+
+    /// fold := ('reduce' | 'foreach') term 'as' pattern '(' pipe ';' pipe (';' pipe)? ')',
+    /// with the leading keyword already consumed; only `foreach` takes the
+    /// third clause
+    fn fold(&mut self, foreach: bool, start: Span) -> Result<Ast, ParseError> {
+        let source = Box::new(self.term()?);
+        self.expect_keyword("as")?;
+        let pattern = self.pattern()?;
+        let open = self.expect(&TokenKind::LParen, "`(`")?;
+        let init = Box::new(self.pipe()?);
+        self.expect(&TokenKind::Semicolon, "`;`")?;
+        let update = Box::new(self.pipe()?);
+        let extract = if foreach && self.eat(&TokenKind::Semicolon).is_some() {
+            Some(Box::new(self.pipe()?))
+        } else {
+            None
+        };
+        let construct = if foreach { "foreach" } else { "reduce" };
+        let close = self.expect_close(TokenKind::RParen, construct, open.span)?;
+        let kind = if foreach {
+            AstKind::Foreach {
+                source,
+                pattern,
+                init,
+                update,
+                extract,
+            }
+        } else {
+            AstKind::Reduce {
+                source,
+                pattern,
+                init,
+                update,
+            }
+        };
+        Ok(Ast::new(kind, start.to(close.span)))
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> Option<Token> {
+        if self.at_keyword(keyword) {
+            Some(self.bump())
+        } else {
+            None
+        }
+    }
+
+    /// call := IDENT ('(' pipe (';' pipe)* ')')?
+    fn call(&mut self, name: String, span: Span) -> Result<Ast, ParseError> {
+        let mut args = Vec::new();
+        let mut end = span;
+        if let Some(open) = self.eat(&TokenKind::LParen) {
+            loop {
+                args.push(self.pipe()?);
+                if self.eat(&TokenKind::Semicolon).is_none() {
+                    break;
+                }
+            }
+            end = self
+                .expect_close(TokenKind::RParen, "argument list", open.span)?
+                .span;
+        }
+        Ok(Ast::new(AstKind::FunctionCall { name, args }, span.to(end)))
+    }
+
+    /// object := '{' (entry (',' entry)*)? '}'
+    fn object(&mut self, open: Span) -> Result<Ast, ParseError> {
+        let mut entries = Vec::new();
+        if self.peek().kind != TokenKind::RBrace {
+            loop {
+                entries.push(self.object_entry()?);
+                if self.eat(&TokenKind::Comma).is_none() {
+                    break;
+                }
+            }
+        }
+        let close = self.expect_close(TokenKind::RBrace, "object", open)?;
+        Ok(Ast::new(AstKind::Object(entries), open.to(close.span)))
+    }
+
+    /// format := '@' IDENT STRING?
+    fn format(&mut self, name: String, span: Span) -> Result<Ast, ParseError> {
+        if !formats::is_format(&name) {
+            return Err(ParseError::new(ParseErrorKind::UnknownFormat(name), span));
+        }
+        let string = self.peek().clone();
+        let segments = match string.kind {
+            TokenKind::Str(text) => vec![Segment::Text(text)],
+            TokenKind::Template(segments) => segments,
+            _ => return Ok(Ast::new(AstKind::Format(name), span)),
+        };
+        self.bump();
+        self.template(Some(name), segments, span.to(string.span))
+    }
+
+    /// Parse the interpolations of a string literal spanning `span`
+    fn template(
+        &self,
+        format: Option<String>,
+        segments: Vec<Segment>,
+        span: Span,
+    ) -> Result<Ast, ParseError> {
+        let parts = segments
+            .into_iter()
+            .map(|segment| match segment {
+                Segment::Text(text) => Ok(StringPart::Literal(text)),
+                Segment::Interpolated(tokens) => {
+                    let mut parser = Parser {
+                        source: self.source,
+                        file: self.file,
+                        tokens,
+                        pos: 0,
+                    };
+                    let filter = parser.pipe()?;
+                    parser.finish()?;
+                    Ok(StringPart::Filter(filter))
+                }
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Ast::new(AstKind::Interpolation { format, parts }, span))
+    }
+
+    /// entry := (IDENT | STRING) (':' objval)? | '(' pipe ')' ':' objval
+    fn object_entry(&mut self) -> Result<(Ast, Ast), ParseError> {
+        let token = self.bump();
+        let (key, shorthand) = match token.kind {
+            // `{$name}` is short for `{name: $name}`
+            TokenKind::Variable(name) => {
+                let key = Ast::new(AstKind::Literal(Value::String(name.clone())), token.span);
+                self.pos -= 1;
+                return Ok((key, self.primary()?));
+            }
+            TokenKind::Ident(name) | TokenKind::Str(name) => (
+                Ast::new(AstKind::Literal(Value::String(name.clone())), token.span),
+                Some(name),
+            ),
+            TokenKind::Template(segments) => (self.template(None, segments, token.span)?, None),
+            TokenKind::LParen => {
+                let mut key = self.pipe()?;
+                let close = self.expect_close(TokenKind::RParen, "parenthesis", token.span)?;
+                key.span = token.span.to(close.span);
+                (key, None)
+            }
+            other => {
+                return Err(ParseError::new(
+                    ParseErrorKind::Expected {
+                        expected: "an object key".to_string(),
+                        found: describe(&other),
+                    },
+                    token.span,
+                ))
+            }
+        };
+
+        if self.eat(&TokenKind::Colon).is_some() {
+            return Ok((key, self.object_value()?));
+        }
+        match shorthand {
+            // `{name}` is short for `{name: .name}`
+            Some(name) => {
+                let value = Ast::new(
+                    AstKind::Field {
+                        target: Box::new(Ast::new(AstKind::Identity, token.span)),
+                        name,
+                    },
+                    token.span,
+                );
+                Ok((key, value))
+            }
+            None => Err(ParseError::new(
+                ParseErrorKind::Expected {
+                    expected: "`:`".to_string(),
+                    found: describe(&self.peek().kind),
+                },
+                self.peek().span,
+            )),
+        }
+    }
+
+    /// objval := unary ('|' objval)?, so commas separate entries rather than values
+    fn object_value(&mut self) -> Result<Ast, ParseError> {
+        let lhs = self.unary()?;
+        if self.eat(&TokenKind::Pipe).is_some() {
+            let rhs = self.object_value()?;
+            let span = lhs.span.to(rhs.span);
+            return Ok(Ast::new(
+                AstKind::Pipe {
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                },
+                span,
+            ));
+        }
+        Ok(lhs)
+    }
+
+    /// bracket := '[' (']' | pipe ']' | pipe? ':' pipe? ']')
+    fn bracket(&mut self, target: Ast) -> Result<Ast, ParseError> {
+        let open = self.expect(&TokenKind::LBracket, "`[`")?.span;
+        let target = Box::new(target);
+
+        if let Some(close) = self.eat(&TokenKind::RBracket) {
+            let span = target.span.to(close.span);
+            return Ok(Ast::new(AstKind::Iterate { target }, span));
+        }
+
+        let start = if self.peek().kind == TokenKind::Colon {
+            None
+        } else {
+            Some(Box::new(self.pipe()?))
+        };
+
+        if self.eat(&TokenKind::Colon).is_some() {
+            let end = if self.peek().kind == TokenKind::RBracket {
+                None
+            } else {
+                Some(Box::new(self.pipe()?))
+            };
+            let close = self.expect_close(TokenKind::RBracket, "slice", open)?;
+            let span = target.span.to(close.span);
+            return Ok(Ast::new(AstKind::Slice { target, start, end }, span));
+        }
+
+        let close = self.expect_close(TokenKind::RBracket, "index", open)?;
+        let span = target.span.to(close.span);
+        // A bare `:` took the slice branch, so `.[]` is the only way to get
+        // here without an index, and the caller handles that first
+        let index = start.ok_or_else(|| {
+            ParseError::new(
+                ParseErrorKind::Expected {
+                    expected: "an index".to_string(),
+                    found: "`]`".to_string(),
+                },
+                close.span,
+            )
+        })?;
+        Ok(Ast::new(AstKind::Index { target, index }, span))
+    }
+}
+
+fn is_string(kind: &TokenKind) -> bool {
+    matches!(kind, TokenKind::Str(_) | TokenKind::Template(_))
+}
+
+/// Words that cannot be used as function names
+fn is_keyword(word: &str) -> bool {
+    matches!(
+        word,
+        "and"
+            | "or"
+            | "if"
+            | "then"
+            | "elif"
+            | "else"
+            | "end"
+            | "as"
+            | "reduce"
+            | "try"
+            | "catch"
+            | "foreach"
+            | "def"
+            | "import"
+            | "include"
+    )
+}
+
+fn binary_op(kind: &TokenKind) -> Option<BinaryOp> {
+    let op = match kind {
+        TokenKind::Alt => BinaryOp::Alt,
+        TokenKind::Assign => BinaryOp::Assign,
+        TokenKind::PipeAssign => BinaryOp::Update,
+        TokenKind::PlusAssign => BinaryOp::AddAssign,
+        TokenKind::MinusAssign => BinaryOp::SubAssign,
+        TokenKind::StarAssign => BinaryOp::MulAssign,
+        TokenKind::SlashAssign => BinaryOp::DivAssign,
+        TokenKind::PercentAssign => BinaryOp::ModAssign,
+        TokenKind::AltAssign => BinaryOp::AltAssign,
+        TokenKind::Ident(word) if word == "or" => BinaryOp::Or,
+        TokenKind::Ident(word) if word == "and" => BinaryOp::And,
+        TokenKind::EqEq => BinaryOp::Eq,
+        TokenKind::NotEq => BinaryOp::Ne,
+        TokenKind::Lt => BinaryOp::Lt,
+        TokenKind::Le => BinaryOp::Le,
+        TokenKind::Gt => BinaryOp::Gt,
+        TokenKind::Ge => BinaryOp::Ge,
+        TokenKind::Plus => BinaryOp::Add,
+        TokenKind::Minus => BinaryOp::Sub,
+        TokenKind::Star => BinaryOp::Mul,
+        TokenKind::Slash => BinaryOp::Div,
+        TokenKind::Percent => BinaryOp::Mod,
+        _ => return None,
+    };
+    Some(op)
+}
+
+fn describe(kind: &TokenKind) -> String {
+    match kind {
+        TokenKind::Dot => "`.`".to_string(),
+        TokenKind::DotDot => "`..`".to_string(),
+        TokenKind::Field(name) => format!("`.{}`", name),
+        TokenKind::Ident(name) => format!("`{}`", name),
+        TokenKind::Variable(name) => format!("`${}`", name),
+        TokenKind::Format(name) => format!("`@{}`", name),
+        TokenKind::Number(text) => format!("`{}`", text),
+        TokenKind::Str(text) => format!("string {:?}", text),
+        TokenKind::Template(_) => "interpolated string".to_string(),
+        TokenKind::LBracket => "`[`".to_string(),
+        TokenKind::RBracket => "`]`".to_string(),
+        TokenKind::LParen => "`(`".to_string(),
+        TokenKind::RParen => "`)`".to_string(),
+        TokenKind::LBrace => "`{`".to_string(),
+        TokenKind::RBrace => "`}`".to_string(),
+        TokenKind::Colon => "`:`".to_string(),
+        TokenKind::Semicolon => "`;`".to_string(),
+        TokenKind::Question => "`?`".to_string(),
+        TokenKind::Comma => "`,`".to_string(),
+        TokenKind::Pipe => "`|`".to_string(),
+        TokenKind::Plus => "`+`".to_string(),
+        TokenKind::Minus => "`-`".to_string(),
+        TokenKind::Star => "`*`".to_string(),
+        TokenKind::Slash => "`/`".to_string(),
+        TokenKind::Percent => "`%`".to_string(),
+        TokenKind::Alt => "`//`".to_string(),
+        TokenKind::Assign => "`=`".to_string(),
+        TokenKind::PipeAssign => "`|=`".to_string(),
+        TokenKind::PlusAssign => "`+=`".to_string(),
+        TokenKind::MinusAssign => "`-=`".to_string(),
+        TokenKind::StarAssign => "`*=`".to_string(),
+        TokenKind::SlashAssign => "`/=`".to_string(),
+        TokenKind::PercentAssign => "`%=`".to_string(),
+        TokenKind::AltAssign => "`//=`".to_string(),
+        TokenKind::EqEq => "`==`".to_string(),
+        TokenKind::NotEq => "`!=`".to_string(),
+        TokenKind::Lt => "`<`".to_string(),
+        TokenKind::Le => "`<=`".to_string(),
+        TokenKind::Gt => "`>`".to_string(),
+        TokenKind::Ge => "`>=`".to_string(),
+        TokenKind::Eof => "end of filter".to_string(),
+    }
+}
+
+/// Parse a filter that may start with `import` and `include` directives
+pub fn parse_program(source: &str) -> Result<Program, ParseError> {
+    let mut parser = Parser::new(source, TOP_LEVEL)?;
+    let imports = parser.imports()?;
+    let body = parser.pipe()?;
+    parser.finish()?;
+    Ok(Program { imports, body })
+}
+
+/// Parse the module at `file`: directives followed by function definitions
+/// only
+pub fn parse_module(source: &str, file: &str) -> Result<Module, ParseError> {
+    let mut parser = Parser::new(source, file)?;
+    let imports = parser.imports()?;
+    let mut defs = Vec::new();
+    while parser.peek().kind != TokenKind::Eof {
+        if !parser.at_keyword("def") {
+            return Err(ParseError::new(
+                ParseErrorKind::Expected {
+                    expected: "a function definition".to_string(),
+                    found: describe(&parser.peek().kind),
+                },
+                parser.peek().span,
+            ));
+        }
+        defs.push(parser.definition()?.0);
+    }
+    Ok(Module { imports, defs })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Parse a complete filter string into an AST
+    fn parse(source: &str) -> Result<Ast, ParseError> {
+        let mut parser = Parser::new(source, TOP_LEVEL)?;
+        let ast = parser.pipe()?;
+        parser.finish()?;
+        Ok(ast)
+    }
+
+    fn identity(start: usize) -> Box<Ast> {
+        Box::new(Ast::new(AstKind::Identity, Span::new(start, start + 1)))
+    }
+
+    #[test]
+    fn test_identity() {
+        assert_eq!(
+            parse(" . ").unwrap(),
+            Ast::new(AstKind::Identity, Span::new(1, 2))
+        );
+    }
+
+    #[test]
+    fn test_recursive_descent() {
+        assert_eq!(
+            parse("..").unwrap(),
+            Ast::new(
+                AstKind::FunctionCall {
+                    name: "recurse".to_string(),
+                    args: Vec::new()
+                },
+                Span::new(0, 2)
+            )
+        );
+        assert!(parse("..[0]").is_ok());
+    }
+
+    #[test]
+    fn test_formats() {
+        assert_eq!(
+            parse("@base64").unwrap(),
+            Ast::new(AstKind::Format("base64".to_string()), Span::new(0, 7))
+        );
+        assert_eq!(
+            parse("@sh \"echo\"").unwrap(),
+            Ast::new(
+                AstKind::Interpolation {
+                    format: Some("sh".to_string()),
+                    parts: vec![StringPart::Literal("echo".to_string())]
+                },
+                Span::new(0, 10)
+            )
+        );
+        let err = parse(".[] | @yaml").unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::UnknownFormat("yaml".to_string()));
+        assert_eq!(err.span, Span::new(6, 11));
+    }
+
+    #[test]
+    fn test_field() {
+        assert_eq!(
+            parse(".fizz").unwrap(),
+            Ast::new(
+                AstKind::Field {
+                    target: identity(0),
+                    name: "fizz".to_string()
+                },
+                Span::new(0, 5)
+            )
+        );
+    }
+
+    #[test]
+    fn test_quoted_field() {
+        assert_eq!(
+            parse(".\"a b\"").unwrap(),
+            Ast::new(
+                AstKind::Field {
+                    target: identity(0),
+                    name: "a b".to_string()
+                },
+                Span::new(0, 6)
+            )
+        );
+        let ast = parse(".x.\"y-z\"").unwrap();
+        assert_eq!(ast.span, Span::new(0, 8));
+        assert!(matches!(ast.kind, AstKind::Field { name, .. } if name == "y-z"));
+        assert!(matches!(
+            parse(".\"\\(1)\"").unwrap().kind,
+            AstKind::Index { .. }
+        ));
+    }
+
+    #[test]
+    fn test_chained_fields() {
+        let ast = parse(".really.annoying").unwrap();
+        assert_eq!(ast.span, Span::new(0, 16));
+        match ast.kind {
+            AstKind::Field { target, name } => {
+                assert_eq!(name, "annoying");
+                assert_eq!(
+                    target.kind,
+                    AstKind::Field {
+                        target: identity(0),
+                        name: "really".to_string()
+                    }
+                );
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_index() {
+        assert_eq!(
+            parse(".[0]").unwrap(),
+            Ast::new(
+                AstKind::Index {
+                    target: identity(0),
+                    index: Box::new(Ast::new(AstKind::Literal(json!(0)), Span::new(2, 3)))
+                },
+                Span::new(0, 4)
+            )
+        );
+        // `.foo.[1]` is accepted as a spelling of `.foo[1]`
+        match parse(".foo.[1]").unwrap().kind {
+            AstKind::Index { target, index } => {
+                assert_eq!(target.span, Span::new(0, 4));
+                assert_eq!(index.kind, AstKind::Literal(json!(1)));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_slice() {
+        let literal = |value, start| {
+            Some(Box::new(Ast::new(
+                AstKind::Literal(json!(value)),
+                Span::new(start, start + 1),
+            )))
+        };
+        assert_eq!(
+            parse(".[0:2]").unwrap().kind,
+            AstKind::Slice {
+                target: identity(0),
+                start: literal(0, 2),
+                end: literal(2, 4)
+            }
+        );
+        assert_eq!(
+            parse(".[:2]").unwrap().kind,
+            AstKind::Slice {
+                target: identity(0),
+                start: None,
+                end: literal(2, 3)
+            }
+        );
+        assert_eq!(
+            parse(".[1:]").unwrap().kind,
+            AstKind::Slice {
+                target: identity(0),
+                start: literal(1, 2),
+                end: None
+            }
+        );
+    }
+
+    #[test]
+    fn test_iterate() {
+        assert_eq!(
+            parse(".[]").unwrap(),
+            Ast::new(
+                AstKind::Iterate {
+                    target: identity(0)
+                },
+                Span::new(0, 3)
+            )
+        );
+    }
+
+    #[test]
+    fn test_pipe_is_right_associative() {
+        let ast = parse(".[] | .name | length").unwrap();
+        assert_eq!(ast.span, Span::new(0, 20));
+        match ast.kind {
+            AstKind::Pipe { lhs, rhs } => {
+                assert_eq!(
+                    lhs.kind,
+                    AstKind::Iterate {
+                        target: identity(0)
+                    }
+                );
+                assert!(matches!(rhs.kind, AstKind::Pipe { .. }));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_function_call() {
+        assert_eq!(
+            parse("length").unwrap(),
+            Ast::new(
+                AstKind::FunctionCall {
+                    name: "length".to_string(),
+                    args: vec![]
+                },
+                Span::new(0, 6)
+            )
+        );
+        let ast = parse("del(.fizzes)").unwrap();
+        assert_eq!(ast.span, Span::new(0, 12));
+        match ast.kind {
+            AstKind::FunctionCall { name, args } => {
+                assert_eq!(name, "del");
+                assert_eq!(args.len(), 1);
+                assert_eq!(args[0].span, Span::new(4, 11));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parentheses() {
+        let ast = parse("(.a | .b)[0]").unwrap();
+        match ast.kind {
+            AstKind::Index { target, .. } => {
+                assert_eq!(target.span, Span::new(0, 9));
+                assert!(matches!(target.kind, AstKind::Pipe { .. }));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_comma_binds_tighter_than_pipe() {
+        match parse(".a, .b | .c").unwrap().kind {
+            AstKind::Pipe { lhs, .. } => assert!(matches!(lhs.kind, AstKind::Comma { .. })),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_array_construction() {
+        assert_eq!(
+            parse("[]").unwrap(),
+            Ast::new(AstKind::Array(None), Span::new(0, 2))
+        );
+        match parse("[.[] | .name]").unwrap().kind {
+            AstKind::Array(Some(body)) => assert!(matches!(body.kind, AstKind::Pipe { .. })),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_object_construction() {
+        let ast = parse(r#"{name, "pos": .position, (.k): .v | length}"#).unwrap();
+        let entries = match ast.kind {
+            AstKind::Object(entries) => entries,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].0.kind, AstKind::Literal(json!("name")));
+        assert_eq!(
+            entries[0].1.kind,
+            AstKind::Field {
+                target: Box::new(Ast::new(AstKind::Identity, Span::new(1, 5))),
+                name: "name".to_string()
+            }
+        );
+        assert_eq!(entries[1].0.kind, AstKind::Literal(json!("pos")));
+        assert!(matches!(entries[2].0.kind, AstKind::Field { .. }));
+        assert!(matches!(entries[2].1.kind, AstKind::Pipe { .. }));
+    }
+
+    #[test]
+    fn test_object_errors() {
+        assert_eq!(
+            parse("{a: 1").unwrap_err().to_string(),
+            "expected `}` to close object opened at column 1, found end of filter"
+        );
+        assert_eq!(
+            parse("{(.a)}").unwrap_err().to_string(),
+            "expected `:`, found `}`"
+        );
+        assert_eq!(
+            parse("{1: 2}").unwrap_err().to_string(),
+            "expected an object key, found `1`"
+        );
+    }
+
+    /// Render an expression with explicit parentheses to check grouping
+    fn grouping(ast: &Ast) -> String {
+        match &ast.kind {
+            AstKind::Binary { op, lhs, rhs } => {
+                format!("({} {} {})", grouping(lhs), op.symbol(), grouping(rhs))
+            }
+            AstKind::Neg(operand) => format!("(-{})", grouping(operand)),
+            AstKind::Pipe { lhs, rhs } => format!("({} | {})", grouping(lhs), grouping(rhs)),
+            AstKind::Try { body, catch: None } => format!("{}?", grouping(body)),
+            AstKind::Try {
+                body,
+                catch: Some(handler),
+            } => format!("(try {} catch {})", grouping(body), grouping(handler)),
+            AstKind::Literal(value) => value.to_string(),
+            AstKind::Field { name, .. } => format!(".{}", name),
+            AstKind::Identity => ".".to_string(),
+            AstKind::FunctionCall { name, .. } => name.clone(),
+            other => format!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_arithmetic_precedence() {
+        assert_eq!(grouping(&parse("1 + 2 * 3").unwrap()), "(1 + (2 * 3))");
+        assert_eq!(grouping(&parse("1 - 2 - 3").unwrap()), "((1 - 2) - 3)");
+        assert_eq!(grouping(&parse("8 / 4 % 3").unwrap()), "((8 / 4) % 3)");
+        assert_eq!(grouping(&parse("(1 + 2) * 3").unwrap()), "((1 + 2) * 3)");
+    }
+
+    #[test]
+    fn test_boolean_precedence() {
+        assert_eq!(
+            grouping(&parse(".a < 1 or .b == 2 and .c").unwrap()),
+            "((.a < 1) or ((.b == 2) and .c))"
+        );
+        assert_eq!(
+            grouping(&parse(".a // .b // 3 + 1").unwrap()),
+            "(.a // (.b // (3 + 1)))"
+        );
+        assert_eq!(
+            grouping(&parse(".a or .b // .c").unwrap()),
+            "((.a or .b) // .c)"
+        );
+    }
+
+    #[test]
+    fn test_assignment_precedence() {
+        assert_eq!(grouping(&parse(".a |= . + 1").unwrap()), "(.a |= (. + 1))");
+        assert_eq!(
+            grouping(&parse(".a = .b or .c // 1").unwrap()),
+            "((.a = (.b or .c)) // 1)"
+        );
+        assert_eq!(
+            parse(".a = .b |= 1").unwrap_err().to_string(),
+            "`=` cannot be chained with `|=`; add parentheses"
+        );
+    }
+
+    #[test]
+    fn test_try_precedence() {
+        assert_eq!(
+            grouping(&parse("try .a catch . | length").unwrap()),
+            "((try .a catch .) | length)"
+        );
+        assert_eq!(grouping(&parse("try .a + 1").unwrap()), "(.a? + 1)");
+        assert_eq!(grouping(&parse(".a? // 1").unwrap()), "(.a? // 1)");
+        assert_eq!(
+            parse("try").unwrap_err().to_string(),
+            "expected a filter, found end of filter"
+        );
+        assert_eq!(
+            parse("catch").unwrap_err().to_string(),
+            "expected a filter, found keyword `catch`"
+        );
+    }
+
+    #[test]
+    fn test_json_keywords() {
+        assert_eq!(parse("true").unwrap().kind, AstKind::Literal(json!(true)));
+        assert_eq!(parse("false").unwrap().kind, AstKind::Literal(json!(false)));
+        assert_eq!(parse("null").unwrap().kind, AstKind::Literal(json!(null)));
+    }
+
+    #[test]
+    fn test_unary_minus() {
+        assert_eq!(
+            parse("-1").unwrap(),
+            Ast::new(AstKind::Literal(json!(-1)), Span::new(0, 2))
+        );
+        assert_eq!(grouping(&parse("-.a * 2").unwrap()), "((-.a) * 2)");
+        assert_eq!(grouping(&parse("3 - -2").unwrap()), "(3 - -2)");
+    }
+
+    #[test]
+    fn test_leading_dot_numbers() {
+        assert_eq!(
+            parse(".1").unwrap(),
+            Ast::new(AstKind::Literal(json!(0.1)), Span::new(0, 2))
+        );
+        assert_eq!(parse("-.25").unwrap().kind, AstKind::Literal(json!(-0.25)));
+        assert_eq!(grouping(&parse("1 - .5").unwrap()), "(1 - 0.5)");
+        // Only a digit right after the dot makes a number
+        assert_eq!(grouping(&parse(". * .a").unwrap()), "(. * .a)");
+    }
+
+    #[test]
+    fn test_operator_errors() {
+        assert_eq!(
+            parse("1 == 2 == 3").unwrap_err().to_string(),
+            "`==` cannot be chained with `==`; add parentheses"
+        );
+        assert!(parse("1 < 2 >= 3").is_err());
+        assert!(parse("(1 < 2) >= 3").is_ok());
+        assert_eq!(
+            parse("and").unwrap_err().to_string(),
+            "expected a filter, found keyword `and`"
+        );
+        assert!(parse("1 +").is_err());
+    }
+
+    #[test]
+    fn test_if() {
+        let ast = parse("if .a then 1 elif .b then 2 else 3 end").unwrap();
+        assert_eq!(ast.span, Span::new(0, 38));
+        let (cond, else_branch) = match ast.kind {
+            AstKind::If {
+                cond, else_branch, ..
+            } => (cond, else_branch.unwrap()),
+            other => panic!("unexpected {:?}", other),
+        };
+        assert!(matches!(cond.kind, AstKind::Field { .. }));
+        match else_branch.kind {
+            AstKind::If { else_branch, .. } => {
+                assert_eq!(else_branch.unwrap().kind, AstKind::Literal(json!(3)))
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        match parse("if . then 1 end").unwrap().kind {
+            AstKind::If { else_branch, .. } => assert!(else_branch.is_none()),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_if_errors() {
+        assert_eq!(
+            parse("if . then 1").unwrap_err().to_string(),
+            "expected `end`, found end of filter"
+        );
+        assert_eq!(
+            parse("if . 1 end").unwrap_err().to_string(),
+            "expected `then`, found `1`"
+        );
+        assert!(parse("then").is_err());
+    }
+
+    #[test]
+    fn test_bindings() {
+        let ast = parse(". as $x | $x + 1, 2").unwrap();
+        let (source, pattern, body) = match ast.kind {
+            AstKind::Bind {
+                source,
+                pattern,
+                body,
+            } => (source, pattern, body),
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(source.kind, AstKind::Identity);
+        assert_eq!(pattern, Pattern::Variable("x".to_string()));
+        // The body swallows everything to its right, including the comma
+        assert!(matches!(body.kind, AstKind::Comma { .. }));
+
+        // A binding can appear as an operand and still extends rightwards
+        assert!(matches!(
+            parse("1 + . as $x | $x").unwrap().kind,
+            AstKind::Binary { .. }
+        ));
+    }
+
+    #[test]
+    fn test_program_imports() {
+        let program =
+            parse_program("import \"lib/util\" as util; include \"common\"; util::f").unwrap();
+        assert_eq!(
+            program.imports,
+            vec![
+                Import {
+                    path: "lib/util".to_string(),
+                    alias: Some("util".to_string()),
+                    span: Span::new(0, 26),
+                },
+                Import {
+                    path: "common".to_string(),
+                    alias: None,
+                    span: Span::new(27, 44),
+                },
+            ]
+        );
+        assert!(
+            matches!(program.body.kind, AstKind::FunctionCall { ref name, .. } if name == "util::f")
+        );
+        assert_eq!(
+            parse_program("import \"lib\";").unwrap_err().to_string(),
+            "expected `as`, found `;`"
+        );
+        assert_eq!(
+            parse_program("import lib as lib; .")
+                .unwrap_err()
+                .to_string(),
+            "expected a module path, found `lib`"
+        );
+        // Directives only make sense at the very start
+        assert!(parse_program(". | import \"lib\" as lib; .").is_err());
+    }
+
+    #[test]
+    fn test_module() {
+        let module = parse_module("include \"base\";\ndef a: 1;\ndef b(f): f;\n", "m.jq").unwrap();
+        assert_eq!(module.imports.len(), 1);
+        assert_eq!(
+            module
+                .defs
+                .iter()
+                .map(|def| def.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["a", "b"]
+        );
+        assert_eq!(
+            parse_module("def a: 1; a", "m.jq").unwrap_err().to_string(),
+            "expected a function definition, found `a`"
+        );
+    }
+
+    #[test]
+    fn test_definitions() {
+        let ast = parse("def f(g; $x): g + $x; def h: 1; f(.; h)").unwrap();
+        let (def, rest) = match ast.kind {
+            AstKind::Def { def, rest } => (def, rest),
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(def.name, "f");
+        assert_eq!(
+            def.params,
+            vec![
+                Param::Filter("g".to_string()),
+                Param::Value("x".to_string())
+            ]
+        );
+        assert!(matches!(rest.kind, AstKind::Def { .. }));
+
+        // Definitions may appear at the start of any pipe
+        assert!(parse(".[] | def double: . * 2; double").is_ok());
+        assert!(parse("[def one: 1; one]").is_ok());
+        assert_eq!(
+            parse("def f: 1").unwrap_err().to_string(),
+            "expected `;`, found end of filter"
+        );
+        assert_eq!(
+            parse("def if: 1; 2").unwrap_err().to_string(),
+            "expected a function name, found `if`"
+        );
+        assert_eq!(
+            parse("def f(1): 1; 2").unwrap_err().to_string(),
+            "expected a parameter, found `1`"
+        );
+    }
+
+    #[test]
+    fn test_reduce_and_foreach() {
+        match parse("reduce .[] as $x (0; . + $x)").unwrap().kind {
+            AstKind::Reduce { source, init, .. } => {
+                assert!(matches!(source.kind, AstKind::Iterate { .. }));
+                assert_eq!(init.kind, AstKind::Literal(json!(0)));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        match parse("foreach .[] as [$a, $b] (0; . + $a; [$b, .])")
+            .unwrap()
+            .kind
+        {
+            AstKind::Foreach { extract, .. } => assert!(extract.is_some()),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(
+            parse("reduce .[] as $x (0; . + $x; .)")
+                .unwrap_err()
+                .to_string(),
+            "expected `)` to close reduce opened at column 18, found `;`"
+        );
+        assert_eq!(
+            parse("reduce .[] (0; 1)").unwrap_err().to_string(),
+            "expected `as`, found `(`"
+        );
+    }
+
+    #[test]
+    fn test_destructuring_patterns() {
+        let ast = parse(". as {name: $n, scores: [$first], $id, \"a b\": $ab} | $n").unwrap();
+        let pattern = match ast.kind {
+            AstKind::Bind { pattern, .. } => pattern,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(pattern.variables(), vec!["n", "first", "id", "ab"]);
+        assert!(parse(". as [$a, {b: $c}] | $a").is_ok());
+        assert!(parse(". as {$a: [$b]} | $b").is_ok());
+        assert_eq!(
+            parse(". as $x").unwrap_err().to_string(),
+            "expected `|`, found end of filter"
+        );
+        assert_eq!(
+            parse(". as x | .").unwrap_err().to_string(),
+            "expected a pattern, found `x`"
+        );
+    }
+
+    #[test]
+    fn test_loc() {
+        assert_eq!(
+            parse("1,\n$__loc__").unwrap().kind,
+            AstKind::Comma {
+                lhs: Box::new(Ast::new(AstKind::Literal(json!(1)), Span::new(0, 1))),
+                rhs: Box::new(Ast::new(
+                    AstKind::Literal(json!({"file": "<top-level>", "line": 2})),
+                    Span::new(3, 11)
+                )),
+            }
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(parse(".[1").unwrap_err().span, Span::new(3, 3));
+        assert_eq!(parse(".a ]").unwrap_err().span, Span::new(3, 4));
+        assert_eq!(parse("").unwrap_err().span, Span::new(0, 0));
+        assert_eq!(parse(". |").unwrap_err().span, Span::new(3, 3));
+        assert!(parse("del(.a").is_err());
+    }
+}
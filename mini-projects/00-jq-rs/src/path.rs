@@ -0,0 +1,637 @@
+use crate::ast::{Ast, AstKind, BinaryOp};
+use crate::builtins;
+use crate::env::{Env, Function};
+use crate::error::RuntimeError;
+use crate::eval::{bind, call, catching, destructure, eval, with_params, Stream, ValueStream};
+use crate::ops;
+use crate::value;
+use serde_json::{Map, Value};
+use std::cmp::Ordering;
+use std::iter;
+use std::mem;
+use std::rc::Rc;
+
+/// A path into a value together with the value found there
+pub type PathValue = (Vec<Value>, Value);
+
+/// The lazily produced outputs of a filter evaluated as a path expression
+pub type PathStream<'a> = Stream<'a, PathValue>;
+
+/// A path stream with exactly one item
+pub fn once<'a>(result: Result<PathValue, RuntimeError>) -> PathStream<'a> {
+    Box::new(iter::once(result))
+}
+
+/// Feed every successful output of a path stream into `f`, passing errors through
+pub fn bind_paths<'a, F>(stream: PathStream<'a>, mut f: F) -> PathStream<'a>
+where
+    F: FnMut(PathValue) -> PathStream<'a> + 'a,
+{
+    Box::new(stream.flat_map(move |result| match result {
+        Ok(path_value) => f(path_value),
+        Err(err) => once(Err(err)),
+    }))
+}
+
+/// Feed every successful output of a value stream into a path-producing `f`
+pub fn bind_values<'a, F>(stream: ValueStream<'a>, mut f: F) -> PathStream<'a>
+where
+    F: FnMut(Value) -> PathStream<'a> + 'a,
+{
+    Box::new(stream.flat_map(move |result| match result {
+        Ok(value) => f(value),
+        Err(err) => once(Err(err)),
+    }))
+}
+
+fn push(path: &[Value], key: Value) -> Vec<Value> {
+    let mut path = path.to_vec();
+    path.push(key);
+    path
+}
+
+/// Evaluate `ast` as a path expression starting from `current`, which sits at
+/// `path` within the document, yielding every path the filter selects
+pub fn eval_paths<'a>(ast: &'a Ast, env: &Env<'a>, (path, current): PathValue) -> PathStream<'a> {
+    match &ast.kind {
+        AstKind::Identity => once(Ok((path, current))),
+        AstKind::Field { target, name } => {
+            let key = Value::String(name.clone());
+            bind_paths(
+                eval_paths(target, env, (path, current)),
+                move |(path, value)| {
+                    let child = value::index(&value, &key);
+                    once(child.map(|child| (push(&path, key.clone()), child)))
+                },
+            )
+        }
+        AstKind::Index { target, index } => {
+            let env = env.clone();
+            bind_values(eval(index, &env, current.clone()), move |key| {
+                bind_paths(
+                    eval_paths(target, &env, (path.clone(), current.clone())),
+                    move |(path, value)| {
+                        let child = value::index(&value, &key);
+                        once(child.map(|child| (push(&path, key.clone()), child)))
+                    },
+                )
+            })
+        }
+        AstKind::Slice { target, start, end } => {
+            let env = env.clone();
+            let bound = |bound: &'a Option<Box<Ast>>, env: &Env<'a>, input: Value| match bound {
+                Some(bound) => eval(bound, env, input),
+                None => crate::eval::once(Ok(Value::Null)),
+            };
+            let end_input = current.clone();
+            bind_values(bound(start, &env, current.clone()), move |start| {
+                let env = env.clone();
+                let path = path.clone();
+                let current = current.clone();
+                bind_values(bound(end, &env, end_input.clone()), move |end| {
+                    let start = start.clone();
+                    bind_paths(
+                        eval_paths(target, &env, (path.clone(), current.clone())),
+                        move |(path, value)| {
+                            let child = value::slice(&value, &start, &end);
+                            let key = value::slice_key(start.clone(), end.clone());
+                            once(child.map(|child| (push(&path, key), child)))
+                        },
+                    )
+                })
+            })
+        }
+        AstKind::Iterate { target } => bind_paths(
+            eval_paths(target, env, (path, current)),
+            |(path, value)| match value {
+                Value::Array(items) => Box::new(
+                    items
+                        .into_iter()
+                        .enumerate()
+                        .map(move |(i, item)| Ok((push(&path, Value::from(i)), item))),
+                ),
+                Value::Object(entries) => Box::new(
+                    entries
+                        .into_iter()
+                        .map(move |(key, item)| Ok((push(&path, Value::String(key)), item))),
+                ),
+                other => once(Err(RuntimeError::CannotIterate(value::describe(&other)))),
+            },
+        ),
+        AstKind::Pipe { lhs, rhs } => {
+            let env = env.clone();
+            bind_paths(eval_paths(lhs, &env, (path, current)), move |pv| {
+                eval_paths(rhs, &env, pv)
+            })
+        }
+        AstKind::Comma { lhs, rhs } => Box::new(
+            eval_paths(lhs, env, (path.clone(), current.clone())).chain(eval_paths(
+                rhs,
+                env,
+                (path, current),
+            )),
+        ),
+        AstKind::If {
+            cond,
+            then_branch,
+            else_branch,
+        } => {
+            let env = env.clone();
+            bind_values(eval(cond, &env, current.clone()), move |cond| {
+                let path_value = (path.clone(), current.clone());
+                if ops::is_truthy(&cond) {
+                    eval_paths(then_branch, &env, path_value)
+                } else {
+                    match else_branch {
+                        Some(else_branch) => eval_paths(else_branch, &env, path_value),
+                        None => once(Ok(path_value)),
+                    }
+                }
+            })
+        }
+        // Paths selected before an error are kept, but a handler's outputs are
+        // values rather than paths
+        AstKind::Try { body, catch } => {
+            let env = env.clone();
+            catching(
+                eval_paths(body, &env, (path, current)),
+                move |err| match catch {
+                    Some(handler) => invalid_path(handler, &env, err.value()),
+                    None => Box::new(iter::empty()),
+                },
+            )
+        }
+        AstKind::Bind {
+            source,
+            pattern,
+            body,
+        } => {
+            let env = env.clone();
+            bind_values(eval(source, &env, current.clone()), move |value| {
+                let path = path.clone();
+                let current = current.clone();
+                match destructure(pattern, &env, &current, value) {
+                    Ok(envs) => Box::new(envs.into_iter().flat_map(move |env| {
+                        eval_paths(body, &env, (path.clone(), current.clone()))
+                    })),
+                    Err(err) => once(Err(err)),
+                }
+            })
+        }
+        AstKind::Def { def, rest } => eval_paths(rest, &env.define(def), (path, current)),
+        AstKind::FunctionCall { name, args } => match env.function(name, args.len()) {
+            Some(Function::Closure(body, closure_env)) => {
+                eval_paths(body, &closure_env, (path, current))
+            }
+            Some(Function::Defined(def, def_env)) => {
+                let body_current = current.clone();
+                let run = Rc::new(move |env: Env<'a>| {
+                    eval_paths(&def.body, &env, (path.clone(), body_current.clone()))
+                });
+                call(|| with_params(&def.params, args, env.clone(), def_env, current, run))
+            }
+            None => match builtins::call_paths(name, args, env, (path, current.clone())) {
+                Some(paths) => paths,
+                None => invalid_path(ast, env, current),
+            },
+        },
+        AstKind::Literal(_)
+        | AstKind::Format(_)
+        | AstKind::Interpolation { .. }
+        | AstKind::Variable(_)
+        | AstKind::Array(_)
+        | AstKind::Object(_)
+        | AstKind::Binary { .. }
+        | AstKind::Neg(_)
+        | AstKind::Reduce { .. }
+        | AstKind::Foreach { .. } => invalid_path(ast, env, current),
+    }
+}
+
+/// Evaluate a filter that cannot produce paths, failing on its first output
+fn invalid_path<'a>(ast: &'a Ast, env: &Env<'a>, current: Value) -> PathStream<'a> {
+    bind_values(eval(ast, env, current), |result| {
+        once(Err(RuntimeError::Type(format!(
+            "Invalid path expression with result {}",
+            value::describe(&result)
+        ))))
+    })
+}
+
+/// Every path `ast` selects within `input`
+pub fn paths<'a>(
+    ast: &'a Ast,
+    env: &Env<'a>,
+    input: Value,
+) -> Result<Vec<Vec<Value>>, RuntimeError> {
+    eval_paths(ast, env, (Vec::new(), input))
+        .map(|result| result.map(|(path, _)| path))
+        .collect()
+}
+
+/// Evaluate an assignment operator: `lhs` selects the paths to change within
+/// `input`, and `rhs`, also run on `input`, supplies the new values
+pub fn assign<'a>(
+    op: BinaryOp,
+    lhs: &'a Ast,
+    rhs: &'a Ast,
+    env: &Env<'a>,
+    input: Value,
+) -> ValueStream<'a> {
+    if op == BinaryOp::Update {
+        return crate::eval::once(update(lhs, rhs, env, input));
+    }
+    let env = env.clone();
+    bind(eval(rhs, &env, input.clone()), move |new| {
+        let assigned = paths(lhs, &env, input.clone()).and_then(|paths| {
+            paths.iter().try_fold(input.clone(), |mut value, path| {
+                let updated = match op.updating() {
+                    None => new.clone(),
+                    Some(BinaryOp::Alt) => {
+                        let old = get_path(&value, path)?;
+                        if ops::is_truthy(&old) {
+                            old
+                        } else {
+                            new.clone()
+                        }
+                    }
+                    Some(op) => ops::apply(op, get_path(&value, path)?, new.clone())?,
+                };
+                set_path(&mut value, path, updated)?;
+                Ok(value)
+            })
+        });
+        crate::eval::once(assigned)
+    })
+}
+
+/// `lhs |= f`: replace each selected value with the first output of `f` run
+/// on it, deleting the paths for which `f` produces nothing
+fn update<'a>(
+    lhs: &'a Ast,
+    f: &'a Ast,
+    env: &Env<'a>,
+    input: Value,
+) -> Result<Value, RuntimeError> {
+    let mut value = input.clone();
+    let mut deleted = Vec::new();
+    for path in paths(lhs, env, input)? {
+        let old = get_path(&value, &path)?;
+        match eval(f, env, old).next() {
+            Some(new) => set_path(&mut value, &path, new?)?,
+            None => deleted.push(path),
+        }
+    }
+    delete_paths(value, deleted)
+}
+
+/// The value at `path` within `value`, or null where the path runs past the
+/// end of the document
+pub fn get_path(value: &Value, path: &[Value]) -> Result<Value, RuntimeError> {
+    let (key, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return Ok(value.clone()),
+    };
+    let child = match (value, key) {
+        (Value::Null, _) => return Ok(Value::Null),
+        (_, Value::Object(bounds)) => {
+            let (start, end) = slice_key_bounds(bounds);
+            value::slice(value, start, end)?
+        }
+        _ => value::index(value, key)?,
+    };
+    get_path(&child, rest)
+}
+
+/// Replace the value at `path` within `value` with `new`, creating objects
+/// and arrays (padded with nulls) where the path leads through null
+pub fn set_path(value: &mut Value, path: &[Value], new: Value) -> Result<(), RuntimeError> {
+    let (key, rest) = match path.split_first() {
+        Some(split) => split,
+        None => {
+            *value = new;
+            return Ok(());
+        }
+    };
+    if value.is_null() {
+        match key {
+            Value::String(_) => *value = Value::Object(Map::new()),
+            Value::Number(_) | Value::Object(_) => *value = Value::Array(Vec::new()),
+            _ => return Err(value::cannot_index(value, key)),
+        }
+    }
+
+    match (&mut *value, key) {
+        (Value::Object(map), Value::String(k)) => {
+            set_path(map.entry(k.clone()).or_insert(Value::Null), rest, new)
+        }
+        (Value::Array(items), Value::Number(n)) => {
+            let mut i = value::to_f64(n).floor();
+            if i < 0.0 {
+                i += items.len() as f64;
+                if i < 0.0 {
+                    return Err(RuntimeError::IndexOutOfRange(
+                        "Out of bounds negative array index".to_string(),
+                    ));
+                }
+            }
+            let i = i as usize;
+            if i >= items.len() {
+                // Padding out to a huge index would exhaust memory
+                if !value::fits(i.saturating_add(1), mem::size_of::<Value>()) {
+                    return Err(RuntimeError::IndexOutOfRange(
+                        "Array index too large".to_string(),
+                    ));
+                }
+                items.resize(i + 1, Value::Null);
+            }
+            set_path(&mut items[i], rest, new)
+        }
+        (Value::Array(items), Value::Object(bounds)) => {
+            let (start, end) = slice_key_bounds(bounds);
+            let (start, end) = value::slice_bounds(items.len(), start, end)?;
+            let mut slice = Value::Array(items[start..end].to_vec());
+            set_path(&mut slice, rest, new)?;
+            match slice {
+                Value::Array(replacement) => {
+                    items.splice(start..end, replacement);
+                    Ok(())
+                }
+                _ => Err(RuntimeError::Type(
+                    "A slice of an array can only be assigned another array".to_string(),
+                )),
+            }
+        }
+        _ => Err(value::cannot_index(value, key)),
+    }
+}
+
+/// The `start` and `end` of a slice path component
+fn slice_key_bounds(key: &Map<String, Value>) -> (&Value, &Value) {
+    (
+        key.get("start").unwrap_or(&Value::Null),
+        key.get("end").unwrap_or(&Value::Null),
+    )
+}
+
+/// Remove every path from `value`. Paths are deleted from last to first so
+/// that removing an array element does not shift the indices of the others.
+pub fn delete_paths(mut value: Value, mut paths: Vec<Vec<Value>>) -> Result<Value, RuntimeError> {
+    paths.sort_by(|a, b| compare_paths(a, b));
+    paths.dedup();
+    for path in paths.iter().rev() {
+        delete_path(&mut value, path)?;
+    }
+    Ok(value)
+}
+
+fn compare_paths(a: &[Value], b: &[Value]) -> Ordering {
+    value::compare(&Value::Array(a.to_vec()), &Value::Array(b.to_vec()))
+}
+
+fn delete_path(value: &mut Value, path: &[Value]) -> Result<(), RuntimeError> {
+    let (key, rest) = match path.split_first() {
+        Some(split) => split,
+        None => {
+            *value = Value::Null;
+            return Ok(());
+        }
+    };
+
+    if !rest.is_empty() {
+        let child = match (&mut *value, key) {
+            (Value::Null, _) => return Ok(()),
+            (Value::Object(map), Value::String(k)) => map.get_mut(k),
+            (Value::Array(items), Value::Number(n)) => {
+                value::array_index(items.len(), n).and_then(|i| items.get_mut(i))
+            }
+            _ => return Err(value::cannot_index(value, key)),
+        };
+        return match child {
+            Some(child) => delete_path(child, rest),
+            None => Ok(()),
+        };
+    }
+
+    match (&mut *value, key) {
+        (Value::Null, _) => {}
+        (Value::Object(map), Value::String(k)) => {
+            map.shift_remove(k);
+        }
+        (Value::Array(items), Value::Number(n)) => {
+            if let Some(i) = value::array_index(items.len(), n) {
+                items.remove(i);
+            }
+        }
+        (Value::Array(items), Value::Object(bounds)) => {
+            let (start, end) = slice_key_bounds(bounds);
+            let (start, end) = value::slice_bounds(items.len(), start, end)?;
+            items.drain(start..end);
+        }
+        _ => return Err(value::cannot_index(value, key)),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_program;
+    use serde_json::json;
+
+    fn paths_of(filter: &str, input: Value) -> Vec<Vec<Value>> {
+        paths(&parse_program(filter).unwrap().body, &Env::new(), input).unwrap()
+    }
+
+    #[test]
+    fn test_paths() {
+        assert_eq!(paths_of(".", json!(1)), vec![Vec::<Value>::new()]);
+        assert_eq!(
+            paths_of(".a.b", json!(null)),
+            vec![vec![json!("a"), json!("b")]]
+        );
+        assert_eq!(
+            paths_of(".[]", json!({"x": 1, "y": 2})),
+            vec![vec![json!("x")], vec![json!("y")]]
+        );
+        assert_eq!(
+            paths_of(".a | .[1:]", json!({"a": [1, 2]})),
+            vec![vec![json!("a"), json!({"start": 1, "end": null})]]
+        );
+    }
+
+    #[test]
+    fn test_comma_paths() {
+        assert_eq!(
+            paths_of(".a, .b[0]", json!({})),
+            vec![vec![json!("a")], vec![json!("b"), json!(0)]]
+        );
+    }
+
+    #[test]
+    fn test_conditional_paths() {
+        assert_eq!(
+            paths_of(".[] | select(. > 1)", json!([1, 2, 3])),
+            vec![vec![json!(1)], vec![json!(2)]]
+        );
+        assert_eq!(
+            paths_of("if .a then .b else .c end", json!({"a": false})),
+            vec![vec![json!("c")]]
+        );
+        assert_eq!(paths_of("empty", json!(1)), Vec::<Vec<Value>>::new());
+    }
+
+    #[test]
+    fn test_function_paths() {
+        assert_eq!(
+            paths_of(
+                "def items(f): .items[] | f; items(.id)",
+                json!({"items": [{}]})
+            ),
+            vec![vec![json!("items"), json!(0), json!("id")]]
+        );
+        assert_eq!(
+            paths_of("def at($k): .[$k]; at(\"a\", \"b\")", json!({})),
+            vec![vec![json!("a")], vec![json!("b")]]
+        );
+    }
+
+    #[test]
+    fn test_optional_paths() {
+        assert_eq!(
+            paths_of(".[] | .a?", json!([{"a": 1}, 2, {}])),
+            vec![vec![json!(0), json!("a")], vec![json!(2), json!("a")]]
+        );
+        assert_eq!(
+            paths_of("(.a, .[0], .b)?", json!({})),
+            vec![vec![json!("a")]]
+        );
+        let err = paths(
+            &parse_program("try .[0] catch .").unwrap().body,
+            &Env::new(),
+            json!({}),
+        );
+        assert_eq!(
+            err.unwrap_err().to_string(),
+            "Invalid path expression with result string (\"Cannot in...)"
+        );
+    }
+
+    #[test]
+    fn test_negative_index_paths() {
+        let mut value = json!([1, 2, 3]);
+        set_path(&mut value, &[json!(-1)], json!(9)).unwrap();
+        assert_eq!(value, json!([1, 2, 9]));
+        assert_eq!(get_path(&value, &[json!(-2)]).unwrap(), json!(2));
+        assert_eq!(
+            delete_paths(value, vec![vec![json!(-1)], vec![json!(0)]]).unwrap(),
+            json!([2])
+        );
+    }
+
+    #[test]
+    fn test_invalid_path() {
+        let err = paths(
+            &parse_program("length").unwrap().body,
+            &Env::new(),
+            json!([1]),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid path expression with result number (1)"
+        );
+    }
+
+    #[test]
+    fn test_set_path() {
+        let mut value = json!({"a": 1, "b": [1, 2, 3]});
+        set_path(&mut value, &[json!("b"), json!(-1)], json!(0)).unwrap();
+        set_path(&mut value, &[json!("c"), json!(1), json!("d")], json!(true)).unwrap();
+        set_path(&mut value, &[json!("a")], json!(2)).unwrap();
+        assert_eq!(
+            serde_json::to_string(&value).unwrap(),
+            r#"{"a":2,"b":[1,2,0],"c":[null,{"d":true}]}"#
+        );
+
+        let mut items = json!([1, 2, 3, 4]);
+        let slice = [json!({"start": 1, "end": 3})];
+        set_path(&mut items, &slice, json!(["x"])).unwrap();
+        assert_eq!(items, json!([1, "x", 4]));
+        assert_eq!(
+            set_path(&mut items, &slice, json!(1))
+                .unwrap_err()
+                .to_string(),
+            "A slice of an array can only be assigned another array"
+        );
+        assert_eq!(
+            set_path(&mut items, &[json!(-5)], json!(1))
+                .unwrap_err()
+                .to_string(),
+            "Out of bounds negative array index"
+        );
+        // Indices that would need more padding than can be allocated fail
+        for index in [json!(100000000), json!(1e19)] {
+            let mut value = json!(null);
+            assert_eq!(
+                set_path(&mut value, &[index], json!(1))
+                    .unwrap_err()
+                    .to_string(),
+                "Array index too large"
+            );
+        }
+        let mut value = json!(null);
+        set_path(&mut value, &[json!(1000)], json!(1)).unwrap();
+        assert_eq!(value.as_array().map(Vec::len), Some(1001));
+        assert!(set_path(&mut items, &[json!("a")], json!(1)).is_err());
+    }
+
+    #[test]
+    fn test_get_path() {
+        let value = json!({"a": [1, 2, 3]});
+        assert_eq!(get_path(&value, &[]).unwrap(), value);
+        assert_eq!(get_path(&value, &[json!("a"), json!(1)]).unwrap(), json!(2));
+        assert_eq!(
+            get_path(&value, &[json!("a"), json!({"start": 1, "end": null})]).unwrap(),
+            json!([2, 3])
+        );
+        assert_eq!(
+            get_path(&value, &[json!("b"), json!("c")]).unwrap(),
+            json!(null)
+        );
+    }
+
+    #[test]
+    fn test_delete_object_key_keeps_order() {
+        let input = json!({"fizz": "buzz", "baz": null, "fizzes": [1], "biz": 42});
+        let deleted = delete_paths(input, vec![vec![json!("fizzes")]]).unwrap();
+        assert_eq!(
+            serde_json::to_string(&deleted).unwrap(),
+            r#"{"fizz":"buzz","baz":null,"biz":42}"#
+        );
+    }
+
+    #[test]
+    fn test_delete_array_elements() {
+        let input = json!([0, 1, 2, 3, 4]);
+        let deleted = delete_paths(
+            input.clone(),
+            vec![vec![json!(1)], vec![json!(3)], vec![json!(10)]],
+        )
+        .unwrap();
+        assert_eq!(deleted, json!([0, 2, 4]));
+        let deleted = delete_paths(input, vec![vec![json!({"start": 1, "end": 3})]]).unwrap();
+        assert_eq!(deleted, json!([0, 3, 4]));
+    }
+
+    #[test]
+    fn test_delete_nested_and_root() {
+        let input = json!({"a": {"b": 1, "c": 2}});
+        assert_eq!(
+            delete_paths(input.clone(), vec![vec![json!("a"), json!("b")]]).unwrap(),
+            json!({"a": {"c": 2}})
+        );
+        assert_eq!(delete_paths(input, vec![vec![]]).unwrap(), json!(null));
+        assert!(delete_paths(json!([1]), vec![vec![json!("a")]]).is_err());
+    }
+}
@@ -0,0 +1,377 @@
+use crate::error::ColorError;
+use serde_json::Value;
+use std::env;
+use std::io::{self, Write};
+
+/// The colors used for whatever `JQ_COLORS` leaves out, in its order
+const DEFAULT_COLORS: [&str; 8] = [
+    "0;90", "0;37", "0;37", "0;37", "0;32", "1;37", "1;37", "1;34",
+];
+
+/// What each entry of `JQ_COLORS` colors, in its order
+const KINDS: [&str; 8] = [
+    "null",
+    "false",
+    "true",
+    "numbers",
+    "strings",
+    "arrays",
+    "objects",
+    "object keys",
+];
+
+/// The longest entry jq accepts in `JQ_COLORS`
+const MAX_ENTRY_LEN: usize = 12;
+
+/// Kinds of text that each have their own color
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Null,
+    False,
+    True,
+    Number,
+    String,
+    /// Brackets and commas of an array
+    Array,
+    /// Braces, commas and colons of an object
+    Object,
+    Key,
+}
+
+/// The SGR parameters, such as `1;34`, to color each kind of text with
+#[derive(Debug, Clone, PartialEq)]
+pub struct Colors([String; 8]);
+
+impl Default for Colors {
+    fn default() -> Self {
+        Colors(DEFAULT_COLORS.map(String::from))
+    }
+}
+
+impl Colors {
+    /// Colors from a `JQ_COLORS` value of colon-separated entries. Entries
+    /// left off the end keep their defaults.
+    pub fn parse(spec: &str) -> Result<Colors, ColorError> {
+        let mut colors = Colors::default();
+        if spec.is_empty() {
+            return Ok(colors);
+        }
+        let entries: Vec<&str> = spec.strip_suffix(':').unwrap_or(spec).split(':').collect();
+        if entries.len() > KINDS.len() {
+            return Err(ColorError::TooManyEntries(entries.len()));
+        }
+        for ((i, entry), color) in entries.into_iter().enumerate().zip(&mut colors.0) {
+            if let Some(found) = entry.chars().find(|c| !c.is_ascii_digit() && *c != ';') {
+                return Err(ColorError::InvalidChar {
+                    entry: i + 1,
+                    kind: KINDS[i],
+                    text: entry.to_string(),
+                    found,
+                });
+            }
+            if entry.len() > MAX_ENTRY_LEN {
+                return Err(ColorError::TooLong {
+                    entry: i + 1,
+                    kind: KINDS[i],
+                    text: entry.to_string(),
+                    max: MAX_ENTRY_LEN,
+                });
+            }
+            *color = entry.to_string();
+        }
+        Ok(colors)
+    }
+
+    /// Colors from the `JQ_COLORS` environment variable, or the defaults
+    /// when it is not set
+    pub fn from_env() -> Result<Colors, ColorError> {
+        match env::var_os("JQ_COLORS") {
+            Some(spec) => Colors::parse(&spec.to_string_lossy()),
+            None => Ok(Colors::default()),
+        }
+    }
+}
+
+/// How nested values are laid out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Indent {
+    /// Everything on one line, with no spaces
+    Compact,
+    /// One element per line, indented this many spaces per level. Zero
+    /// spaces is the same as compact.
+    Spaces(usize),
+    /// One element per line, indented a tab per level
+    Tab,
+}
+
+/// Writes values as JSON text
+#[derive(Debug, Clone, PartialEq)]
+pub struct Printer {
+    pub indent: Indent,
+    /// How to color each kind of text, or `None` for monochrome
+    pub colors: Option<Colors>,
+    /// Print the keys of every object in order
+    pub sort_keys: bool,
+    /// Escape every character outside ASCII
+    pub ascii: bool,
+    /// Write a string on its own as its contents rather than as JSON
+    pub raw: bool,
+}
+
+impl Default for Printer {
+    fn default() -> Self {
+        Printer {
+            indent: Indent::Spaces(2),
+            colors: None,
+            sort_keys: false,
+            ascii: false,
+            raw: false,
+        }
+    }
+}
+
+impl Printer {
+    /// Write `value` with no trailing newline
+    pub fn print(&self, out: &mut impl Write, value: &Value) -> io::Result<()> {
+        match value {
+            // As in jq, --ascii-output still quotes raw strings so that the
+            // escapes can be told apart from the text
+            Value::String(s) if self.raw && !self.ascii => out.write_all(s.as_bytes()),
+            _ => self.value(out, value, 0),
+        }
+    }
+
+    fn value(&self, out: &mut impl Write, value: &Value, depth: usize) -> io::Result<()> {
+        match value {
+            Value::Null => self.paint(out, Kind::Null, "null"),
+            Value::Bool(false) => self.paint(out, Kind::False, "false"),
+            Value::Bool(true) => self.paint(out, Kind::True, "true"),
+            Value::Number(n) => self.paint(out, Kind::Number, &n.to_string()),
+            Value::String(s) => self.paint(out, Kind::String, &self.escape(s)),
+            Value::Array(items) if items.is_empty() => self.paint(out, Kind::Array, "[]"),
+            Value::Array(items) => {
+                self.paint(out, Kind::Array, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        self.paint(out, Kind::Array, ",")?;
+                    }
+                    self.newline(out, depth + 1)?;
+                    self.value(out, item, depth + 1)?;
+                }
+                self.newline(out, depth)?;
+                self.paint(out, Kind::Array, "]")
+            }
+            Value::Object(map) if map.is_empty() => self.paint(out, Kind::Object, "{}"),
+            Value::Object(map) => {
+                let mut entries: Vec<_> = map.iter().collect();
+                if self.sort_keys {
+                    entries.sort_by_key(|(key, _)| *key);
+                }
+                self.paint(out, Kind::Object, "{")?;
+                for (i, (key, item)) in entries.into_iter().enumerate() {
+                    if i > 0 {
+                        self.paint(out, Kind::Object, ",")?;
+                    }
+                    self.newline(out, depth + 1)?;
+                    self.paint(out, Kind::Key, &self.escape(key))?;
+                    self.paint(out, Kind::Object, ":")?;
+                    if !self.compact() {
+                        write!(out, " ")?;
+                    }
+                    self.value(out, item, depth + 1)?;
+                }
+                self.newline(out, depth)?;
+                self.paint(out, Kind::Object, "}")
+            }
+        }
+    }
+
+    /// Write `text` in the color for `kind`, if printing in color
+    fn paint(&self, out: &mut impl Write, kind: Kind, text: &str) -> io::Result<()> {
+        match &self.colors {
+            Some(Colors(colors)) => write!(out, "\x1b[{}m{}\x1b[0m", colors[kind as usize], text),
+            None => out.write_all(text.as_bytes()),
+        }
+    }
+
+    fn compact(&self) -> bool {
+        matches!(self.indent, Indent::Compact | Indent::Spaces(0))
+    }
+
+    /// Start a new line indented to `depth`, unless printing compactly
+    fn newline(&self, out: &mut impl Write, depth: usize) -> io::Result<()> {
+        match self.indent {
+            Indent::Compact | Indent::Spaces(0) => Ok(()),
+            Indent::Spaces(width) => write!(out, "\n{:1$}", "", width * depth),
+            Indent::Tab => write!(out, "\n{}", "\t".repeat(depth)),
+        }
+    }
+
+    /// `s` as a quoted JSON string
+    fn escape(&self, s: &str) -> String {
+        let mut escaped = String::with_capacity(s.len() + 2);
+        escaped.push('"');
+        for c in s.chars() {
+            match c {
+                '"' => escaped.push_str("\\\""),
+                '\\' => escaped.push_str("\\\\"),
+                '\n' => escaped.push_str("\\n"),
+                '\t' => escaped.push_str("\\t"),
+                '\r' => escaped.push_str("\\r"),
+                '\u{8}' => escaped.push_str("\\b"),
+                '\u{c}' => escaped.push_str("\\f"),
+                c if c < ' ' || c == '\u{7f}' || (self.ascii && !c.is_ascii()) => {
+                    for unit in c.encode_utf16(&mut [0; 2]) {
+                        escaped.push_str(&format!("\\u{:04x}", unit));
+                    }
+                }
+                c => escaped.push(c),
+            }
+        }
+        escaped.push('"');
+        escaped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn print(printer: Printer, value: Value) -> String {
+        let mut out = Vec::new();
+        printer.print(&mut out, &value).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_indentation() {
+        let value = json!({"a": [1, {"b": null}], "c": {}, "d": []});
+        assert_eq!(
+            print(Printer::default(), value.clone()),
+            serde_json::to_string_pretty(&value).unwrap()
+        );
+        let with = |indent| Printer {
+            indent,
+            ..Printer::default()
+        };
+        assert_eq!(
+            print(with(Indent::Compact), value.clone()),
+            "{\"a\":[1,{\"b\":null}],\"c\":{},\"d\":[]}"
+        );
+        assert_eq!(
+            print(with(Indent::Spaces(0)), value.clone()),
+            print(with(Indent::Compact), value.clone())
+        );
+        assert_eq!(
+            print(with(Indent::Tab), json!({"a": [1]})),
+            "{\n\t\"a\": [\n\t\t1\n\t]\n}"
+        );
+        assert_eq!(
+            print(with(Indent::Spaces(4)), json!([true])),
+            "[\n    true\n]"
+        );
+    }
+
+    #[test]
+    fn test_strings() {
+        let text = json!("tab\t \"quoted\" \u{1} \u{7f} é 😀");
+        assert_eq!(
+            print(Printer::default(), text.clone()),
+            "\"tab\\t \\\"quoted\\\" \\u0001 \\u007f é 😀\""
+        );
+        let ascii = Printer {
+            ascii: true,
+            ..Printer::default()
+        };
+        assert_eq!(
+            print(ascii, json!({"é": "😀"})),
+            "{\n  \"\\u00e9\": \"\\ud83d\\ude00\"\n}"
+        );
+        let raw = Printer {
+            raw: true,
+            ..Printer::default()
+        };
+        assert_eq!(
+            print(raw.clone(), text.clone()),
+            "tab\t \"quoted\" \u{1} \u{7f} é 😀"
+        );
+        assert_eq!(print(raw.clone(), json!(["a"])), "[\n  \"a\"\n]");
+        assert_eq!(
+            print(Printer { ascii: true, ..raw }, json!("é")),
+            "\"\\u00e9\""
+        );
+    }
+
+    #[test]
+    fn test_sort_keys() {
+        let sorted = Printer {
+            indent: Indent::Compact,
+            sort_keys: true,
+            ..Printer::default()
+        };
+        assert_eq!(
+            print(
+                sorted,
+                json!({"b": [{"z": 1, "a": 2}], "a": {"d": 3, "c": 4}})
+            ),
+            "{\"a\":{\"c\":4,\"d\":3},\"b\":[{\"a\":2,\"z\":1}]}"
+        );
+    }
+
+    #[test]
+    fn test_colors() {
+        let colored = |colors: Colors, value| {
+            print(
+                Printer {
+                    indent: Indent::Compact,
+                    colors: Some(colors),
+                    ..Printer::default()
+                },
+                value,
+            )
+        };
+        assert_eq!(
+            colored(Colors::default(), json!({"a": [null, true, 1, "s"]})),
+            "\x1b[1;37m{\x1b[0m\x1b[1;34m\"a\"\x1b[0m\x1b[1;37m:\x1b[0m\
+             \x1b[1;37m[\x1b[0m\x1b[0;90mnull\x1b[0m\x1b[1;37m,\x1b[0m\
+             \x1b[0;37mtrue\x1b[0m\x1b[1;37m,\x1b[0m\x1b[0;37m1\x1b[0m\
+             \x1b[1;37m,\x1b[0m\x1b[0;32m\"s\"\x1b[0m\x1b[1;37m]\x1b[0m\
+             \x1b[1;37m}\x1b[0m"
+        );
+        // Entries left off the end keep their defaults
+        let colors = Colors::parse("1;31:4").unwrap();
+        assert_eq!(
+            colored(colors.clone(), json!(null)),
+            "\x1b[1;31mnull\x1b[0m"
+        );
+        assert_eq!(colored(colors.clone(), json!(false)), "\x1b[4mfalse\x1b[0m");
+        assert_eq!(colored(colors, json!(true)), "\x1b[0;37mtrue\x1b[0m");
+        assert_eq!(Colors::parse("").unwrap(), Colors::default());
+        assert_eq!(
+            Colors::parse("0;90:0;37:0;37:0;37:0;32:1;37:1;37:1;34:").unwrap(),
+            Colors::default()
+        );
+    }
+
+    #[test]
+    fn test_invalid_colors() {
+        let error = |spec| Colors::parse(spec).unwrap_err().to_string();
+        assert_eq!(
+            error("1:2:3:4:5:6:7:8:9"),
+            "JQ_COLORS has 9 entries, but only 8 kinds of value can be colored"
+        );
+        assert_eq!(
+            error("0;90:0;3x"),
+            "JQ_COLORS entry 2 (for false) is \"0;3x\", but 'x' is not a digit or `;`"
+        );
+        assert_eq!(
+            error("::::::: 1"),
+            "JQ_COLORS entry 8 (for object keys) is \" 1\", but ' ' is not a digit or `;`"
+        );
+        assert_eq!(
+            error("1;2;3;4;5;6;7"),
+            "JQ_COLORS entry 1 (for null) is \"1;2;3;4;5;6;7\", which is longer than 12 characters"
+        );
+    }
+}
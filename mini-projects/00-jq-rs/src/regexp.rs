@@ -0,0 +1,295 @@
+use crate::error::RuntimeError;
+use crate::value::describe;
+use regex::{Captures, Regex, RegexBuilder};
+use serde_json::{Map, Value};
+
+/// A compiled regex along with the jq flags that control how it is applied
+pub struct Matcher {
+    regex: Regex,
+    /// `g`: find every match rather than just the first
+    global: bool,
+    /// `n`: ignore matches of the empty string
+    skip_empty: bool,
+}
+
+/// One match of a regex, with its byte range in the input and the match
+/// object jq's `match` reports for it
+pub struct Found {
+    pub start: usize,
+    pub end: usize,
+    pub object: Value,
+}
+
+/// The string a regex builtin runs on
+pub fn subject(input: &Value) -> Result<&str, RuntimeError> {
+    match input {
+        Value::String(s) => Ok(s),
+        _ => Err(RuntimeError::Type(format!(
+            "{} cannot be matched, as it is not a string",
+            describe(input)
+        ))),
+    }
+}
+
+impl Matcher {
+    /// Compile `re` with a string of jq `flags`, or null for none. Like jq,
+    /// `re` may also be an array holding the regex and its flags.
+    pub fn new(re: &Value, flags: &Value) -> Result<Matcher, RuntimeError> {
+        let (re, flags) = match re {
+            Value::Array(items) if flags.is_null() => (
+                items.first().unwrap_or(&Value::Null),
+                items.get(1).unwrap_or(&Value::Null),
+            ),
+            _ => (re, flags),
+        };
+        let re = match re {
+            Value::String(re) => re,
+            other => {
+                return Err(RuntimeError::Type(format!(
+                    "{} cannot be matched, as it is not a string",
+                    describe(other)
+                )))
+            }
+        };
+        let flags = match flags {
+            Value::Null => "",
+            Value::String(flags) => flags,
+            other => {
+                return Err(RuntimeError::Type(format!(
+                    "{} is not a string",
+                    describe(other)
+                )))
+            }
+        };
+
+        let mut builder = RegexBuilder::new(re);
+        let (mut global, mut skip_empty) = (false, false);
+        for flag in flags.chars() {
+            match flag {
+                'g' => global = true,
+                'n' => skip_empty = true,
+                'i' => {
+                    builder.case_insensitive(true);
+                }
+                'x' => {
+                    builder.ignore_whitespace(true);
+                }
+                'p' => {
+                    builder.dot_matches_new_line(true);
+                }
+                // Single-line and longest-match modes need no special handling
+                's' | 'l' => {}
+                _ => {
+                    return Err(RuntimeError::Type(format!(
+                        "{} is not a valid modifier string",
+                        flags
+                    )))
+                }
+            }
+        }
+        let regex = builder.build().map_err(|err| {
+            // The regex crate explains syntax errors over several lines,
+            // ending with the summary
+            let err = err.to_string();
+            let summary = err.lines().last().unwrap_or_default();
+            let summary = summary.strip_prefix("error: ").unwrap_or(summary);
+            RuntimeError::Type(format!("{:?} is not a valid regex: {}", re, summary))
+        })?;
+        Ok(Matcher {
+            regex,
+            global,
+            skip_empty,
+        })
+    }
+
+    /// The same regex, finding every match
+    pub fn global(self) -> Matcher {
+        Matcher {
+            global: true,
+            ..self
+        }
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        self.regex.is_match(text)
+    }
+
+    /// The matches in `text`: only the first unless the `g` flag was given
+    pub fn find(&self, text: &str) -> Vec<Found> {
+        let limit = if self.global { usize::MAX } else { 1 };
+        self.regex
+            .captures_iter(text)
+            .filter_map(|captures| {
+                let whole = captures.get(0)?;
+                if self.skip_empty && whole.is_empty() {
+                    return None;
+                }
+                Some(Found {
+                    start: whole.start(),
+                    end: whole.end(),
+                    object: self.match_object(text, &captures),
+                })
+            })
+            .take(limit)
+            .collect()
+    }
+
+    /// `{"offset", "length", "string", "captures"}`, with offsets and lengths
+    /// counted in codepoints
+    fn match_object(&self, text: &str, captures: &Captures) -> Value {
+        let group = |index: usize, name: Option<&str>| {
+            let mut object = Map::new();
+            match captures.get(index) {
+                Some(m) => {
+                    let offset = text[..m.start()].chars().count();
+                    object.insert("offset".to_string(), Value::from(offset));
+                    object.insert(
+                        "length".to_string(),
+                        Value::from(m.as_str().chars().count()),
+                    );
+                    object.insert("string".to_string(), Value::from(m.as_str()));
+                }
+                None => {
+                    object.insert("offset".to_string(), Value::from(-1));
+                    object.insert("length".to_string(), Value::from(0));
+                    object.insert("string".to_string(), Value::Null);
+                }
+            }
+            if index > 0 {
+                object.insert("name".to_string(), name.map_or(Value::Null, Value::from));
+            }
+            object
+        };
+        let mut object = group(0, None);
+        let groups = self
+            .regex
+            .capture_names()
+            .enumerate()
+            .skip(1)
+            .map(|(index, name)| Value::Object(group(index, name)))
+            .collect();
+        object.insert("captures".to_string(), Value::Array(groups));
+        Value::Object(object)
+    }
+}
+
+impl Found {
+    fn groups(&self) -> &[Value] {
+        match self.object.get("captures") {
+            Some(Value::Array(groups)) => groups,
+            _ => &[],
+        }
+    }
+
+    /// The named groups of the match as an object of strings, as `capture` reports
+    pub fn captures(&self) -> Value {
+        Value::Object(
+            self.groups()
+                .iter()
+                .filter_map(|group| match &group["name"] {
+                    Value::String(name) => Some((name.clone(), group["string"].clone())),
+                    _ => None,
+                })
+                .collect(),
+        )
+    }
+
+    /// The matched string, or the strings of its groups if the regex has any,
+    /// as `scan` reports
+    pub fn scanned(&self) -> Value {
+        match self.groups() {
+            [] => self.object["string"].clone(),
+            groups => Value::Array(groups.iter().map(|group| group["string"].clone()).collect()),
+        }
+    }
+}
+
+/// The pieces of `text` between every match
+pub fn split(text: &str, found: &[Found]) -> Value {
+    let mut pieces = Vec::new();
+    let mut last = 0;
+    for found in found {
+        pieces.push(Value::from(&text[last..found.start]));
+        last = found.end;
+    }
+    pieces.push(Value::from(&text[last..]));
+    Value::Array(pieces)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn matcher(re: &str, flags: &str) -> Matcher {
+        Matcher::new(&json!(re), &json!(flags)).unwrap()
+    }
+
+    #[test]
+    fn test_match_objects() {
+        let found = matcher("(?<word>[a-z]+)(\\d)?", "g").find("ab1 é c");
+        let objects: Vec<Value> = found.into_iter().map(|found| found.object).collect();
+        assert_eq!(
+            objects[0],
+            json!({
+                "offset": 0, "length": 3, "string": "ab1",
+                "captures": [
+                    {"offset": 0, "length": 2, "string": "ab", "name": "word"},
+                    {"offset": 2, "length": 1, "string": "1", "name": null}
+                ]
+            })
+        );
+        // Offsets count codepoints, and unmatched groups are reported as null
+        assert_eq!(
+            objects[1],
+            json!({
+                "offset": 6, "length": 1, "string": "c",
+                "captures": [
+                    {"offset": 6, "length": 1, "string": "c", "name": "word"},
+                    {"offset": -1, "length": 0, "string": null, "name": null}
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn test_flags() {
+        assert_eq!(matcher("a", "").find("aaa").len(), 1);
+        assert_eq!(matcher("a", "g").find("aaa").len(), 3);
+        assert!(matcher("A", "i").is_match("a"));
+        assert!(!matcher("A", "").is_match("a"));
+        assert!(matcher("a b", "x").is_match("ab"));
+        assert!(matcher("a.b", "p").is_match("a\nb"));
+        assert_eq!(matcher("x*", "g").find("ab").len(), 3);
+        assert_eq!(matcher("x*", "gn").find("ab").len(), 0);
+        assert!(Matcher::new(&json!(["A", "i"]), &json!(null))
+            .unwrap()
+            .is_match("a"));
+        assert_eq!(
+            Matcher::new(&json!("a"), &json!("q"))
+                .err()
+                .unwrap()
+                .to_string(),
+            "q is not a valid modifier string"
+        );
+        assert_eq!(
+            Matcher::new(&json!("("), &json!(null))
+                .err()
+                .unwrap()
+                .to_string(),
+            "\"(\" is not a valid regex: unclosed group"
+        );
+    }
+
+    #[test]
+    fn test_captures_scan_and_split() {
+        let found = matcher("(?<key>\\w+)=(?<value>\\w*)", "g").find("a=1, b=");
+        assert_eq!(found[0].captures(), json!({"key": "a", "value": "1"}));
+        assert_eq!(found[1].scanned(), json!(["b", ""]));
+        assert_eq!(matcher("\\d", "g").find("a1b2")[1].scanned(), json!("2"));
+        assert_eq!(
+            split("a, b,c", &matcher(", *", "g").find("a, b,c")),
+            json!(["a", "b", "c"])
+        );
+    }
+}
@@ -0,0 +1,264 @@
+use crate::error::RuntimeError;
+use crate::value::{describe, type_name};
+use serde_json::Value;
+
+/// A string as-is, or any other value as compact JSON
+pub fn tostring(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Parse a string as a JSON number, passing numbers through unchanged
+pub fn tonumber(value: &Value) -> Result<Value, RuntimeError> {
+    match value {
+        Value::Number(_) => Ok(value.clone()),
+        Value::String(s) => match serde_json::from_str::<Value>(s) {
+            Ok(number @ Value::Number(_)) => Ok(number),
+            _ => Err(RuntimeError::Type(format!(
+                "Cannot parse {} as a number",
+                describe(value)
+            ))),
+        },
+        _ => Err(RuntimeError::Type(format!(
+            "{} cannot be parsed as a number",
+            describe(value)
+        ))),
+    }
+}
+
+fn expect_string<'v>(value: &'v Value, name: &str) -> Result<&'v str, RuntimeError> {
+    match value {
+        Value::String(s) => Ok(s),
+        _ => Err(RuntimeError::Type(format!(
+            "{} input must be a string",
+            name
+        ))),
+    }
+}
+
+/// Split a string on every occurrence of `separator`
+pub fn split(input: &Value, separator: &Value) -> Result<Value, RuntimeError> {
+    let (input, separator) = match (input, separator) {
+        (Value::String(input), Value::String(separator)) => (input, separator),
+        _ => {
+            return Err(RuntimeError::Type(
+                "split input and separator must be strings".to_string(),
+            ))
+        }
+    };
+    let parts: Vec<Value> = if input.is_empty() {
+        Vec::new()
+    } else if separator.is_empty() {
+        input
+            .chars()
+            .map(|c| Value::String(c.to_string()))
+            .collect()
+    } else {
+        input.split(separator.as_str()).map(Value::from).collect()
+    };
+    Ok(Value::Array(parts))
+}
+
+/// Concatenate the elements of an array with `separator` between them.
+/// Nulls become empty strings and numbers and booleans are written as JSON.
+pub fn join(input: &Value, separator: &Value) -> Result<Value, RuntimeError> {
+    let items = match input {
+        Value::Array(items) => items,
+        _ => return Err(RuntimeError::CannotIterate(describe(input))),
+    };
+    let separator = match separator {
+        Value::String(s) => s,
+        _ => {
+            return Err(RuntimeError::Type(format!(
+                "{} is not a valid separator",
+                describe(separator)
+            )))
+        }
+    };
+    let mut joined = String::new();
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            joined.push_str(separator);
+        }
+        match item {
+            Value::Null => {}
+            Value::String(s) => joined.push_str(s),
+            Value::Number(_) | Value::Bool(_) => joined.push_str(&item.to_string()),
+            _ => {
+                return Err(RuntimeError::Type(format!(
+                    "Cannot join with {}",
+                    type_name(item)
+                )))
+            }
+        }
+    }
+    Ok(Value::String(joined))
+}
+
+/// The input without `prefix`, or unchanged if it does not start with it
+pub fn ltrimstr(input: &Value, prefix: &Value) -> Value {
+    match (input, prefix) {
+        (Value::String(s), Value::String(prefix)) => match s.strip_prefix(prefix.as_str()) {
+            Some(rest) => Value::from(rest),
+            None => input.clone(),
+        },
+        _ => input.clone(),
+    }
+}
+
+/// The input without `suffix`, or unchanged if it does not end with it
+pub fn rtrimstr(input: &Value, suffix: &Value) -> Value {
+    match (input, suffix) {
+        (Value::String(s), Value::String(suffix)) => match s.strip_suffix(suffix.as_str()) {
+            Some(rest) => Value::from(rest),
+            None => input.clone(),
+        },
+        _ => input.clone(),
+    }
+}
+
+pub fn startswith(input: &Value, prefix: &Value) -> Result<Value, RuntimeError> {
+    match (input, prefix) {
+        (Value::String(s), Value::String(prefix)) => {
+            Ok(Value::Bool(s.starts_with(prefix.as_str())))
+        }
+        _ => Err(RuntimeError::Type(
+            "startswith() requires string inputs".to_string(),
+        )),
+    }
+}
+
+pub fn endswith(input: &Value, suffix: &Value) -> Result<Value, RuntimeError> {
+    match (input, suffix) {
+        (Value::String(s), Value::String(suffix)) => Ok(Value::Bool(s.ends_with(suffix.as_str()))),
+        _ => Err(RuntimeError::Type(
+            "endswith() requires string inputs".to_string(),
+        )),
+    }
+}
+
+pub fn ascii_downcase(input: &Value) -> Result<Value, RuntimeError> {
+    Ok(Value::String(
+        expect_string(input, "ascii_downcase")?.to_ascii_lowercase(),
+    ))
+}
+
+pub fn ascii_upcase(input: &Value) -> Result<Value, RuntimeError> {
+    Ok(Value::String(
+        expect_string(input, "ascii_upcase")?.to_ascii_uppercase(),
+    ))
+}
+
+/// The codepoints of a string
+pub fn explode(input: &Value) -> Result<Value, RuntimeError> {
+    Ok(Value::Array(
+        expect_string(input, "explode")?
+            .chars()
+            .map(|c| Value::from(c as u32))
+            .collect(),
+    ))
+}
+
+/// The string made of an array of codepoints
+pub fn implode(input: &Value) -> Result<Value, RuntimeError> {
+    let codepoints = match input {
+        Value::Array(codepoints) => codepoints,
+        _ => {
+            return Err(RuntimeError::Type(
+                "implode input must be an array".to_string(),
+            ))
+        }
+    };
+    codepoints
+        .iter()
+        .map(|codepoint| {
+            codepoint
+                .as_u64()
+                .and_then(|n| u32::try_from(n).ok())
+                .and_then(char::from_u32)
+                .ok_or_else(|| {
+                    RuntimeError::Type(format!("Invalid codepoint literal {}", describe(codepoint)))
+                })
+        })
+        .collect::<Result<String, _>>()
+        .map(Value::String)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_tostring_and_tonumber() {
+        assert_eq!(tostring(&json!("a")), "a");
+        assert_eq!(tostring(&json!({"a": [1, null]})), r#"{"a":[1,null]}"#);
+        assert_eq!(tonumber(&json!("1.5")).unwrap(), json!(1.5));
+        assert_eq!(tonumber(&json!(3)).unwrap(), json!(3));
+        assert_eq!(
+            tonumber(&json!("abc")).unwrap_err().to_string(),
+            "Cannot parse string (\"abc\") as a number"
+        );
+        assert!(tonumber(&json!("[1]")).is_err());
+        assert!(tonumber(&json!(null)).is_err());
+    }
+
+    #[test]
+    fn test_split_and_join() {
+        assert_eq!(
+            split(&json!("a, b, c"), &json!(", ")).unwrap(),
+            json!(["a", "b", "c"])
+        );
+        assert_eq!(split(&json!("ab"), &json!("")).unwrap(), json!(["a", "b"]));
+        assert_eq!(split(&json!(""), &json!(",")).unwrap(), json!([]));
+        assert_eq!(split(&json!("a,"), &json!(",")).unwrap(), json!(["a", ""]));
+        assert!(split(&json!(1), &json!(",")).is_err());
+
+        assert_eq!(
+            join(&json!(["a", 1, null, true]), &json!("-")).unwrap(),
+            json!("a-1--true")
+        );
+        assert_eq!(join(&json!([]), &json!("-")).unwrap(), json!(""));
+        assert_eq!(
+            join(&json!([[1]]), &json!("-")).unwrap_err().to_string(),
+            "Cannot join with array"
+        );
+    }
+
+    #[test]
+    fn test_affixes() {
+        assert_eq!(ltrimstr(&json!("foobar"), &json!("foo")), json!("bar"));
+        assert_eq!(ltrimstr(&json!("foobar"), &json!("bar")), json!("foobar"));
+        assert_eq!(ltrimstr(&json!(1), &json!("foo")), json!(1));
+        assert_eq!(rtrimstr(&json!("foobar"), &json!("bar")), json!("foo"));
+        assert_eq!(
+            startswith(&json!("foobar"), &json!("foo")).unwrap(),
+            json!(true)
+        );
+        assert_eq!(
+            endswith(&json!("foobar"), &json!("foo")).unwrap(),
+            json!(false)
+        );
+        assert_eq!(
+            startswith(&json!(1), &json!("foo"))
+                .unwrap_err()
+                .to_string(),
+            "startswith() requires string inputs"
+        );
+    }
+
+    #[test]
+    fn test_case_and_codepoints() {
+        assert_eq!(ascii_upcase(&json!("abc é")).unwrap(), json!("ABC é"));
+        assert_eq!(ascii_downcase(&json!("ABC")).unwrap(), json!("abc"));
+        assert!(ascii_upcase(&json!(1)).is_err());
+        assert_eq!(explode(&json!("aé😀")).unwrap(), json!([97, 233, 128512]));
+        assert_eq!(implode(&json!([97, 233, 128512])).unwrap(), json!("aé😀"));
+        assert_eq!(
+            implode(&json!([55296])).unwrap_err().to_string(),
+            "Invalid codepoint literal number (55296)"
+        );
+    }
+}
@@ -0,0 +1,622 @@
+use crate::error::RuntimeError;
+use crate::value::{self, describe};
+use serde_json::Value;
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The format of `todate` and `fromdate`
+pub const ISO8601: &str = "%Y-%m-%dT%H:%M:%SZ";
+
+const WEEKDAYS: [&str; 7] = [
+    "Sunday",
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+];
+
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+/// The largest magnitude of a timestamp, about three billion years either
+/// side of the epoch, so that calendar arithmetic cannot overflow an `i64`
+const MAX_TIMESTAMP: f64 = 1e17;
+
+/// A "broken down" UTC time, which jq represents as the array
+/// `[year, month, day, hours, minutes, seconds, weekday, day of year]` with
+/// months and days of the year counted from 0 and weekdays from Sunday
+#[derive(Debug, Clone, PartialEq)]
+struct Tm {
+    year: i64,
+    month: i64,
+    day: i64,
+    hour: i64,
+    minute: i64,
+    second: f64,
+    weekday: i64,
+    yearday: i64,
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar, with
+/// `month` counted from 1 (Howard Hinnant's `days_from_civil`)
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_from_march = (month + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// The year, month (from 1) and day of a number of days since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn out_of_range(name: &str) -> RuntimeError {
+    RuntimeError::Type(format!("{} time is out of range", name))
+}
+
+impl Tm {
+    /// The broken down time of a timestamp, or `None` if it is further than
+    /// `MAX_TIMESTAMP` from the epoch
+    fn from_timestamp(timestamp: f64) -> Option<Tm> {
+        if timestamp.is_nan() || timestamp.abs() >= MAX_TIMESTAMP {
+            return None;
+        }
+        let whole = timestamp.floor();
+        let days = (whole / 86400.0).floor() as i64;
+        let seconds = whole as i64 - days * 86400;
+        let (year, month, day) = civil_from_days(days);
+        Some(Tm {
+            year,
+            month: month - 1,
+            day,
+            hour: seconds / 3600,
+            minute: seconds / 60 % 60,
+            second: (seconds % 60) as f64 + (timestamp - whole),
+            weekday: (days + 4).rem_euclid(7),
+            yearday: days - days_from_civil(year, 1, 1),
+        })
+    }
+
+    /// Seconds since the epoch, or `None` if the time is `MAX_TIMESTAMP` or
+    /// more from the epoch. Fields out of their usual range carry over, so
+    /// month 12 is January of the next year.
+    fn timestamp(&self) -> Option<f64> {
+        let year = self.year.checked_add(self.month.div_euclid(12))?;
+        let max_year = (MAX_TIMESTAMP / (366.0 * 86400.0)) as i64;
+        if !(-max_year..=max_year).contains(&year) {
+            return None;
+        }
+        let month = self.month.rem_euclid(12) + 1;
+        let days = days_from_civil(year, month, 1)
+            .checked_add(self.day)?
+            .checked_sub(1)?;
+        let seconds = days
+            .checked_mul(86400)?
+            .checked_add(self.hour.checked_mul(3600)?)?
+            .checked_add(self.minute.checked_mul(60)?)?;
+        let timestamp = seconds as f64 + self.second;
+        (timestamp.abs() < MAX_TIMESTAMP).then_some(timestamp)
+    }
+
+    /// The same time with every field in range and the weekday and day of
+    /// the year recomputed
+    fn normalized(&self) -> Option<Tm> {
+        Tm::from_timestamp(self.timestamp()?)
+    }
+
+    /// Read a broken down time, as produced by `gmtime` or `strptime`
+    fn from_value(value: &Value, name: &str) -> Result<Tm, RuntimeError> {
+        let fields = match value {
+            Value::Array(fields) => fields,
+            _ => {
+                return Err(RuntimeError::Type(format!(
+                    "{} requires parsed datetime inputs",
+                    name
+                )))
+            }
+        };
+        let numbers: Vec<f64> = fields.iter().map_while(value::as_f64).collect();
+        if numbers.len() < 6 || numbers.len() < fields.len() {
+            return Err(RuntimeError::Type(format!(
+                "{} requires array of 6 numbers",
+                name
+            )));
+        }
+        let field = |i: usize| numbers.get(i).copied().unwrap_or(0.0) as i64;
+        Ok(Tm {
+            year: field(0),
+            month: field(1),
+            day: field(2),
+            hour: field(3),
+            minute: field(4),
+            second: numbers[5],
+            weekday: field(6),
+            yearday: field(7),
+        })
+    }
+
+    fn to_value(&self) -> Value {
+        Value::Array(vec![
+            Value::from(self.year),
+            Value::from(self.month),
+            Value::from(self.day),
+            Value::from(self.hour),
+            Value::from(self.minute),
+            value::number(self.second),
+            Value::from(self.weekday),
+            Value::from(self.yearday),
+        ])
+    }
+}
+
+/// Seconds since the epoch, with a fractional part
+pub fn now() -> Value {
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |elapsed| elapsed.as_secs_f64());
+    value::number(elapsed)
+}
+
+fn timestamp(input: &Value, name: &str) -> Result<f64, RuntimeError> {
+    match value::as_f64(input) {
+        Some(timestamp) if timestamp.is_finite() => Ok(timestamp),
+        _ => Err(RuntimeError::Type(format!(
+            "{}() requires a number, not {}",
+            name,
+            describe(input)
+        ))),
+    }
+}
+
+/// The broken down UTC time of a timestamp
+pub fn gmtime(input: &Value) -> Result<Value, RuntimeError> {
+    Tm::from_timestamp(timestamp(input, "gmtime")?)
+        .map(|tm| tm.to_value())
+        .ok_or_else(|| out_of_range("gmtime"))
+}
+
+/// The timestamp of a broken down UTC time, in whole seconds
+pub fn mktime(input: &Value) -> Result<Value, RuntimeError> {
+    let tm = Tm::from_value(input, "mktime")?;
+    let timestamp = tm.timestamp().ok_or_else(|| out_of_range("mktime"))?;
+    Ok(Value::from(timestamp.floor() as i64))
+}
+
+/// Format a timestamp or broken down time
+pub fn strftime(input: &Value, format: &Value) -> Result<Value, RuntimeError> {
+    let tm = match input {
+        Value::Number(_) => Tm::from_timestamp(timestamp(input, "strftime")?),
+        _ => Tm::from_value(input, "strftime/1")?.normalized(),
+    }
+    .ok_or_else(|| out_of_range("strftime/1"))?;
+    let format = match format {
+        Value::String(format) => format,
+        _ => {
+            return Err(RuntimeError::Type(
+                "strftime/1 requires a string format".to_string(),
+            ))
+        }
+    };
+    let mut out = String::new();
+    format_into(&mut out, &tm, format)?;
+    Ok(Value::String(out))
+}
+
+/// The conversions that stand for a longer format in the C locale
+fn expansion(spec: char) -> Option<&'static str> {
+    match spec {
+        'c' => Some("%a %b %e %H:%M:%S %Y"),
+        'D' | 'x' => Some("%m/%d/%y"),
+        'F' => Some("%Y-%m-%d"),
+        'r' => Some("%I:%M:%S %p"),
+        'R' => Some("%H:%M"),
+        'T' | 'X' => Some("%H:%M:%S"),
+        _ => None,
+    }
+}
+
+fn format_into(out: &mut String, tm: &Tm, format: &str) -> Result<(), RuntimeError> {
+    let hour12 = (tm.hour + 11) % 12 + 1;
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        let spec = chars.next().ok_or_else(|| {
+            RuntimeError::Type(format!("{:?} ends with an incomplete conversion", format))
+        })?;
+        if let Some(expanded) = expansion(spec) {
+            format_into(out, tm, expanded)?;
+            continue;
+        }
+        // Writing to a String cannot fail
+        let _ = match spec {
+            'a' => write!(out, "{}", &WEEKDAYS[tm.weekday as usize][..3]),
+            'A' => write!(out, "{}", WEEKDAYS[tm.weekday as usize]),
+            'b' | 'h' => write!(out, "{}", &MONTHS[tm.month as usize][..3]),
+            'B' => write!(out, "{}", MONTHS[tm.month as usize]),
+            'C' => write!(out, "{:02}", tm.year.div_euclid(100)),
+            'd' => write!(out, "{:02}", tm.day),
+            'e' => write!(out, "{:2}", tm.day),
+            'H' => write!(out, "{:02}", tm.hour),
+            'I' => write!(out, "{:02}", hour12),
+            'j' => write!(out, "{:03}", tm.yearday + 1),
+            'k' => write!(out, "{:2}", tm.hour),
+            'l' => write!(out, "{:2}", hour12),
+            'm' => write!(out, "{:02}", tm.month + 1),
+            'M' => write!(out, "{:02}", tm.minute),
+            'n' => writeln!(out),
+            'p' => write!(out, "{}", if tm.hour < 12 { "AM" } else { "PM" }),
+            // `tm` came from a timestamp, so it converts back to one
+            's' => write!(out, "{}", tm.timestamp().unwrap_or(0.0).floor() as i64),
+            'S' => write!(out, "{:02}", tm.second.floor() as i64),
+            't' => write!(out, "\t"),
+            'u' => write!(out, "{}", (tm.weekday + 6) % 7 + 1),
+            'w' => write!(out, "{}", tm.weekday),
+            'y' => write!(out, "{:02}", tm.year.rem_euclid(100)),
+            'Y' => write!(out, "{}", tm.year),
+            'z' => write!(out, "+0000"),
+            'Z' => write!(out, "UTC"),
+            '%' => write!(out, "%"),
+            other => {
+                return Err(RuntimeError::Type(format!(
+                    "%{} is not a supported strftime conversion",
+                    other
+                )))
+            }
+        };
+    }
+    Ok(())
+}
+
+/// Parse a string with a strftime-style format into a broken down time.
+/// A `%z` offset is applied, so the result is always UTC.
+pub fn strptime(input: &Value, format: &Value) -> Result<Value, RuntimeError> {
+    let (text, format) = match (input, format) {
+        (Value::String(text), Value::String(format)) => (text, format),
+        _ => {
+            return Err(RuntimeError::Type(
+                "strptime/1 requires string inputs and arguments".to_string(),
+            ))
+        }
+    };
+    let mismatch = || {
+        RuntimeError::Type(format!(
+            "date {:?} does not match format {:?}",
+            text, format
+        ))
+    };
+    let mut parser = Strptime {
+        rest: text,
+        tm: Tm {
+            year: 1900,
+            month: 0,
+            day: 1,
+            hour: 0,
+            minute: 0,
+            second: 0.0,
+            weekday: 0,
+            yearday: 0,
+        },
+        offset: 0,
+        pm: None,
+        epoch: None,
+    };
+    parser.parse(format).ok_or_else(mismatch)?;
+    if !parser.rest.trim_start().is_empty() {
+        return Err(mismatch());
+    }
+    let mut tm = parser.tm;
+    if let Some(pm) = parser.pm {
+        tm.hour = tm.hour % 12 + if pm { 12 } else { 0 };
+    }
+    let timestamp = match parser.epoch {
+        Some(epoch) => Some(epoch as f64),
+        None => tm
+            .timestamp()
+            .map(|timestamp| timestamp - parser.offset as f64),
+    };
+    timestamp
+        .and_then(Tm::from_timestamp)
+        .map(|tm| tm.to_value())
+        .ok_or_else(|| out_of_range("strptime/1"))
+}
+
+/// The state of `strptime` partway through its input
+struct Strptime<'t> {
+    rest: &'t str,
+    tm: Tm,
+    /// Seconds east of UTC, from `%z`
+    offset: i64,
+    /// Whether `%p` said PM, to adjust a `%I` hour
+    pm: Option<bool>,
+    /// Seconds since the epoch, from `%s`
+    epoch: Option<i64>,
+}
+
+impl Strptime<'_> {
+    /// Consume the input matched by `format`, or `None` if it does not match
+    fn parse(&mut self, format: &str) -> Option<()> {
+        let mut chars = format.chars();
+        while let Some(c) = chars.next() {
+            if c.is_whitespace() {
+                self.rest = self.rest.trim_start();
+                continue;
+            }
+            if c != '%' {
+                self.rest = self.rest.strip_prefix(c)?;
+                continue;
+            }
+            match chars.next()? {
+                'Y' => self.tm.year = self.signed()?,
+                'C' => self.tm.year = self.number(2, 0, 99)? * 100 + self.tm.year.rem_euclid(100),
+                'y' => {
+                    let year = self.number(2, 0, 99)?;
+                    self.tm.year = if year < 69 { 2000 + year } else { 1900 + year };
+                }
+                'm' => self.tm.month = self.number(2, 1, 12)? - 1,
+                'd' | 'e' => {
+                    self.rest = self.rest.trim_start();
+                    self.tm.day = self.number(2, 1, 31)?;
+                }
+                'H' | 'k' => {
+                    self.rest = self.rest.trim_start();
+                    self.tm.hour = self.number(2, 0, 23)?;
+                }
+                'I' | 'l' => {
+                    self.rest = self.rest.trim_start();
+                    self.tm.hour = self.number(2, 1, 12)?;
+                }
+                'M' => self.tm.minute = self.number(2, 0, 59)?,
+                'S' => self.tm.second = self.number(2, 0, 60)? as f64,
+                'j' => {
+                    let yearday = self.number(3, 1, 366)?;
+                    self.tm.month = 0;
+                    self.tm.day = yearday;
+                }
+                'a' | 'A' => {
+                    self.name(&WEEKDAYS)?;
+                }
+                'b' | 'B' | 'h' => self.tm.month = self.name(&MONTHS)? as i64,
+                'p' => {
+                    let upper = self.rest.get(..2)?.to_ascii_uppercase();
+                    self.pm = Some(match upper.as_str() {
+                        "AM" => false,
+                        "PM" => true,
+                        _ => return None,
+                    });
+                    self.rest = &self.rest[2..];
+                }
+                's' => self.epoch = Some(self.signed()?),
+                'z' => self.offset = self.offset()?,
+                'Z' => {
+                    let end = self
+                        .rest
+                        .find(|c: char| !c.is_ascii_alphabetic())
+                        .unwrap_or(self.rest.len());
+                    self.rest = &self.rest[end..];
+                }
+                'D' => self.parse("%m/%d/%y")?,
+                'F' => self.parse("%Y-%m-%d")?,
+                'R' => self.parse("%H:%M")?,
+                'T' => self.parse("%H:%M:%S")?,
+                'n' | 't' => self.rest = self.rest.trim_start(),
+                '%' => self.rest = self.rest.strip_prefix('%')?,
+                _ => return None,
+            }
+        }
+        Some(())
+    }
+
+    /// An unsigned number of at most `width` digits between `min` and `max`
+    fn number(&mut self, width: usize, min: i64, max: i64) -> Option<i64> {
+        let len = self
+            .rest
+            .bytes()
+            .take(width)
+            .take_while(u8::is_ascii_digit)
+            .count();
+        let n: i64 = self.rest[..len].parse().ok()?;
+        self.rest = &self.rest[len..];
+        (min..=max).contains(&n).then_some(n)
+    }
+
+    /// A number of any length with an optional sign
+    fn signed(&mut self) -> Option<i64> {
+        let negative = match self.rest.strip_prefix(['-', '+']) {
+            Some(rest) => {
+                let negative = self.rest.starts_with('-');
+                self.rest = rest;
+                negative
+            }
+            None => false,
+        };
+        let len = self.rest.bytes().take_while(u8::is_ascii_digit).count();
+        let n: i64 = self.rest[..len].parse().ok()?;
+        self.rest = &self.rest[len..];
+        Some(if negative { -n } else { n })
+    }
+
+    /// The index of the name (or its three-letter abbreviation) at the start
+    /// of the input, ignoring case
+    fn name(&mut self, names: &[&str]) -> Option<usize> {
+        names.iter().enumerate().find_map(|(i, name)| {
+            [*name, &name[..3]].into_iter().find_map(|candidate| {
+                let prefix = self.rest.get(..candidate.len())?;
+                if prefix.eq_ignore_ascii_case(candidate) {
+                    self.rest = &self.rest[candidate.len()..];
+                    Some(i)
+                } else {
+                    None
+                }
+            })
+        })
+    }
+
+    /// `Z`, or `+hh`, `+hhmm` or `+hh:mm` with either sign, in seconds
+    fn offset(&mut self) -> Option<i64> {
+        if let Some(rest) = self.rest.strip_prefix('Z') {
+            self.rest = rest;
+            return Some(0);
+        }
+        let sign = match self.rest.chars().next()? {
+            '+' => 1,
+            '-' => -1,
+            _ => return None,
+        };
+        self.rest = &self.rest[1..];
+        let hours = self.number(2, 0, 23)?;
+        if let Some(rest) = self.rest.strip_prefix(':') {
+            self.rest = rest;
+        }
+        let minutes = if self.rest.starts_with(|c: char| c.is_ascii_digit()) {
+            self.number(2, 0, 59)?
+        } else {
+            0
+        };
+        Some(sign * (hours * 3600 + minutes * 60))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_civil_dates() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        for days in [-800_000, -1, 0, 59, 11016, 19_000, 2_932_896] {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+    }
+
+    #[test]
+    fn test_gmtime_and_mktime() {
+        let broken_down = gmtime(&json!(1425599621)).unwrap();
+        assert_eq!(broken_down, json!([2015, 2, 5, 23, 53, 41, 4, 63]));
+        assert_eq!(mktime(&broken_down).unwrap(), json!(1425599621));
+        assert_eq!(
+            gmtime(&json!(-1.5)).unwrap(),
+            json!([1969, 11, 31, 23, 59, 58.5, 3, 364])
+        );
+        // Out of range fields carry over
+        assert_eq!(mktime(&json!([1969, 11, 32, 0, 0, 0])).unwrap(), json!(0));
+        assert_eq!(
+            mktime(&json!([1, 2])).unwrap_err().to_string(),
+            "mktime requires array of 6 numbers"
+        );
+        assert_eq!(
+            mktime(&json!("x")).unwrap_err().to_string(),
+            "mktime requires parsed datetime inputs"
+        );
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        assert_eq!(
+            mktime(&json!([1e17, 0, 1, 0, 0, 0]))
+                .unwrap_err()
+                .to_string(),
+            "mktime time is out of range"
+        );
+        assert!(mktime(&json!([1970, 0, 1, 1e19, 0, 0])).is_err());
+        assert!(mktime(&json!([0, 0, 0, 1, 0, 1e300])).is_err());
+        assert!(gmtime(&json!(1e300)).is_err());
+        assert!(gmtime(&json!(-1e17)).is_err());
+        assert!(strftime(&json!(1e300), &json!(ISO8601)).is_err());
+        assert!(strftime(&json!([1e17, 0, 1, 0, 0, 0]), &json!(ISO8601)).is_err());
+        assert!(strptime(&json!("99999999999999999"), &json!("%Y")).is_err());
+    }
+
+    #[test]
+    fn test_strftime() {
+        let format = |input: Value, format: &str| strftime(&input, &json!(format)).unwrap();
+        assert_eq!(
+            format(json!(1425599621), ISO8601),
+            json!("2015-03-05T23:53:41Z")
+        );
+        assert_eq!(
+            format(
+                json!(1425599621),
+                "%A, %B %e %Y %I:%M %p (%j) %a %b %u %w %y %C"
+            ),
+            json!("Thursday, March  5 2015 11:53 PM (064) Thu Mar 4 4 15 20")
+        );
+        assert_eq!(
+            format(json!([2015, 2, 5, 23, 51, 47, 0, 0]), "%c %s %% %Z"),
+            json!("Thu Mar  5 23:51:47 2015 1425599507 % UTC")
+        );
+        assert!(strftime(&json!(0), &json!("%Q")).is_err());
+        assert!(strftime(&json!("2015"), &json!("%Y")).is_err());
+    }
+
+    #[test]
+    fn test_strptime() {
+        let parse = |input: &str, format: &str| strptime(&json!(input), &json!(format));
+        assert_eq!(
+            parse("2015-03-05T23:51:47Z", ISO8601).unwrap(),
+            json!([2015, 2, 5, 23, 51, 47, 4, 63])
+        );
+        assert_eq!(
+            parse(
+                "Thu, 5 Mar 2015 11:51:47 pm +0100",
+                "%a, %d %b %Y %I:%M:%S %p %z"
+            )
+            .unwrap(),
+            json!([2015, 2, 5, 22, 51, 47, 4, 63])
+        );
+        assert_eq!(
+            parse("2015-03-05T23:51:47-05:30", "%FT%T%z").unwrap(),
+            json!([2015, 2, 6, 5, 21, 47, 5, 64])
+        );
+        assert_eq!(
+            parse("1425599621", "%s").unwrap(),
+            json!([2015, 2, 5, 23, 53, 41, 4, 63])
+        );
+        assert_eq!(
+            parse("2015-13-05", "%Y-%m-%d").unwrap_err().to_string(),
+            "date \"2015-13-05\" does not match format \"%Y-%m-%d\""
+        );
+        assert!(parse("2015-03-05 extra", "%Y-%m-%d").is_err());
+        assert!(parse("2015", "%Y-%m").is_err());
+        assert!(strptime(&json!(1), &json!("%Y")).is_err());
+    }
+}
@@ -0,0 +1,565 @@
+use crate::error::RuntimeError;
+use serde_json::{Map, Number, Value};
+use std::cmp::Ordering;
+
+/// jq's name for the type of a value
+pub fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Position of a type in jq's ordering: null < false < true < numbers < strings < arrays < objects
+fn type_rank(value: &Value) -> u8 {
+    match value {
+        Value::Null => 0,
+        Value::Bool(false) => 1,
+        Value::Bool(true) => 2,
+        Value::Number(_) => 3,
+        Value::String(_) => 4,
+        Value::Array(_) => 5,
+        Value::Object(_) => 6,
+    }
+}
+
+/// jq's total ordering of JSON values. Objects compare by their sorted key
+/// sets first and then by the values under those keys. Integers of any size
+/// compare exactly; other numbers compare as f64.
+pub fn compare(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => compare_integers(&x.to_string(), &y.to_string())
+            .unwrap_or_else(|| to_f64(x).partial_cmp(&to_f64(y)).unwrap_or(Ordering::Equal)),
+        (Value::String(x), Value::String(y)) => x.cmp(y),
+        (Value::Array(x), Value::Array(y)) => {
+            for (x, y) in x.iter().zip(y) {
+                let ordering = compare(x, y);
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            x.len().cmp(&y.len())
+        }
+        (Value::Object(x), Value::Object(y)) => {
+            let mut x_keys: Vec<&String> = x.keys().collect();
+            let mut y_keys: Vec<&String> = y.keys().collect();
+            x_keys.sort();
+            y_keys.sort();
+            x_keys.cmp(&y_keys).then_with(|| {
+                x_keys
+                    .iter()
+                    .map(|key| compare(&x[key.as_str()], &y[key.as_str()]))
+                    .find(|ordering| *ordering != Ordering::Equal)
+                    .unwrap_or(Ordering::Equal)
+            })
+        }
+        _ => type_rank(a).cmp(&type_rank(b)),
+    }
+}
+
+/// Compare two numbers by their text if both are written as integers, which
+/// keeps IDs too large for f64 distinct
+fn compare_integers(x: &str, y: &str) -> Option<Ordering> {
+    fn digits(text: &str) -> Option<(bool, &str)> {
+        let (negative, digits) = match text.strip_prefix('-') {
+            Some(digits) => (digits != "0", digits),
+            None => (false, text),
+        };
+        let valid = !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit());
+        valid.then_some((negative, digits))
+    }
+    let ((x_negative, x), (y_negative, y)) = (digits(x)?, digits(y)?);
+    let magnitude = x.len().cmp(&y.len()).then_with(|| x.cmp(y));
+    Some(match (x_negative, y_negative) {
+        (false, false) => magnitude,
+        (true, true) => magnitude.reverse(),
+        (false, true) => Ordering::Greater,
+        (true, false) => Ordering::Less,
+    })
+}
+
+/// Whether an integer is within ±`u64::MAX`, the range kept exact. It is
+/// symmetric so that `-a - 1` overflows exactly when `a + 1` does.
+fn in_range(n: i128) -> bool {
+    n.unsigned_abs() <= u128::from(u64::MAX)
+}
+
+/// A number as an exact integer, if it is written as one within ±`u64::MAX`
+pub fn integer(n: &Number) -> Option<i128> {
+    n.as_i64()
+        .map(i128::from)
+        .or_else(|| n.as_u64().map(i128::from))
+        .or_else(|| n.to_string().parse().ok().filter(|&n| in_range(n)))
+}
+
+/// An exact integer result as a JSON number, or `None` if it is not within
+/// ±`u64::MAX`
+pub fn from_integer(n: i128) -> Option<Value> {
+    if !in_range(n) {
+        return None;
+    }
+    match i64::try_from(n) {
+        Ok(n) => Some(Value::from(n)),
+        Err(_) => match u64::try_from(n) {
+            Ok(n) => Some(Value::from(n)),
+            // Below `i64::MIN`, which only the literal text can hold
+            Err(_) => n.to_string().parse().ok().map(Value::Number),
+        },
+    }
+}
+
+/// Type and (truncated) contents of a value, as shown in error messages
+pub fn describe(value: &Value) -> String {
+    const MAX_LEN: usize = 11;
+    let text = value.to_string();
+    let text = if text.chars().count() > MAX_LEN {
+        format!("{}...", text.chars().take(MAX_LEN - 1).collect::<String>())
+    } else {
+        text
+    };
+    format!("{} ({})", type_name(value), text)
+}
+
+/// The most memory a filter may ask for at once to build a single string or
+/// array, such as with `"ab" * n`. Bigger requests fail with an error rather
+/// than exhausting memory.
+const MAX_BYTES: usize = 1 << 30;
+
+/// Whether `count` items of `size` bytes each fit within `MAX_BYTES`
+pub fn fits(count: usize, size: usize) -> bool {
+    count
+        .checked_mul(size)
+        .is_some_and(|bytes| bytes <= MAX_BYTES)
+}
+
+/// A number as an f64. Numbers beyond the f64 range, which are only kept as
+/// their literal text, saturate to ±`f64::MAX` like they do in jq.
+pub fn to_f64(n: &Number) -> f64 {
+    n.as_f64().unwrap_or_else(|| {
+        let n: f64 = n.to_string().parse().unwrap_or(0.0);
+        n.clamp(-f64::MAX, f64::MAX)
+    })
+}
+
+/// `to_f64` of a value, or `None` if it is not a number
+pub fn as_f64(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => Some(to_f64(n)),
+        _ => None,
+    }
+}
+
+/// Build a JSON number, keeping integral results as integers. A result that
+/// overflows f64 saturates to ±`f64::MAX` (`1.7976931348623157e+308`) as in
+/// jq, so `1e308 * 10` is still a number. NaN has no JSON form and becomes
+/// null.
+pub fn number(n: f64) -> Value {
+    if n.fract() == 0.0 && n.abs() < 9007199254740992.0 {
+        Value::Number(Number::from(n as i64))
+    } else if n.fract() == 0.0 && plain_digits(n) {
+        // Display spells out every digit, so `2^64` is `18446744073709552000`
+        n.to_string().parse().map_or(Value::Null, Value::Number)
+    } else {
+        Number::from_f64(n.clamp(-f64::MAX, f64::MAX)).map_or(Value::Null, Value::Number)
+    }
+}
+
+/// Whether jq prints a large integral f64 in plain digits, which it does
+/// unless that takes more than 15 zeros after the significant digits: `2^64`
+/// is `18446744073709552000`, but `1e17` is `1e+17`
+fn plain_digits(n: f64) -> bool {
+    let scientific = format!("{:e}", n.abs());
+    let (mantissa, exponent) = scientific.split_once('e').unwrap_or((&scientific, "0"));
+    let digits = mantissa.chars().filter(char::is_ascii_digit).count();
+    exponent
+        .parse::<usize>()
+        .is_ok_and(|exponent| exponent < digits + 15)
+}
+
+/// Number of elements, characters, or the absolute value for numbers
+pub fn length(value: &Value) -> Result<Value, RuntimeError> {
+    match value {
+        Value::Null => Ok(Value::from(0)),
+        Value::Bool(_) => Err(RuntimeError::Type(format!(
+            "{} has no length",
+            describe(value)
+        ))),
+        Value::Number(n) => match integer(n).and_then(|i| from_integer(i.abs())) {
+            Some(abs) => Ok(abs),
+            None => Ok(number(to_f64(n).abs())),
+        },
+        Value::String(s) => Ok(Value::from(s.chars().count())),
+        Value::Array(a) => Ok(Value::from(a.len())),
+        Value::Object(o) => Ok(Value::from(o.len())),
+    }
+}
+
+/// The keys of an object, sorted unless `sorted` is false, or the indices
+/// of an array
+pub fn keys(value: &Value, sorted: bool) -> Result<Value, RuntimeError> {
+    match value {
+        Value::Object(o) => {
+            let mut keys: Vec<&String> = o.keys().collect();
+            if sorted {
+                keys.sort();
+            }
+            Ok(Value::Array(
+                keys.into_iter()
+                    .map(|key| Value::String(key.clone()))
+                    .collect(),
+            ))
+        }
+        Value::Array(a) => Ok(Value::Array((0..a.len()).map(Value::from).collect())),
+        _ => Err(RuntimeError::Type(format!(
+            "{} has no keys",
+            describe(value)
+        ))),
+    }
+}
+
+/// Whether an object has the string `key`, or an array the index `key`
+pub fn has(value: &Value, key: &Value) -> Result<Value, RuntimeError> {
+    match (value, key) {
+        (Value::Object(o), Value::String(k)) => Ok(Value::Bool(o.contains_key(k))),
+        (Value::Array(a), Value::Number(n)) => {
+            let i = to_f64(n);
+            Ok(Value::Bool(i >= 0.0 && i < a.len() as f64))
+        }
+        _ => Err(RuntimeError::Type(format!(
+            "Cannot check whether {} has a {} key",
+            type_name(value),
+            type_name(key)
+        ))),
+    }
+}
+
+/// Whether `value` is kept by the type selector `name`, such as `numbers`
+pub fn selected_by(name: &str, value: &Value) -> bool {
+    match name {
+        "values" => !value.is_null(),
+        "nulls" => value.is_null(),
+        "booleans" => value.is_boolean(),
+        "numbers" => value.is_number(),
+        "strings" => value.is_string(),
+        "arrays" => value.is_array(),
+        "objects" => value.is_object(),
+        "iterables" => value.is_array() || value.is_object(),
+        "scalars" => !(value.is_array() || value.is_object()),
+        _ => false,
+    }
+}
+
+/// Sort the keys of every object inside `value`, for `--sort-keys`
+pub fn sort_keys(value: Value) -> Value {
+    match value {
+        Value::Array(items) => Value::Array(items.into_iter().map(sort_keys).collect()),
+        Value::Object(entries) => {
+            let mut entries: Vec<(String, Value)> = entries.into_iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(key, item)| (key, sort_keys(item)))
+                    .collect(),
+            )
+        }
+        other => other,
+    }
+}
+
+/// `value[key]` for a single key, following jq's lenient null handling
+pub fn index(value: &Value, key: &Value) -> Result<Value, RuntimeError> {
+    match (value, key) {
+        (Value::Object(o), Value::String(k)) => Ok(o.get(k).cloned().unwrap_or(Value::Null)),
+        (Value::Array(a), Value::Number(n)) => Ok(array_index(a.len(), n)
+            .and_then(|i| a.get(i).cloned())
+            .unwrap_or(Value::Null)),
+        (Value::Null, Value::String(_) | Value::Number(_)) => Ok(Value::Null),
+        _ => Err(cannot_index(value, key)),
+    }
+}
+
+/// The position an index refers to in an array of `len` elements, counting
+/// negative indices back from the end, or `None` if it is out of range
+pub fn array_index(len: usize, n: &Number) -> Option<usize> {
+    let mut i = to_f64(n).floor();
+    if i < 0.0 {
+        i += len as f64;
+    }
+    if i < 0.0 || i >= len as f64 {
+        None
+    } else {
+        Some(i as usize)
+    }
+}
+
+/// The error for indexing `value` with an unsupported `key`
+pub fn cannot_index(value: &Value, key: &Value) -> RuntimeError {
+    RuntimeError::CannotIndex {
+        target: type_name(value).to_string(),
+        key: match key {
+            Value::String(s) => format!("\"{}\"", s),
+            other => type_name(other).to_string(),
+        },
+    }
+}
+
+/// Resolve optional slice bounds against a sequence of `len` elements.
+/// Negative bounds count back from the end, fractional ones widen the slice,
+/// and bounds past either end are clamped to it.
+pub fn slice_bounds(
+    len: usize,
+    start: &Value,
+    end: &Value,
+) -> Result<(usize, usize), RuntimeError> {
+    let bound = |bound: &Value, default: usize, round: fn(f64) -> f64| match bound {
+        Value::Null => Ok(default),
+        Value::Number(n) => {
+            let mut n = to_f64(n);
+            if n < 0.0 {
+                n += len as f64;
+            }
+            Ok(round(n).clamp(0.0, len as f64) as usize)
+        }
+        other => Err(RuntimeError::Type(format!(
+            "Start and end indices of an array slice must be numbers, not {}",
+            type_name(other)
+        ))),
+    };
+    let start = bound(start, 0, f64::floor)?;
+    let end = bound(end, len, f64::ceil)?.max(start);
+    Ok((start, end))
+}
+
+/// `value[start:end]` for arrays, strings (by codepoint) and null
+pub fn slice(value: &Value, start: &Value, end: &Value) -> Result<Value, RuntimeError> {
+    match value {
+        Value::Null => Ok(Value::Null),
+        Value::Array(a) => {
+            let (start, end) = slice_bounds(a.len(), start, end)?;
+            Ok(Value::Array(a[start..end].to_vec()))
+        }
+        Value::String(s) => {
+            let (start, end) = slice_bounds(s.chars().count(), start, end)?;
+            Ok(Value::String(
+                s.chars().skip(start).take(end - start).collect(),
+            ))
+        }
+        _ => Err(RuntimeError::Type(format!(
+            "Cannot index {} with object",
+            type_name(value)
+        ))),
+    }
+}
+
+/// The values produced by `.[]`
+pub fn iterate(value: Value) -> Result<Vec<Value>, RuntimeError> {
+    match value {
+        Value::Array(a) => Ok(a),
+        Value::Object(o) => Ok(o.into_iter().map(|(_, v)| v).collect()),
+        other => Err(RuntimeError::CannotIterate(describe(&other))),
+    }
+}
+
+/// The `{"start": s, "end": e}` path component jq uses for slices
+pub fn slice_key(start: Value, end: Value) -> Value {
+    let mut key = Map::new();
+    key.insert("start".to_string(), start);
+    key.insert("end".to_string(), end);
+    Value::Object(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_describe() {
+        assert_eq!(describe(&json!(null)), "null (null)");
+        assert_eq!(describe(&json!("abc")), "string (\"abc\")");
+        assert_eq!(
+            describe(&json!({"name": "Leo Lightning"})),
+            "object ({\"name\":\"L...)"
+        );
+    }
+
+    #[test]
+    fn test_compare() {
+        let ordered = [
+            json!(null),
+            json!(false),
+            json!(true),
+            json!(-1),
+            json!(2.5),
+            json!("a"),
+            json!("b"),
+            json!([]),
+            json!([1]),
+            json!([1, 0]),
+            json!({"a": 2}),
+            json!({"a": 1, "b": 0}),
+            json!({"b": 0}),
+        ];
+        for window in ordered.windows(2) {
+            assert_eq!(compare(&window[0], &window[1]), Ordering::Less);
+            assert_eq!(compare(&window[1], &window[0]), Ordering::Greater);
+        }
+        assert_eq!(compare(&json!(1), &json!(1.0)), Ordering::Equal);
+        assert_eq!(
+            compare(&json!({"a": 1, "b": 2}), &json!({"b": 2, "a": 1})),
+            Ordering::Equal
+        );
+    }
+
+    #[test]
+    fn test_compare_large_numbers() {
+        let parse = |text: &str| serde_json::from_str::<Value>(text).unwrap();
+        let (a, b) = (
+            parse("12345678901234567890123"),
+            parse("12345678901234567890124"),
+        );
+        assert_eq!(compare(&a, &b), Ordering::Less);
+        assert_eq!(compare(&b, &a), Ordering::Greater);
+        assert_eq!(compare(&a, &a.clone()), Ordering::Equal);
+        assert_eq!(compare(&parse("-20"), &parse("-3")), Ordering::Less);
+        assert_eq!(compare(&parse("-0"), &parse("0")), Ordering::Equal);
+        assert_eq!(compare(&parse("1e2"), &parse("100")), Ordering::Equal);
+        assert_eq!(compare(&parse("22.0"), &parse("22")), Ordering::Equal);
+    }
+
+    #[test]
+    fn test_number() {
+        let text = |n: f64| number(n).to_string();
+        assert_eq!(text(1e3), "1000");
+        assert_eq!(text(-2.5), "-2.5");
+        // Large integral results print in plain digits while jq would
+        assert_eq!(text(18446744073709551616.0), "18446744073709552000");
+        assert_eq!(text(-1152921504606846976.0), "-1152921504606847000");
+        assert_eq!(text(-1e16), "-1e+16");
+        assert_eq!(text(1.2345e18), "1234500000000000000");
+        assert_eq!(text(1e17), "1e+17");
+        assert_eq!(text(1e300), "1e+300");
+        assert_eq!(text(f64::INFINITY), "1.7976931348623157e+308");
+        assert_eq!(number(f64::NAN), Value::Null);
+    }
+
+    #[test]
+    fn test_length() {
+        assert_eq!(length(&json!(null)).unwrap(), json!(0));
+        assert_eq!(length(&json!(-5)).unwrap(), json!(5));
+        assert_eq!(length(&json!(-2.5)).unwrap(), json!(2.5));
+        assert_eq!(length(&json!("héllo")).unwrap(), json!(5));
+        assert_eq!(length(&json!([1, 2, 3])).unwrap(), json!(3));
+        assert_eq!(length(&json!({"a": 1})).unwrap(), json!(1));
+        assert_eq!(
+            length(&serde_json::from_str("-1e1000").unwrap())
+                .unwrap()
+                .to_string(),
+            "1.7976931348623157e+308"
+        );
+        assert_eq!(
+            length(&json!(true)).unwrap_err().to_string(),
+            "boolean (true) has no length"
+        );
+    }
+
+    #[test]
+    fn test_keys_and_has() {
+        let object = json!({"b": 1, "a": 2});
+        assert_eq!(keys(&object, true).unwrap(), json!(["a", "b"]));
+        assert_eq!(keys(&object, false).unwrap(), json!(["b", "a"]));
+        assert_eq!(keys(&json!([5, 6]), true).unwrap(), json!([0, 1]));
+        assert_eq!(
+            keys(&json!(1), true).unwrap_err().to_string(),
+            "number (1) has no keys"
+        );
+        assert_eq!(has(&object, &json!("a")).unwrap(), json!(true));
+        assert_eq!(has(&object, &json!("c")).unwrap(), json!(false));
+        assert_eq!(has(&json!([0, 1]), &json!(1)).unwrap(), json!(true));
+        assert_eq!(has(&json!([0, 1]), &json!(2)).unwrap(), json!(false));
+        assert_eq!(
+            has(&object, &json!(0)).unwrap_err().to_string(),
+            "Cannot check whether object has a number key"
+        );
+    }
+
+    #[test]
+    fn test_sort_keys() {
+        let sorted = sort_keys(json!({"b": [{"d": 1, "c": 2}], "a": null}));
+        assert_eq!(sorted.to_string(), r#"{"a":null,"b":[{"c":2,"d":1}]}"#);
+    }
+
+    #[test]
+    fn test_index() {
+        assert_eq!(index(&json!({"a": 1}), &json!("a")).unwrap(), json!(1));
+        assert_eq!(index(&json!({"a": 1}), &json!("b")).unwrap(), json!(null));
+        assert_eq!(index(&json!([1, 2]), &json!(1)).unwrap(), json!(2));
+        assert_eq!(index(&json!([1, 2]), &json!(1.7)).unwrap(), json!(2));
+        assert_eq!(index(&json!([1, 2]), &json!(5)).unwrap(), json!(null));
+        assert_eq!(index(&json!([1, 2]), &json!(-1)).unwrap(), json!(2));
+        assert_eq!(index(&json!([1, 2]), &json!(-2.5)).unwrap(), json!(null));
+        assert_eq!(index(&json!(null), &json!("a")).unwrap(), json!(null));
+        assert_eq!(
+            index(&json!([1]), &json!("a")).unwrap_err().to_string(),
+            "Cannot index array with \"a\""
+        );
+        assert_eq!(
+            index(&json!({}), &json!(0)).unwrap_err().to_string(),
+            "Cannot index object with number"
+        );
+    }
+
+    #[test]
+    fn test_slice() {
+        let array = json!(["one", "two", "three"]);
+        assert_eq!(
+            slice(&array, &json!(0), &json!(2)).unwrap(),
+            json!(["one", "two"])
+        );
+        assert_eq!(
+            slice(&array, &json!(1), &json!(null)).unwrap(),
+            json!(["two", "three"])
+        );
+        assert_eq!(slice(&array, &json!(10), &json!(20)).unwrap(), json!([]));
+        assert_eq!(slice(&array, &json!(2), &json!(1)).unwrap(), json!([]));
+        assert_eq!(
+            slice(&json!(null), &json!(0), &json!(1)).unwrap(),
+            json!(null)
+        );
+        assert_eq!(
+            slice(&array, &json!(-2), &json!(-1)).unwrap(),
+            json!(["two"])
+        );
+        assert_eq!(
+            slice(&array, &json!(0.5), &json!(1.2)).unwrap(),
+            json!(["one", "two"])
+        );
+        assert_eq!(
+            slice(&json!("héllo"), &json!(1), &json!(3)).unwrap(),
+            json!("él")
+        );
+        assert_eq!(
+            slice(&json!("abc"), &json!(5), &json!(null)).unwrap(),
+            json!("")
+        );
+        assert!(slice(&json!({}), &json!(0), &json!(1)).is_err());
+    }
+
+    #[test]
+    fn test_iterate() {
+        assert_eq!(iterate(json!([1, 2])).unwrap(), vec![json!(1), json!(2)]);
+        assert_eq!(
+            iterate(json!({"a": 1, "b": 2})).unwrap(),
+            vec![json!(1), json!(2)]
+        );
+        assert_eq!(
+            iterate(json!(5)).unwrap_err().to_string(),
+            "Cannot iterate over number (5)"
+        );
+    }
+}
@@ -0,0 +1,127 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+/// Run jq-rs with `args`, feeding it `stdin`
+fn jq(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_jq-rs"))
+        .args(args)
+        .env_remove("JQ_COLORS")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // A run that fails early may close stdin before reading all of it
+    let _ = child.stdin.take().unwrap().write_all(stdin.as_bytes());
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn test_success() {
+    let output = jq(&["-c", ".a"], r#"{"a": [1, 2]} {"a": null}"#);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "[1,2]\nnull\n");
+    assert_eq!(stderr(&output), "");
+    // Without --exit-status, neither falsy nor missing output is a failure
+    assert_eq!(jq(&["empty"], "1").status.code(), Some(0));
+}
+
+#[test]
+fn test_filters_starting_with_a_hyphen() {
+    assert_eq!(stdout(&jq(&["-n", "-1 + 2"], "")), "1\n");
+    assert_eq!(stdout(&jq(&["-n", "-(1+2)"], "")), "-3\n");
+    // Options after the filter are still options
+    assert_eq!(stdout(&jq(&["-.a | [.]", "-c"], r#"{"a": 1}"#)), "[-1]\n");
+}
+
+#[test]
+fn test_exit_status() {
+    assert_eq!(jq(&["-e", "."], "1").status.code(), Some(0));
+    assert_eq!(jq(&["-e", "."], "false").status.code(), Some(1));
+    assert_eq!(jq(&["-e", "."], "1 null").status.code(), Some(1));
+    // Only the last output counts
+    assert_eq!(jq(&["-e", "."], "null 1").status.code(), Some(0));
+    assert_eq!(jq(&["-e", "empty"], "1").status.code(), Some(4));
+    assert_eq!(jq(&["-e", "."], "").status.code(), Some(4));
+}
+
+#[test]
+fn test_usage_errors() {
+    let output = jq(&["-n", "--argjson", "x", "{", "$x"], "");
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).starts_with("jq-rs: error: invalid JSON text passed to --argjson x"));
+}
+
+#[test]
+fn test_compile_errors() {
+    let output = jq(&[".a |"], "{}");
+    assert_eq!(output.status.code(), Some(3));
+    assert!(stderr(&output).starts_with("error: expected a filter, found end of filter"));
+    assert_eq!(stdout(&output), "");
+    assert_eq!(jq(&["nosuchfunction"], "{}").status.code(), Some(3));
+}
+
+#[test]
+fn test_io_errors() {
+    let output = jq(&[".", "no/such/file.json"], "");
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).starts_with("jq-rs: error: could not read no/such/file.json"));
+    // The same failure through `input`
+    let output = jq(&["-n", "input", "no/such/file.json"], "");
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_parse_errors() {
+    // Values before the invalid one are still printed
+    let output = jq(&["-c", "."], "1 2 {");
+    assert_eq!(output.status.code(), Some(6));
+    assert_eq!(stdout(&output), "1\n2\n");
+    assert_eq!(
+        stderr(&output),
+        "jq-rs: error: could not parse <stdin>:1:5: EOF while parsing an object\n"
+    );
+    // `inputs` reports the same error, and `try` cannot hide it
+    for filter in ["[inputs]", "try [inputs] catch 0", "[inputs] // 0"] {
+        let output = jq(&["-n", "-c", filter], "1 2 {");
+        assert_eq!(output.status.code(), Some(6), "{}", filter);
+        assert_eq!(
+            stderr(&output),
+            "jq-rs: error: could not parse <stdin>:1:5: EOF while parsing an object\n"
+        );
+    }
+}
+
+#[test]
+fn test_runtime_errors() {
+    // An error stops the filter for that input only
+    let output = jq(&[". + 1"], r#"1 "a" 3"#);
+    assert_eq!(output.status.code(), Some(5));
+    assert_eq!(stdout(&output), "2\n4\n");
+    assert_eq!(
+        stderr(&output),
+        "jq-rs: error (at <stdin>:1:3): string (\"a\") and number (1) cannot be added\n"
+    );
+    // A runtime error wins over --exit-status
+    assert_eq!(jq(&["-e", "error"], "null").status.code(), Some(5));
+    let output = jq(&["--raw-output0", "."], r#""a\u0000b""#);
+    assert_eq!(output.status.code(), Some(5));
+}
+
+#[test]
+fn test_terminators() {
+    assert_eq!(stdout(&jq(&["-r", ".[]"], r#"["a", "b"]"#)), "a\nb\n");
+    assert_eq!(stdout(&jq(&["-j", ".[]"], r#"["a", 1]"#)), "a1");
+    assert_eq!(
+        stdout(&jq(&["--raw-output0", ".[]"], r#"["a", "b"]"#)),
+        "a\0b\0"
+    );
+}